@@ -11,5 +11,5 @@ fn main() {
         true,
     )
     .unwrap();
-    height_map::mesh_from_image(dyn_img);
+    height_map::terrain_from_image(dyn_img, Default::default()).mesh();
 }
@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use bevy::prelude::Image;
+use bevy::render::texture::{CompressedImageFormats, ImageType};
+use venture::height_map::contours::{self, Contour};
+use venture::height_map::geotiff::parse_geotiff;
+use venture::height_map::ImageHeightSource;
+
+/// usage: contours <height map> <output.{geojson,svg}> <interval>
+///
+/// GeoTIFFs keep their coordinates in the GeoJSON, everything else is in samples.
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let input = PathBuf::from(args.next().context("missing input height map")?);
+    let output = PathBuf::from(args.next().context("missing output file")?);
+    let interval: f32 = args.next().context("missing contour interval")?.parse()?;
+    if interval <= 0.0 {
+        bail!("the interval has to be positive");
+    }
+
+    let ext = input
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .context("input needs a file extension")?
+        .to_lowercase();
+    let bytes = std::fs::read(&input)?;
+    let (contours, georeference, width, height): (Vec<Contour>, _, _, _) =
+        if ext == "tif" || ext == "tiff" {
+            let (raster, georeference) = parse_geotiff(&bytes)?;
+            let (width, height) = (raster.width, raster.height);
+            let contours = contours::contours_at_interval(&raster, width, height, interval);
+            (contours, Some(georeference), width, height)
+        } else {
+            let image = Image::from_buffer(
+                &bytes,
+                ImageType::Extension(&ext),
+                CompressedImageFormats::all(),
+                true,
+            )?;
+            let width = image.texture_descriptor.size.width as usize;
+            let height = image.texture_descriptor.size.height as usize;
+            let source = ImageHeightSource::from_grayscale(image);
+            let contours = contours::contours_at_interval(&source, width, height, interval);
+            (contours, None, width, height)
+        };
+
+    let out = match output.extension().and_then(|ext| ext.to_str()) {
+        Some("geojson") | Some("json") => contours::to_geojson(&contours, georeference.as_ref()),
+        Some("svg") => contours::to_svg(&contours, width, height),
+        _ => bail!("unsupported output format: {:?}", output),
+    };
+    std::fs::write(&output, out)?;
+    println!("wrote {} contour lines to {:?}", contours.len(), output);
+
+    Ok(())
+}
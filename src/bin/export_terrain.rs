@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use bevy::prelude::Image;
+use bevy::render::texture::{CompressedImageFormats, ImageType};
+use venture::height_map;
+use venture::height_map::export::{Encoding, ExportFormat};
+use venture::height_map::solid::SolidBuilder;
+
+/// usage: export_terrain <height map> <output.{obj,stl,ply}> [--ascii] [--solid <base thickness>]
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let input = PathBuf::from(args.next().context("missing input height map")?);
+    let output = PathBuf::from(args.next().context("missing output file")?);
+
+    let mut format = ExportFormat::from_path(&output)
+        .ok_or_else(|| anyhow!("unsupported output format: {:?}", output))?;
+    let mut solid_thickness = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ascii" => {
+                format = match format {
+                    ExportFormat::Stl(_) => ExportFormat::Stl(Encoding::Ascii),
+                    ExportFormat::Ply(_) => ExportFormat::Ply(Encoding::Ascii),
+                    ExportFormat::Obj => ExportFormat::Obj,
+                }
+            }
+            "--solid" => {
+                let thickness: f32 = args
+                    .next()
+                    .context("--solid needs a base thickness")?
+                    .parse()?;
+                solid_thickness = Some(thickness);
+            }
+            _ => bail!("unknown argument {}", arg),
+        }
+    }
+
+    let ext = input
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .context("input needs a file extension")?;
+    let bytes = std::fs::read(&input)?;
+    let image = Image::from_buffer(
+        &bytes,
+        ImageType::Extension(ext),
+        CompressedImageFormats::all(),
+        true,
+    )?;
+
+    let geometry = height_map::geometry_from_image(image);
+    let geometry = match solid_thickness {
+        Some(thickness) => SolidBuilder::new(geometry).base_thickness(thickness).build(),
+        None => geometry,
+    };
+
+    let mut out = BufWriter::new(File::create(&output)?);
+    format.write(&mut out, &geometry.positions, &geometry.indices)?;
+    println!("wrote {} triangles to {:?}", geometry.indices.len() / 3, output);
+
+    Ok(())
+}
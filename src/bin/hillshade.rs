@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use bevy::prelude::Image;
+use bevy::render::texture::{CompressedImageFormats, ImageType};
+use venture::height_map::hillshade::Hillshade;
+use venture::height_map::{ImageHeightSource, TERRAIN_SIZE};
+
+/// usage: hillshade <height map> <output.png> [--azimuth <degrees>] [--altitude <degrees>]
+///                  [--z-factor <factor>] [--multi] [--tint]
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let input = PathBuf::from(args.next().context("missing input height map")?);
+    let output = PathBuf::from(args.next().context("missing output image")?);
+
+    let mut hillshade = Hillshade::default();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| -> anyhow::Result<f32> {
+            Ok(args
+                .next()
+                .with_context(|| format!("{} needs a value", name))?
+                .parse()?)
+        };
+        match arg.as_str() {
+            "--azimuth" => hillshade.azimuth = value("--azimuth")?,
+            "--altitude" => hillshade.altitude = value("--altitude")?,
+            "--z-factor" => hillshade.z_factor = value("--z-factor")?,
+            "--multi" => hillshade.multi_directional = true,
+            "--tint" => hillshade.tint = true,
+            _ => bail!("unknown argument {}", arg),
+        }
+    }
+
+    let ext = input
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .context("input needs a file extension")?;
+    let bytes = std::fs::read(&input)?;
+    let image = Image::from_buffer(
+        &bytes,
+        ImageType::Extension(ext),
+        CompressedImageFormats::all(),
+        true,
+    )?;
+    let width = image.texture_descriptor.size.width as usize;
+    let height = image.texture_descriptor.size.height as usize;
+
+    // same proportions as the mesh: heights from 0 to 1
+    hillshade.cell_size = TERRAIN_SIZE / width.max(height) as f32;
+    let source = ImageHeightSource::from_grayscale(image);
+    hillshade.render(&source, width, height).save(&output)?;
+    println!("wrote {}x{} shaded relief to {:?}", width, height, output);
+
+    Ok(())
+}
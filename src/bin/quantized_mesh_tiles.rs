@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use bevy::prelude::Image;
+use bevy::render::texture::{CompressedImageFormats, ImageType};
+use venture::height_map::quantized_mesh::{
+    GeographicBounds, QuantizedMeshOptions, QuantizedMeshTiler,
+};
+use venture::height_map::ImageHeightSource;
+
+/// usage: quantized_mesh_tiles <height map> <out dir> <west> <south> <east> <north> <max level>
+///        <height scale>
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 8 {
+        anyhow::bail!(
+            "usage: quantized_mesh_tiles <height map> <out dir> <west> <south> <east> <north> \
+             <max level> <height scale>"
+        );
+    }
+
+    let input = PathBuf::from(&args[0]);
+    let out_dir = PathBuf::from(&args[1]);
+    let bounds = GeographicBounds {
+        west: args[2].parse()?,
+        south: args[3].parse()?,
+        east: args[4].parse()?,
+        north: args[5].parse()?,
+    };
+    let max_level: u32 = args[6].parse()?;
+    let options = QuantizedMeshOptions {
+        height_scale: args[7].parse()?,
+        ..Default::default()
+    };
+
+    let ext = input
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .context("input needs a file extension")?;
+    let bytes = std::fs::read(&input)?;
+    let image = Image::from_buffer(
+        &bytes,
+        ImageType::Extension(ext),
+        CompressedImageFormats::all(),
+        true,
+    )?;
+    let size = image
+        .texture_descriptor
+        .size
+        .width
+        .min(image.texture_descriptor.size.height) as usize;
+
+    QuantizedMeshTiler::new(bounds, max_level)
+        .options(options)
+        .write_tiles(&ImageHeightSource::from_grayscale(image), size, &out_dir)?;
+    println!("wrote tiles up to level {} to {:?}", max_level, out_dir);
+
+    Ok(())
+}
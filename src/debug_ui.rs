@@ -2,6 +2,13 @@ use bevy::app::App;
 use bevy::diagnostic::{Diagnostics, EntityCountDiagnosticsPlugin, FrameTimeDiagnosticsPlugin};
 use bevy::prelude::*;
 
+use crate::height_map::hillshade;
+use crate::height_map::picking::TerrainPick;
+use crate::height_map::profile::TerrainPath;
+
+/// size of the elevation profile chart, in pixels
+const PROFILE_CHART_SIZE: (u32, u32) = (240, 80);
+
 pub struct DebugUiPlugin;
 
 impl Plugin for DebugUiPlugin {
@@ -9,6 +16,10 @@ impl Plugin for DebugUiPlugin {
         app.add_startup_system(setup_ui);
         app.add_system(update_fps_text);
         app.add_system(update_entity_count_text);
+        app.init_resource::<TerrainPick>();
+        app.add_system(update_terrain_pick_text);
+        app.init_resource::<TerrainPath>();
+        app.add_system(update_profile);
     }
 }
 
@@ -18,6 +29,15 @@ struct FpsText;
 #[derive(Component)]
 struct EntityCountText;
 
+#[derive(Component)]
+struct TerrainPickText;
+
+#[derive(Component)]
+struct ProfileText;
+
+#[derive(Component)]
+struct ProfileChart;
+
 fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
     let font = asset_server.load("font/FiraSans-Book.otf");
 
@@ -109,6 +129,82 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
                     ..Default::default()
                 })
                 .insert(EntityCountText);
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        align_self: AlignSelf::FlexStart,
+                        flex_shrink: 0.0,
+                        ..Default::default()
+                    },
+                    text: Text {
+                        sections: vec![
+                            TextSection {
+                                value: "Cursor: ".to_string(),
+                                style: TextStyle {
+                                    font: font.clone(),
+                                    font_size: 15.0,
+                                    color: Color::WHITE,
+                                },
+                            },
+                            TextSection {
+                                value: "-".to_string(),
+                                style: TextStyle {
+                                    font: font.clone(),
+                                    font_size: 15.0,
+                                    color: Color::YELLOW,
+                                },
+                            },
+                        ],
+                        alignment: Default::default(),
+                    },
+                    ..Default::default()
+                })
+                .insert(TerrainPickText);
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        align_self: AlignSelf::FlexStart,
+                        flex_shrink: 0.0,
+                        ..Default::default()
+                    },
+                    text: Text {
+                        sections: vec![
+                            TextSection {
+                                value: "Profile: ".to_string(),
+                                style: TextStyle {
+                                    font: font.clone(),
+                                    font_size: 15.0,
+                                    color: Color::WHITE,
+                                },
+                            },
+                            TextSection {
+                                value: "-".to_string(),
+                                style: TextStyle {
+                                    font: font.clone(),
+                                    font_size: 15.0,
+                                    color: Color::YELLOW,
+                                },
+                            },
+                        ],
+                        alignment: Default::default(),
+                    },
+                    ..Default::default()
+                })
+                .insert(ProfileText);
+            // hidden until there is a path to show
+            parent
+                .spawn_bundle(ImageBundle {
+                    style: Style {
+                        size: Size::new(
+                            Val::Px(PROFILE_CHART_SIZE.0 as f32),
+                            Val::Px(PROFILE_CHART_SIZE.1 as f32),
+                        ),
+                        display: Display::None,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .insert(ProfileChart);
         });
 }
 
@@ -149,3 +245,59 @@ fn update_entity_count_text(
         text.sections[1].value = format!("{:.1}", fps);
     }
 }
+
+fn update_terrain_pick_text(
+    pick: Res<TerrainPick>,
+    mut query: Query<&mut Text, With<TerrainPickText>>,
+) {
+    if !pick.is_changed() {
+        return;
+    }
+
+    for mut text in query.iter_mut() {
+        text.sections[1].value = match pick.0 {
+            Some(hit) => format!(
+                "{:.2}, {:.2} elevation {:.3} slope {:.1}°",
+                hit.position.x,
+                hit.position.z,
+                hit.position.y,
+                hit.slope_degrees()
+            ),
+            None => "-".to_string(),
+        };
+    }
+}
+
+fn update_profile(
+    path: Res<TerrainPath>,
+    mut images: ResMut<Assets<Image>>,
+    mut texts: Query<&mut Text, With<ProfileText>>,
+    mut charts: Query<(&mut UiImage, &mut Style), With<ProfileChart>>,
+) {
+    if !path.is_changed() {
+        return;
+    }
+
+    for mut text in texts.iter_mut() {
+        text.sections[1].value = match &path.profile {
+            Some(profile) => format!(
+                "length {:.2} ascent {:.3} descent {:.3} max grade {:.1}%",
+                profile.length(),
+                profile.ascent,
+                profile.descent,
+                profile.max_grade * 100.0
+            ),
+            None => "-".to_string(),
+        };
+    }
+    for (mut image, mut style) in charts.iter_mut() {
+        match &path.profile {
+            Some(profile) => {
+                let chart = profile.chart(PROFILE_CHART_SIZE.0, PROFILE_CHART_SIZE.1);
+                image.0 = images.add(hillshade::texture(&chart));
+                style.display = Display::Flex;
+            }
+            None => style.display = Display::None,
+        }
+    }
+}
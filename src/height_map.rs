@@ -1,19 +1,111 @@
 use bevy::{
-    math::Vec3,
+    math::{Vec2, Vec3},
     prelude::{Image, Mesh, Quat, Transform},
-    render::mesh::Indices,
+    render::mesh::{Indices, VertexAttributeValues},
     render::render_resource::{PrimitiveTopology, TextureFormat},
 };
 use rand::Rng;
 
-use std::cmp::min;
+use std::cmp::max;
+use std::str::FromStr;
 use std::time::Instant;
 
+pub mod analysis;
+pub mod contours;
+pub mod encoding;
+pub mod erosion;
+pub mod export;
+pub mod geotiff;
+pub mod grid;
+pub mod hillshade;
+pub mod hydrology;
 pub mod loader;
+pub mod min_max;
+pub mod picking;
+pub mod profile;
+pub mod quantized_mesh;
+pub mod raster;
+pub mod raw;
 pub mod rtin;
+pub mod sampling;
+pub mod sculpt;
+pub mod solid;
+pub mod terrain;
+pub mod viewshed;
+pub mod void_fill;
+pub mod volume;
 
 pub trait HeightSource {
     fn sample_height(&self, x: usize, y: usize) -> f32;
+
+    /// whether the sample at `x`, `y` holds actual data. Mesh builders leave out every triangle
+    /// touching an invalid sample, so sources should still return a sane height for them (like
+    /// the lowest valid one), in case something does not care about the mask.
+    fn is_valid(&self, _x: usize, _y: usize) -> bool {
+        true
+    }
+}
+
+/// marks every sample equal to `nodata` (or NaN) of another source as invalid, and replaces it
+/// with the lowest valid height
+pub struct NodataHeightSource<H: HeightSource> {
+    source: H,
+    nodata: Option<f32>,
+    lowest: f32,
+}
+
+impl<H: HeightSource> NodataHeightSource<H> {
+    /// `width` and `height` are needed to find the lowest valid sample
+    pub fn new(source: H, nodata: Option<f32>, width: usize, height: usize) -> Self {
+        let mut this = Self {
+            source,
+            nodata,
+            lowest: 0.0,
+        };
+        if nodata.is_some() {
+            let lowest = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .filter(|(x, y)| this.is_valid(*x, *y))
+                .map(|(x, y)| this.source.sample_height(x, y))
+                .fold(f32::INFINITY, f32::min);
+            this.lowest = if lowest.is_finite() { lowest } else { 0.0 };
+        }
+        this
+    }
+}
+
+impl<H: HeightSource> HeightSource for NodataHeightSource<H> {
+    #[inline]
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        if self.is_valid(x, y) {
+            self.source.sample_height(x, y)
+        } else {
+            self.lowest
+        }
+    }
+
+    fn is_valid(&self, x: usize, y: usize) -> bool {
+        if !self.source.is_valid(x, y) {
+            return false;
+        }
+        match self.nodata {
+            Some(nodata) => {
+                let value = self.source.sample_height(x, y);
+                value != nodata && !value.is_nan()
+            }
+            None => true,
+        }
+    }
+}
+
+/// what to do with samples that have no data
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VoidOptions {
+    /// sample value (before any height scale) that means "no data", on top of the voids the
+    /// source already knows about
+    pub nodata: Option<f32>,
+    /// fill the voids instead of leaving holes in the mesh
+    pub fill: Option<void_fill::VoidFill>,
 }
 
 pub struct ThreadLocalRngHeightSource;
@@ -89,6 +181,7 @@ pub struct HeightMap<H>
     height_source: H,
     source_size: usize,
     target_size: f32,
+    height_scale: f32,
 }
 
 impl<H: HeightSource> HeightMap<H> {
@@ -97,38 +190,61 @@ impl<H: HeightSource> HeightMap<H> {
             height_source,
             source_size,
             target_size,
+            height_scale: 1.0,
         }
     }
 
+    /// factor every sample gets multiplied with. Useful for sources that return real world units
+    /// (metres), which would be way out of proportion with the `target_size` otherwise.
+    pub fn with_height_scale(mut self, height_scale: f32) -> Self {
+        self.height_scale = height_scale;
+        self
+    }
+
     fn sample(&self, x: usize, y: usize) -> f32 {
         debug_assert!(x <= self.source_size);
         debug_assert!(y <= self.source_size);
-        <H as HeightSource>::sample_height(&self.height_source, x, y)
+        <H as HeightSource>::sample_height(&self.height_source, x, y) * self.height_scale
+    }
+
+    fn is_valid(&self, x: usize, y: usize) -> bool {
+        self.height_source.is_valid(x, y)
+    }
+
+    /// replaces every void by a height made up from the ones around it
+    pub fn fill_voids(self, fill: void_fill::VoidFill) -> HeightMap<grid::HeightGrid> {
+        let filled = fill.fill(&self.height_source, self.source_size, self.source_size);
+        HeightMap {
+            height_source: filled,
+            source_size: self.source_size,
+            target_size: self.target_size,
+            height_scale: self.height_scale,
+        }
     }
 }
 
 fn build_normal<T: HeightSource>(x: usize, y: usize, height_map: &HeightMap<T>) -> [f32; 3] {
     let center_height = height_map.sample(x, y);
 
-    let delta_left = if x == 0 {
+    let delta_left = if x == 0 || !height_map.is_valid(x - 1, y) {
         0.0
     } else {
         center_height - height_map.sample(x - 1, y)
     };
 
-    let delta_right = if x >= (height_map.source_size - 1) {
+    let delta_right = if x >= (height_map.source_size - 1) || !height_map.is_valid(x + 1, y) {
         0.0
     } else {
         center_height - height_map.sample(x + 1, y)
     };
 
-    let delta_top = if y == 0 {
+    let delta_top = if y == 0 || !height_map.is_valid(x, y - 1) {
         0.0
     } else {
         center_height - height_map.sample(x, y - 1)
     };
 
-    let delta_bottom = if y >= (height_map.source_size - 1) {
+    let delta_bottom = if y >= (height_map.source_size - 1) || !height_map.is_valid(x, y + 1) {
         0.0
     } else {
         center_height - height_map.sample(x, y + 1)
@@ -153,7 +269,49 @@ fn build_normal<T: HeightSource>(x: usize, y: usize, height_map: &HeightMap<T>)
 
 use rtin::*;
 
-fn create_mesh<T: HeightSource>(hm: HeightMap<T>) -> Mesh {
+/// how far (in world units) the simplified mesh may be off the actual samples
+// TODO: this is a configurable we want to tweak later
+const MAX_ERROR: f32 = 0.002;
+
+/// how the samples of a terrain are turned into triangles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Triangulation {
+    /// as few triangles as possible while staying within [`MAX_ERROR`] of the samples
+    Rtin,
+    /// the "trivial but slow" one: two triangles for every cell, nothing simplified
+    Grid,
+}
+
+impl FromStr for Triangulation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rtin" => Ok(Triangulation::Rtin),
+            "grid" | "full" => Ok(Triangulation::Grid),
+            _ => anyhow::bail!("unknown triangulation {:?}, expected rtin or grid", s),
+        }
+    }
+}
+
+/// the raw buffers a terrain mesh is made of. Keeping them around (instead of going straight to a
+/// bevy [`Mesh`]) allows us to hand the exact same geometry to exporters and other consumers.
+#[derive(Debug, Clone, Default)]
+pub struct TerrainGeometry {
+    /// the first `grid_size * grid_size` vertices are the height map samples, row by row
+    pub grid_size: usize,
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    /// the middle of the sample's texel in a texture with one texel per sample, so images made
+    /// from the grid (colourings, overlays) fit the mesh
+    pub uvs: Vec<[f32; 2]>,
+    pub indices: Vec<u32>,
+}
+
+pub fn create_geometry<T: HeightSource>(
+    hm: HeightMap<T>,
+    triangulation: Triangulation,
+) -> TerrainGeometry {
     let start = Instant::now();
 
     // we want {resolution}-1 by {resolution}-1 tiles
@@ -182,28 +340,21 @@ fn create_mesh<T: HeightSource>(hm: HeightMap<T>) -> Mesh {
             let height = hm.sample(x, y);
             let offset = x + (y * resolution);
             positions[offset] = [lx, height, ly];
-            uvs[offset] = [lx, ly];
+            uvs[offset] = [
+                (x as f32 + 0.5) / resolution as f32,
+                (y as f32 + 0.5) / resolution as f32,
+            ];
             normals[offset] = build_normal(x, y, &hm);
         }
     }
 
     println!("terrain generation took {:?}", start.elapsed());
-    let rtin = RtinMeshBuilder::from_height_map(hm);
-    let max_error = 0.002; // TODO: this is a configurable we want to tweak later
-    let indices = rtin.get_indices(max_error);
-    //TODO refactor this code se we can have a "trivial but slow" implementation, and an RTIN
-    // based "fast" one.
-    /*let res_plus1 = resolution + 0;
-    for px in 0..(resolution - 1) {
-        for py in 0..(resolution - 1) {
-            for off in [resolution, 1, 0, resolution, 1 + resolution, 1] {
-                indices.push((px + (py * res_plus1) + off) as u32);
-            }
-        }
-    }*/
+    let indices = match triangulation {
+        Triangulation::Rtin => RtinMeshBuilder::from_height_map(hm).get_indices(MAX_ERROR),
+        Triangulation::Grid => grid_indices(&hm),
+    };
 
     println!("terrain generation took {:?}", start.elapsed());
-    let indices = Indices::U32(indices);
 
     let max_triangles = (resolution - 1) * (resolution - 1) * 2;
     println!(
@@ -213,23 +364,234 @@ fn create_mesh<T: HeightSource>(hm: HeightMap<T>) -> Mesh {
         100.0 - (100.0 / max_triangles as f32 * (indices.len() / 3) as f32)
     );
 
+    TerrainGeometry {
+        grid_size: resolution,
+        positions,
+        normals,
+        uvs,
+        indices,
+    }
+}
+
+/// [`Triangulation::Grid`]: two triangles for every grid cell, no simplification. Triangles
+/// touching an invalid sample are left out, just like RTIN does.
+fn grid_indices<T: HeightSource>(hm: &HeightMap<T>) -> Vec<u32> {
+    let resolution = hm.source_size;
+    let mut indices = Vec::with_capacity((resolution - 1) * (resolution - 1) * 6);
+    for py in 0..(resolution - 1) {
+        for px in 0..(resolution - 1) {
+            let triangles = [
+                [(px, py + 1), (px + 1, py), (px, py)],
+                [(px, py + 1), (px + 1, py + 1), (px + 1, py)],
+            ];
+            for triangle in triangles {
+                if triangle.iter().all(|(x, y)| hm.is_valid(*x, *y)) {
+                    indices.extend(triangle.iter().map(|(x, y)| (x + y * resolution) as u32));
+                }
+            }
+        }
+    }
+
+    indices
+}
+
+fn create_mesh<T: HeightSource>(hm: HeightMap<T>, triangulation: Triangulation) -> Mesh {
+    let geometry = create_geometry(hm, triangulation);
+
     let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-    mesh.set_indices(Some(indices));
-    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(geometry.indices)));
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, geometry.positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, geometry.normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, geometry.uvs);
 
     println!("{} vertices in total", mesh.count_vertices());
 
     mesh
 }
 
-pub fn mesh_from_image(height_map: Image) -> Mesh {
+fn height_map_from_image(height_map: Image) -> HeightMap<grid::MaskedGrid> {
     let width = height_map.texture_descriptor.size.width;
     let height = height_map.texture_descriptor.size.height;
     let height_source = ImageHeightSource::from_grayscale(height_map);
 
-    let hm = HeightMap::create(height_source, min(width as usize, height as usize), 10.0);
+    let grid = rtin_grid(
+        height_source,
+        width as usize,
+        height as usize,
+        VoidOptions::default(),
+    );
+    let size = grid.grid.width();
+    HeightMap::create(grid, size, TERRAIN_SIZE)
+}
+
+/// the square of samples of a `width` x `height` source RTIN can work with. Voids get masked (or
+/// filled), sources that are not square are padded with voids (so every sample is kept, without
+/// stretching them), and anything that is not 2^k + 1 samples wide (like SRTM tiles with their
+/// 1201 or 3601 samples) gets resampled to the next size that is.
+fn rtin_grid<T: HeightSource>(
+    height_source: T,
+    width: usize,
+    height: usize,
+    voids: VoidOptions,
+) -> grid::MaskedGrid {
+    let height_source = NodataHeightSource::new(height_source, voids.nodata, width, height);
+    let grid = match voids.fill {
+        Some(fill) => grid::MaskedGrid::from(fill.fill(&height_source, width, height)),
+        None => grid::MaskedGrid::from_source(&height_source, width, height),
+    };
+    let size = max(width, height);
+    let grid = if width == height {
+        grid
+    } else {
+        println!("padding {}x{} samples to {2}x{2}", width, height, size);
+        grid.pad(size, size)
+    };
+
+    let supported = supported_grid_size(size);
+    if supported == size {
+        grid
+    } else {
+        println!("resampling {0}x{0} samples to {1}x{1}", size, supported);
+        grid.resample(supported, supported, grid::Resampling::Bilinear)
+    }
+}
+
+/// masks the voids of a `width` x `height` source (or fills them), and brings its samples into
+/// world units
+fn create_terrain<T: HeightSource>(
+    height_source: T,
+    width: usize,
+    height: usize,
+    height_scale: f32,
+    voids: VoidOptions,
+) -> terrain::Terrain {
+    let mut grid = rtin_grid(height_source, width, height, voids);
+    for sample in grid.grid.data_mut() {
+        *sample *= height_scale;
+    }
+    terrain::Terrain::new(grid)
+}
+
+pub fn geometry_from_image(height_map: Image) -> TerrainGeometry {
+    create_geometry(height_map_from_image(height_map), Triangulation::Rtin)
+}
 
-    create_mesh(hm)
+pub fn terrain_from_image(height_map: Image, voids: VoidOptions) -> terrain::Terrain {
+    let width = height_map.texture_descriptor.size.width as usize;
+    let height = height_map.texture_descriptor.size.height as usize;
+    let height_source = ImageHeightSource::from_grayscale(height_map);
+
+    create_terrain(height_source, width, height, 1.0, voids)
+}
+
+/// the terrain of a Terrain-RGB or Terrarium encoded image. Those decode to metres, so
+/// `height_scale` is needed to bring them into proportion with the width of the mesh.
+pub fn terrain_from_encoded_image(
+    height_map: Image,
+    encoding: encoding::RgbEncoding,
+    height_scale: f32,
+    voids: VoidOptions,
+) -> anyhow::Result<terrain::Terrain> {
+    let width = height_map.texture_descriptor.size.width as usize;
+    let height = height_map.texture_descriptor.size.height as usize;
+    let height_source = encoding::RgbEncodedHeightSource::new(height_map, encoding)?;
+
+    Ok(create_terrain(
+        height_source,
+        width,
+        height,
+        height_scale,
+        voids,
+    ))
+}
+
+/// the terrain of a raster with known cell size.
+///
+/// Like every terrain it is [`TERRAIN_SIZE`] wide, the cell size does not set its extent in
+/// world units. Its longer side is scaled to fit, and the heights get scaled by the same factor,
+/// so the terrain keeps its real proportions. The shorter side is padded with voids.
+pub fn terrain_from_raster(raster: raster::Raster, voids: VoidOptions) -> terrain::Terrain {
+    let (width, height) = (raster.width, raster.height);
+    let units_per_metre = TERRAIN_SIZE / max(width, height) as f32 / raster.cell_size;
+
+    create_terrain(raster, width, height, units_per_metre, voids)
+}
+
+/// the terrain straight from the samples of a raw height map, without going through an (8 bit)
+/// image first.
+pub fn terrain_from_raw(
+    height_source: raw::RawHeightSource,
+    height_scale: f32,
+    voids: VoidOptions,
+) -> terrain::Terrain {
+    let layout = *height_source.layout();
+
+    create_terrain(
+        height_source,
+        layout.width,
+        layout.height,
+        height_scale,
+        voids,
+    )
+}
+
+/// how many world units wide every terrain is, whatever its number of samples
+pub const TERRAIN_SIZE: f32 = 10.0;
+
+/// a line list mesh of polylines (in grid coordinates of `hm`), draped over the terrain and
+/// floating `lift` world units above it, so it is not hidden in the ground
+pub fn polyline_mesh<T: HeightSource>(lines: &[Vec<Vec2>], hm: &HeightMap<T>, lift: f32) -> Mesh {
+    let mut positions = vec![];
+    for line in lines {
+        for segment in line.windows(2) {
+            for point in segment {
+                let height = hm.sample_interpolated(point.x, point.y, grid::Resampling::Bilinear);
+                let (x, z) = hm.grid_to_world(point.x, point.y);
+                positions.push([x, height + lift, z]);
+            }
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+    // the pbr pipeline wants normals and uvs, even for lines
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; positions.len()]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; positions.len()]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh
+}
+
+/// the part of a mesh made by this module where `mask` (a grid of the same size) is not 0,
+/// lifted by `lift` so it can be drawn on top of the terrain as an overlay
+pub fn masked_mesh(mesh: &Mesh, mask: &grid::HeightGrid, lift: f32) -> Option<Mesh> {
+    let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+        VertexAttributeValues::Float32x3(positions) => positions,
+        _ => return None,
+    };
+    if positions.len() != mask.width() * mask.height() {
+        return None;
+    }
+    let inside = |index: u32| mask.data()[index as usize] != 0.0;
+    let indices: Vec<u32> = match mesh.indices()? {
+        Indices::U16(indices) => indices.iter().map(|index| *index as u32).collect(),
+        Indices::U32(indices) => indices.clone(),
+    };
+    let indices = indices
+        .chunks_exact(3)
+        .filter(|triangle| triangle.iter().all(|index| inside(*index)))
+        .flatten()
+        .copied()
+        .collect();
+
+    let lifted: Vec<[f32; 3]> = positions
+        .iter()
+        .map(|[x, y, z]| [*x, *y + lift, *z])
+        .collect();
+    let mut overlay = Mesh::new(PrimitiveTopology::TriangleList);
+    overlay.set_indices(Some(Indices::U32(indices)));
+    overlay.insert_attribute(Mesh::ATTRIBUTE_POSITION, lifted);
+    let normals = mesh.attribute(Mesh::ATTRIBUTE_NORMAL)?.clone();
+    overlay.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    let uvs = mesh.attribute(Mesh::ATTRIBUTE_UV_0)?.clone();
+    overlay.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    Some(overlay)
 }
@@ -0,0 +1,390 @@
+//! terrain derivatives: how steep, which way, how curved and how rough the ground is at every
+//! sample. All of them take the real distance between samples (`cell_size`, in the unit of the
+//! heights), otherwise slopes of a 30m DEM would come out thirty times too steep.
+//!
+//! Samples are addressed like everywhere else, with y growing southwards. Directions are
+//! compass directions with north up (towards smaller y). Invalid samples (voids) are treated
+//! like the ground beyond the border, and are flat themselves.
+use bevy::math::Vec3;
+use image::{Rgb, RgbImage};
+
+use crate::height_map::grid::HeightGrid;
+#[cfg(test)]
+use crate::height_map::grid::MaskedGrid;
+use crate::height_map::HeightSource;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlopeUnit {
+    Degrees,
+    /// rise over run, times 100. 45° is 100%
+    Percent,
+}
+
+/// the 3x3 neighbourhood of a sample, row by row from the north west. Samples beyond the border
+/// repeat the border, invalid ones the sample in the middle (so the window of an invalid sample
+/// is flat).
+pub(crate) fn window<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+) -> [f32; 9] {
+    let center = source.sample_height(x, y);
+    if !source.is_valid(x, y) {
+        return [center; 9];
+    }
+    let mut window = [0.0; 9];
+    for (index, value) in window.iter_mut().enumerate() {
+        let sx = (x + index % 3).saturating_sub(1).min(width - 1);
+        let sy = (y + index / 3).saturating_sub(1).min(height - 1);
+        *value = if source.is_valid(sx, sy) {
+            source.sample_height(sx, sy)
+        } else {
+            center
+        };
+    }
+    window
+}
+
+fn map_windows<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    f: impl Fn([f32; 9]) -> f32,
+) -> HeightGrid {
+    HeightGrid::from_fn(width, height, |x, y| f(window(source, width, height, x, y)))
+}
+
+/// height change per unit towards east and north, after Horn
+pub(crate) fn gradient(z: [f32; 9], cell_size: f32) -> (f32, f32) {
+    let east = ((z[2] + 2.0 * z[5] + z[8]) - (z[0] + 2.0 * z[3] + z[6])) / (8.0 * cell_size);
+    let north = ((z[0] + 2.0 * z[1] + z[2]) - (z[6] + 2.0 * z[7] + z[8])) / (8.0 * cell_size);
+    (east, north)
+}
+
+pub fn slope<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    cell_size: f32,
+    unit: SlopeUnit,
+) -> HeightGrid {
+    map_windows(source, width, height, |z| {
+        let (east, north) = gradient(z, cell_size);
+        let rise = (east * east + north * north).sqrt();
+        match unit {
+            SlopeUnit::Degrees => rise.atan().to_degrees(),
+            SlopeUnit::Percent => rise * 100.0,
+        }
+    })
+}
+
+/// the compass direction the ground faces (downhill), in degrees clockwise from north. Flat
+/// samples get -1.
+pub fn aspect<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    cell_size: f32,
+) -> HeightGrid {
+    map_windows(source, width, height, |z| {
+        let (east, north) = gradient(z, cell_size);
+        if east == 0.0 && north == 0.0 {
+            return -1.0;
+        }
+        let degrees = (-east).atan2(-north).to_degrees();
+        if degrees < 0.0 {
+            degrees + 360.0
+        } else {
+            degrees
+        }
+    })
+}
+
+/// the second order terms of the surface through a 3x3 window ([Zevenbergen & Thorne][0]):
+/// second derivatives towards east and north and the mixed one, and the gradient.
+///
+/// [0]: https://doi.org/10.1002/esp.3290120107
+fn second_derivatives(z: [f32; 9], cell_size: f32) -> (f32, f32, f32, f32, f32) {
+    let l2 = cell_size * cell_size;
+    let d = ((z[3] + z[5]) / 2.0 - z[4]) / l2;
+    let e = ((z[1] + z[7]) / 2.0 - z[4]) / l2;
+    let f = (-z[0] + z[2] + z[6] - z[8]) / (4.0 * l2);
+    let g = (z[5] - z[3]) / (2.0 * cell_size);
+    let h = (z[1] - z[7]) / (2.0 * cell_size);
+    (2.0 * d, 2.0 * e, f, g, h)
+}
+
+/// curvature along the slope, positive where it gets steeper downhill (convex, like the edge of
+/// a plateau) and negative where it flattens out (concave, like the foot of a hill). In 1 / the
+/// unit of `cell_size`, flat samples get 0.
+pub fn profile_curvature<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    cell_size: f32,
+) -> HeightGrid {
+    map_windows(source, width, height, |z| {
+        let (zee, znn, zen, g, h) = second_derivatives(z, cell_size);
+        let p = g * g + h * h;
+        if p == 0.0 {
+            return 0.0;
+        }
+        -(zee * g * g + 2.0 * zen * g * h + znn * h * h) / p
+    })
+}
+
+/// curvature across the slope, positive on ridges and spurs where water spreads out (convex),
+/// negative in hollows and valleys where it comes together (concave). Same unit as
+/// [`profile_curvature`].
+pub fn plan_curvature<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    cell_size: f32,
+) -> HeightGrid {
+    map_windows(source, width, height, |z| {
+        let (zee, znn, zen, g, h) = second_derivatives(z, cell_size);
+        let p = g * g + h * h;
+        if p == 0.0 {
+            return 0.0;
+        }
+        -(zee * h * h - 2.0 * zen * g * h + znn * g * g) / p
+    })
+}
+
+/// terrain ruggedness index ([Riley et al. 1999][0]): the root of the summed squared height
+/// differences to the eight neighbours. Purely vertical, so it does not need the cell size.
+///
+/// [0]: https://download.osgeo.org/qgis/doc/reference-docs/Terrain_Ruggedness_Index.pdf
+pub fn ruggedness<H: HeightSource>(source: &H, width: usize, height: usize) -> HeightGrid {
+    map_windows(source, width, height, |z| {
+        z.iter()
+            .map(|neighbour| (neighbour - z[4]).powi(2))
+            .sum::<f32>()
+            .sqrt()
+    })
+}
+
+/// topographic position index: how much higher a sample is than the mean of the square around
+/// it (`radius` samples in every direction, cut at the border, without voids). Positive on hills
+/// and ridges, negative in valleys, about 0 on flat ground and even slopes.
+pub fn topographic_position<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    radius: usize,
+) -> HeightGrid {
+    // summed area tables of the valid heights and of their count, one row and column bigger
+    // so the sums start at 0
+    let stride = width + 1;
+    let mut sums = vec![0.0f64; stride * (height + 1)];
+    let mut counts = vec![0.0f64; stride * (height + 1)];
+    for y in 0..height {
+        for x in 0..width {
+            let (value, count) = if source.is_valid(x, y) {
+                (source.sample_height(x, y) as f64, 1.0)
+            } else {
+                (0.0, 0.0)
+            };
+            for (table, value) in [(&mut sums, value), (&mut counts, count)] {
+                table[(y + 1) * stride + x + 1] =
+                    value + table[y * stride + x + 1] + table[(y + 1) * stride + x]
+                        - table[y * stride + x];
+            }
+        }
+    }
+
+    HeightGrid::from_fn(width, height, |x, y| {
+        if !source.is_valid(x, y) {
+            return 0.0;
+        }
+        let (x0, y0) = (x.saturating_sub(radius), y.saturating_sub(radius));
+        let (x1, y1) = ((x + radius + 1).min(width), (y + radius + 1).min(height));
+        let area = |table: &[f64]| {
+            table[y1 * stride + x1] - table[y0 * stride + x1] - table[y1 * stride + x0]
+                + table[y0 * stride + x0]
+        };
+        let center = source.sample_height(x, y) as f64;
+        let sum = area(&sums) - center;
+        let count = area(&counts) - 1.0;
+        if count == 0.0 {
+            return 0.0;
+        }
+        (center - sum / count) as f32
+    })
+}
+
+/// all of the above, to pick one at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerrainDerivative {
+    Slope(SlopeUnit),
+    Aspect,
+    ProfileCurvature,
+    PlanCurvature,
+    Ruggedness,
+    /// with the radius in samples
+    TopographicPosition(usize),
+}
+
+impl TerrainDerivative {
+    pub fn compute<H: HeightSource>(
+        &self,
+        source: &H,
+        width: usize,
+        height: usize,
+        cell_size: f32,
+    ) -> HeightGrid {
+        match self {
+            TerrainDerivative::Slope(unit) => slope(source, width, height, cell_size, *unit),
+            TerrainDerivative::Aspect => aspect(source, width, height, cell_size),
+            TerrainDerivative::ProfileCurvature => {
+                profile_curvature(source, width, height, cell_size)
+            }
+            TerrainDerivative::PlanCurvature => plan_curvature(source, width, height, cell_size),
+            TerrainDerivative::Ruggedness => ruggedness(source, width, height),
+            TerrainDerivative::TopographicPosition(radius) => {
+                topographic_position(source, width, height, *radius)
+            }
+        }
+    }
+
+    /// a colour image of the result: slope and ruggedness from white to dark red, aspect around
+    /// the colour wheel (flat is grey), curvature and position from blue (concave, low) over
+    /// white to red (convex, high)
+    pub fn to_image(&self, grid: &HeightGrid) -> RgbImage {
+        let (min, max) = grid.min_max();
+        let colour = |value: f32| -> Vec3 {
+            match self {
+                TerrainDerivative::Slope(_) | TerrainDerivative::Ruggedness => {
+                    let t = if max > min {
+                        (value - min) / (max - min)
+                    } else {
+                        0.0
+                    };
+                    Vec3::ONE.lerp(Vec3::new(0.6, 0.0, 0.0), t)
+                }
+                TerrainDerivative::Aspect if value < 0.0 => Vec3::splat(0.5),
+                TerrainDerivative::Aspect => hue(value),
+                _ => {
+                    // symmetric around 0, so flat stays white
+                    let extent = min.abs().max(max.abs());
+                    let t = if extent > 0.0 { value / extent } else { 0.0 };
+                    if t < 0.0 {
+                        Vec3::ONE.lerp(Vec3::new(0.1, 0.2, 0.8), -t)
+                    } else {
+                        Vec3::ONE.lerp(Vec3::new(0.8, 0.1, 0.1), t)
+                    }
+                }
+            }
+        };
+
+        RgbImage::from_fn(grid.width() as u32, grid.height() as u32, |x, y| {
+            let rgb = colour(grid.get(x as usize, y as usize)) * 255.0;
+            Rgb([rgb.x as u8, rgb.y as u8, rgb.z as u8])
+        })
+    }
+}
+
+/// a fully saturated colour from the colour wheel, `degrees` from red
+fn hue(degrees: f32) -> Vec3 {
+    let sector = (degrees.rem_euclid(360.0) / 60.0) as usize;
+    let t = degrees.rem_euclid(60.0) / 60.0;
+    match sector {
+        0 => Vec3::new(1.0, t, 0.0),
+        1 => Vec3::new(1.0 - t, 1.0, 0.0),
+        2 => Vec3::new(0.0, 1.0, t),
+        3 => Vec3::new(0.0, 1.0 - t, 1.0),
+        4 => Vec3::new(t, 0.0, 1.0),
+        _ => Vec3::new(1.0, 0.0, 1.0 - t),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 1e-4,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_slope_and_aspect_of_a_plane() {
+        // rises by 1 per sample towards the east, samples are 2 apart
+        let plane = HeightGrid::from_fn(5, 5, |x, _| x as f32);
+        assert_close(
+            26.565052,
+            slope(&plane, 5, 5, 2.0, SlopeUnit::Degrees).get(2, 2),
+        );
+        assert_close(50.0, slope(&plane, 5, 5, 2.0, SlopeUnit::Percent).get(2, 2));
+        // it faces downhill, to the west
+        assert_close(270.0, aspect(&plane, 5, 5, 2.0).get(2, 2));
+
+        let south = HeightGrid::from_fn(5, 5, |_, y| -(y as f32));
+        assert_close(180.0, aspect(&south, 5, 5, 1.0).get(2, 2));
+        assert_close(
+            -1.0,
+            aspect(&HeightGrid::filled(3, 3, 1.0), 3, 3, 1.0).get(1, 1),
+        );
+
+        assert_close(0.0, profile_curvature(&plane, 5, 5, 2.0).get(2, 2));
+        assert_close(0.0, plan_curvature(&plane, 5, 5, 2.0).get(2, 2));
+        assert_close(0.0, topographic_position(&plane, 5, 5, 1).get(2, 2));
+        // three neighbours 1 higher, three 1 lower
+        assert_close(6f32.sqrt(), ruggedness(&plane, 5, 5).get(2, 2));
+    }
+
+    #[test]
+    fn test_curvature_of_a_dome() {
+        // z = -0.05 (x² + y²) around the center, convex in every direction
+        let dome = HeightGrid::from_fn(9, 9, |x, y| {
+            let (dx, dy) = (x as f32 - 4.0, y as f32 - 4.0);
+            -0.05 * (dx * dx + dy * dy)
+        });
+        for (x, y) in [(7, 4), (4, 1), (6, 6)] {
+            assert_close(0.1, profile_curvature(&dome, 9, 9, 1.0).get(x, y));
+            assert_close(0.1, plan_curvature(&dome, 9, 9, 1.0).get(x, y));
+        }
+        // twice the spacing, a quarter of the curvature
+        assert_close(0.025, profile_curvature(&dome, 9, 9, 2.0).get(7, 4));
+
+        assert!(topographic_position(&dome, 9, 9, 2).get(4, 4) > 0.0);
+        let bowl = HeightGrid::from_fn(9, 9, |x, y| -dome.get(x, y));
+        assert!(topographic_position(&bowl, 9, 9, 2).get(4, 4) < 0.0);
+        assert_close(-0.1, plan_curvature(&bowl, 9, 9, 1.0).get(6, 6));
+    }
+
+    #[test]
+    fn test_voids_do_not_count() {
+        // the plane from above, with a deep void at the sample east of the middle
+        let plane = HeightGrid::from_fn(5, 5, |x, _| x as f32);
+        let mut grid = plane.clone();
+        grid.set(3, 2, -100.0);
+        let mut mask = vec![true; 25];
+        mask[2 * 5 + 3] = false;
+        let holey = MaskedGrid {
+            grid,
+            mask: Some(mask),
+        };
+
+        // the void counts as high as the middle, so the slope eases a little instead of going
+        // wild
+        assert_close(
+            slope(&plane, 5, 5, 1.0, SlopeUnit::Percent).get(2, 2) * 0.75,
+            slope(&holey, 5, 5, 1.0, SlopeUnit::Percent).get(2, 2),
+        );
+        // the mean of the seven valid neighbours is 13 / 7
+        assert_close(1.0 / 7.0, topographic_position(&holey, 5, 5, 1).get(2, 2));
+        // and is flat itself
+        assert_close(0.0, slope(&holey, 5, 5, 1.0, SlopeUnit::Degrees).get(3, 2));
+        assert_close(-1.0, aspect(&holey, 5, 5, 1.0).get(3, 2));
+        assert_close(0.0, ruggedness(&holey, 5, 5).get(3, 2));
+        assert_close(0.0, topographic_position(&holey, 5, 5, 1).get(3, 2));
+    }
+}
@@ -0,0 +1,439 @@
+//! contour lines (isolines) of a height map, with marching squares.
+//!
+//! Every grid cell is looked at on its own: which of its four corners are above the level
+//! decides which of its edges the contour crosses. The crossings are shared with the
+//! neighbouring cells, which is what we use to join the pieces into long polylines afterwards.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use bevy::math::Vec2;
+
+use crate::height_map::geotiff::GeoReference;
+use crate::height_map::HeightSource;
+
+/// a single contour line, in grid coordinates
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contour {
+    pub elevation: f32,
+    /// closed lines end with their first point again
+    pub points: Vec<Vec2>,
+    /// whether the line goes around (a hill top or a pit). Open lines end at the border of the
+    /// map or at a void.
+    pub closed: bool,
+}
+
+impl Contour {
+    /// where to put the elevation label: half way along the line, with the angle of the line
+    /// there (in radians, turned so the text is never upside down)
+    pub fn label_anchor(&self) -> Option<(Vec2, f32)> {
+        let length: f32 = self.points.windows(2).map(|s| s[0].distance(s[1])).sum();
+        let mut remaining = length / 2.0;
+        for segment in self.points.windows(2) {
+            let (from, to) = (segment[0], segment[1]);
+            let segment_length = from.distance(to);
+            if segment_length > 0.0 && remaining <= segment_length {
+                let direction = to - from;
+                let mut angle = direction.y.atan2(direction.x);
+                if angle > std::f32::consts::FRAC_PI_2 {
+                    angle -= std::f32::consts::PI;
+                } else if angle < -std::f32::consts::FRAC_PI_2 {
+                    angle += std::f32::consts::PI;
+                }
+                return Some((from + direction * (remaining / segment_length), angle));
+            }
+            remaining -= segment_length;
+        }
+        None
+    }
+}
+
+/// all multiples of `interval` between `min` and `max`
+pub fn levels(min: f32, max: f32, interval: f32) -> Vec<f32> {
+    assert!(interval > 0.0);
+    let first = (min / interval).ceil() as i64;
+    let last = (max / interval).floor() as i64;
+    (first..=last).map(|step| step as f32 * interval).collect()
+}
+
+/// a round interval (1, 2 or 5 times a power of 10) giving about `count` levels between `min`
+/// and `max`, like a map maker would pick it
+pub fn nice_interval(min: f32, max: f32, count: usize) -> f32 {
+    let rough = (max - min) / count.max(1) as f32;
+    if rough <= 0.0 {
+        return 1.0;
+    }
+    let magnitude = 10f32.powf(rough.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|step| step * magnitude)
+        .min_by(|a, b| (a - rough).abs().partial_cmp(&(b - rough).abs()).unwrap())
+        .unwrap()
+}
+
+/// the lowest and the highest valid sample, `None` if there are none
+pub fn height_range<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+) -> Option<(f32, f32)> {
+    let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+    for y in 0..height {
+        for x in 0..width {
+            if source.is_valid(x, y) {
+                min = min.min(source.sample_height(x, y));
+                max = max.max(source.sample_height(x, y));
+            }
+        }
+    }
+    if min > max {
+        None
+    } else {
+        Some((min, max))
+    }
+}
+
+/// contours at every multiple of `interval` the source reaches
+pub fn contours_at_interval<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    interval: f32,
+) -> Vec<Contour> {
+    match height_range(source, width, height) {
+        Some((min, max)) => contours(source, width, height, &levels(min, max, interval)),
+        None => vec![],
+    }
+}
+
+/// the edges of the grid, the horizontal one to the right and the vertical one below a sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Edge {
+    Horizontal(usize, usize),
+    Vertical(usize, usize),
+}
+
+/// contours at each of the `levels`. Samples exactly at a level count as above it.
+pub fn contours<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    levels: &[f32],
+) -> Vec<Contour> {
+    levels
+        .iter()
+        .flat_map(|level| contours_at_level(source, width, height, *level))
+        .collect()
+}
+
+fn contours_at_level<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    level: f32,
+) -> Vec<Contour> {
+    // where the contour crosses an edge, and the (up to two) segments using that crossing
+    let mut crossings: HashMap<Edge, Vec2> = HashMap::new();
+    let mut segments: Vec<[Edge; 2]> = vec![];
+    let mut crossing = |edge: Edge| {
+        crossings.entry(edge).or_insert_with(|| {
+            let ((x0, y0), (x1, y1)) = match edge {
+                Edge::Horizontal(x, y) => ((x, y), (x + 1, y)),
+                Edge::Vertical(x, y) => ((x, y), (x, y + 1)),
+            };
+            let (a, b) = (source.sample_height(x0, y0), source.sample_height(x1, y1));
+            let t = ((level - a) / (b - a)).clamp(0.0, 1.0);
+            Vec2::new(x0 as f32, y0 as f32).lerp(Vec2::new(x1 as f32, y1 as f32), t)
+        });
+        edge
+    };
+
+    for y in 0..height.saturating_sub(1) {
+        for x in 0..width.saturating_sub(1) {
+            // clockwise from the top left
+            let corners = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)];
+            if corners.iter().any(|(cx, cy)| !source.is_valid(*cx, *cy)) {
+                continue;
+            }
+            let heights = corners.map(|(cx, cy)| source.sample_height(cx, cy));
+            let above = heights.map(|height| height >= level);
+            // top, right, bottom, left: edge `i` runs from corner `i` to corner `i + 1`
+            let edges = [
+                Edge::Horizontal(x, y),
+                Edge::Vertical(x + 1, y),
+                Edge::Horizontal(x, y + 1),
+                Edge::Vertical(x, y),
+            ];
+            let crossed: Vec<usize> = (0..4)
+                .filter(|edge| above[*edge] != above[(*edge + 1) % 4])
+                .collect();
+
+            match crossed.len() {
+                2 => segments.push([crossing(edges[crossed[0]]), crossing(edges[crossed[1]])]),
+                4 => {
+                    // a saddle: two opposite corners above, two below. The average of the cell
+                    // decides whether the corners above are connected through the middle, then
+                    // the ones below get cut off, or the other way round.
+                    let center_above = heights.iter().sum::<f32>() / 4.0 >= level;
+                    for corner in (0..4).filter(|corner| above[*corner] != center_above) {
+                        // the edges before and after the corner
+                        segments.push([crossing(edges[(corner + 3) % 4]), crossing(edges[corner])]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    join_segments(level, &crossings, &segments)
+}
+
+/// chains the segments of a level into polylines, open ones first so they are walked from one
+/// end to the other instead of starting somewhere in the middle
+fn join_segments(
+    level: f32,
+    crossings: &HashMap<Edge, Vec2>,
+    segments: &[[Edge; 2]],
+) -> Vec<Contour> {
+    let mut by_edge: HashMap<Edge, Vec<usize>> = HashMap::new();
+    for (index, segment) in segments.iter().enumerate() {
+        for edge in segment {
+            by_edge.entry(*edge).or_default().push(index);
+        }
+    }
+
+    let mut used = vec![false; segments.len()];
+    let ends: Vec<usize> = (0..segments.len())
+        .filter(|index| segments[*index].iter().any(|edge| by_edge[edge].len() == 1))
+        .collect();
+    let mut contours = vec![];
+    for start in ends.into_iter().chain(0..segments.len()) {
+        if used[start] {
+            continue;
+        }
+        used[start] = true;
+        // for an open line, begin with the edge nobody else uses
+        let [first, mut edge] = match segments[start] {
+            [a, b] if by_edge[&a].len() == 1 => [a, b],
+            [a, b] => [b, a],
+        };
+        let mut edges = vec![first, edge];
+        while let Some(next) = by_edge[&edge].iter().copied().find(|index| !used[*index]) {
+            used[next] = true;
+            edge = if segments[next][0] == edge {
+                segments[next][1]
+            } else {
+                segments[next][0]
+            };
+            edges.push(edge);
+        }
+
+        let closed = edges.len() > 2 && edges[0] == edges[edges.len() - 1];
+        contours.push(Contour {
+            elevation: level,
+            points: edges.iter().map(|edge| crossings[edge]).collect(),
+            closed,
+        });
+    }
+
+    contours
+}
+
+/// a GeoJSON feature collection with a line string per contour and its `elevation`. With a
+/// geo reference the coordinates are in its CRS, otherwise in samples.
+pub fn to_geojson(contours: &[Contour], georeference: Option<&GeoReference>) -> String {
+    let mut json = String::from(r#"{"type":"FeatureCollection","features":["#);
+    for (index, contour) in contours.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        let coordinates: Vec<String> = contour
+            .points
+            .iter()
+            .map(|point| {
+                let [x, y] = match georeference {
+                    Some(georeference) => {
+                        georeference.sample_coordinates(point.x as f64, point.y as f64)
+                    }
+                    None => [point.x as f64, point.y as f64],
+                };
+                format!("[{},{}]", x, y)
+            })
+            .collect();
+        write!(
+            json,
+            r#"{{"type":"Feature","properties":{{"elevation":{}}},"#,
+            contour.elevation
+        )
+        .unwrap();
+        write!(
+            json,
+            r#""geometry":{{"type":"LineString","coordinates":[{}]}}}}"#,
+            coordinates.join(",")
+        )
+        .unwrap();
+    }
+    json.push_str("]}");
+    json
+}
+
+/// an SVG drawing of the contours over the `width` x `height` samples, every line labelled
+/// with its elevation
+pub fn to_svg(contours: &[Contour], width: usize, height: usize) -> String {
+    let (width, height) = (
+        width.saturating_sub(1).max(1),
+        height.saturating_sub(1).max(1),
+    );
+    let font_size = width.max(height) as f32 / 60.0;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {} {}" width="{}" height="{}">"#,
+        width,
+        height,
+        width * 4,
+        height * 4
+    );
+    svg.push_str(r#"<g fill="none" stroke="saddlebrown" stroke-width="1">"#);
+    for contour in contours {
+        let points: Vec<String> = contour
+            .points
+            .iter()
+            .map(|point| format!("{:.3},{:.3}", point.x, point.y))
+            .collect();
+        write!(
+            svg,
+            r#"<polyline vector-effect="non-scaling-stroke" points="{}"/>"#,
+            points.join(" ")
+        )
+        .unwrap();
+    }
+    svg.push_str("</g>");
+
+    write!(
+        svg,
+        r#"<g fill="saddlebrown" font-family="sans-serif" font-size="{:.3}" text-anchor="middle">"#,
+        font_size
+    )
+    .unwrap();
+    for contour in contours {
+        if let Some((position, angle)) = contour.label_anchor() {
+            write!(
+                svg,
+                r#"<text transform="translate({:.3},{:.3}) rotate({:.1})">{}</text>"#,
+                position.x,
+                position.y,
+                angle.to_degrees(),
+                contour.elevation
+            )
+            .unwrap();
+        }
+    }
+    svg.push_str("</g></svg>");
+    svg
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::grid::{HeightGrid, MaskedGrid};
+
+    fn cone() -> HeightGrid {
+        HeightGrid::from_fn(11, 11, |x, y| {
+            let (dx, dy) = (x as f32 - 5.0, y as f32 - 5.0);
+            10.0 - (dx * dx + dy * dy).sqrt()
+        })
+    }
+
+    #[test]
+    fn test_closed_rings_around_a_cone() {
+        let contours = contours_at_interval(&cone(), 11, 11, 2.0);
+        // 10 is only reached by the single top sample, which gives no line. 6 and 8 are rings,
+        // lower ones hit the border.
+        let elevations: Vec<f32> = contours.iter().map(|contour| contour.elevation).collect();
+        assert!(elevations.contains(&6.0) && elevations.contains(&8.0));
+        assert_eq!(2.0, nice_interval(0.0, 10.0, 5));
+        assert_eq!(50.0, nice_interval(120.0, 830.0, 15));
+
+        for contour in contours.iter().filter(|contour| contour.elevation >= 6.0) {
+            assert!(contour.closed);
+            assert_eq!(contour.points[0], *contour.points.last().unwrap());
+            for point in &contour.points {
+                // every point is on the circle, give or take the linear interpolation
+                let radius = (*point - Vec2::splat(5.0)).length();
+                assert!(
+                    (radius - (10.0 - contour.elevation)).abs() < 0.3,
+                    "{:?}",
+                    point
+                );
+            }
+        }
+        for contour in contours.iter().filter(|contour| contour.elevation <= 4.0) {
+            assert!(!contour.closed);
+        }
+    }
+
+    #[test]
+    fn test_open_line_on_a_slope() {
+        let slope = HeightGrid::from_fn(4, 3, |x, _| x as f32);
+        let contours = contours(&slope, 4, 3, &[1.5]);
+        assert_eq!(1, contours.len());
+        let contour = &contours[0];
+        assert!(!contour.closed);
+        assert_eq!(3, contour.points.len());
+        assert!(contour.points.iter().all(|point| point.x == 1.5));
+        let (anchor, angle) = contour.label_anchor().unwrap();
+        assert_eq!(Vec2::new(1.5, 1.0), anchor);
+        assert!((angle.abs() - std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+
+        let json = to_geojson(&contours, None);
+        assert!(json.contains(r#""elevation":1.5"#));
+        assert!(
+            json.contains("[1.5,0],[1.5,1],[1.5,2]") || json.contains("[1.5,2],[1.5,1],[1.5,0]")
+        );
+        let svg = to_svg(&contours, 4, 3);
+        assert!(svg.contains("<polyline") && svg.contains(">1.5</text>"));
+    }
+
+    #[test]
+    fn test_voids_break_the_lines() {
+        // the slope from above, with a deep void in the middle of the line at 1.5
+        let mut grid = HeightGrid::from_fn(4, 3, |x, _| x as f32);
+        grid.set(1, 1, -100.0);
+        let mut mask = vec![true; 12];
+        mask[4 + 1] = false;
+        let holey = MaskedGrid {
+            grid,
+            mask: Some(mask),
+        };
+
+        assert_eq!(Some((0.0, 3.0)), height_range(&holey, 4, 3));
+        // every cell touching the void is left out, which takes the whole line with it
+        assert!(contours(&holey, 4, 3, &[1.5]).is_empty());
+        // only the last column of cells is clear of the void, and nothing goes down to it
+        let lines = contours_at_interval(&holey, 4, 3, 0.5);
+        let elevations: Vec<f32> = lines.iter().map(|contour| contour.elevation).collect();
+        assert_eq!(vec![2.5, 3.0], elevations);
+        assert_eq!(3, lines[0].points.len());
+    }
+
+    #[test]
+    fn test_saddle_is_disambiguated_by_the_center() {
+        // two opposite corners high, the average of the cell is 0.5
+        let saddle = HeightGrid::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]);
+        let contours_high = contours(&saddle, 2, 2, &[0.4]);
+        let contours_low = contours(&saddle, 2, 2, &[0.6]);
+
+        assert_eq!(2, contours_high.len());
+        assert_eq!(2, contours_low.len());
+        // with the middle above 0.4 the low corners (top right, bottom left) are cut off
+        let top_right = Vec2::new(1.0, 0.0);
+        assert!(contours_high.iter().any(|contour| contour
+            .points
+            .iter()
+            .all(|point| point.distance(top_right) < 0.7)));
+        // with the middle below 0.6 the high corners are cut off instead
+        let top_left = Vec2::ZERO;
+        assert!(contours_low.iter().any(|contour| contour
+            .points
+            .iter()
+            .all(|point| point.distance(top_left) < 0.5)));
+    }
+}
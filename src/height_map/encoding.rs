@@ -0,0 +1,187 @@
+//! height maps that store elevation in all three colour channels instead of a single grey value.
+//! This is what most public elevation tiles use, as 8 bits are not nearly enough for real
+//! terrain.
+//!
+//! * [Mapbox Terrain-RGB][0]: `-10000 + (R * 65536 + G * 256 + B) * 0.1`
+//! * [Terrarium][1]: `R * 256 + G + B / 256 - 32768`
+//!
+//! Both decode to metres.
+//!
+//! [0]: https://docs.mapbox.com/data/tilesets/reference/mapbox-terrain-rgb-v1/
+//! [1]: https://github.com/tilezen/joerd/blob/master/docs/formats.md#terrarium
+use std::path::Path;
+use std::str::FromStr;
+
+use bevy::prelude::Image;
+use bevy::render::render_resource::TextureFormat;
+use image::{Rgb, RgbImage};
+
+use crate::height_map::HeightSource;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RgbEncoding {
+    TerrainRgb,
+    Terrarium,
+}
+
+impl RgbEncoding {
+    /// the encoding is part of the file name, like in `tile.terrarium.png`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?.to_lowercase();
+        file_name.split('.').find_map(|part| part.parse().ok())
+    }
+
+    pub fn decode(&self, [r, g, b]: [u8; 3]) -> f32 {
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        match self {
+            RgbEncoding::TerrainRgb => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+            RgbEncoding::Terrarium => r * 256.0 + g + b / 256.0 - 32768.0,
+        }
+    }
+
+    /// the reverse of [`RgbEncoding::decode`]. Heights outside of the range an encoding can
+    /// represent are clamped.
+    pub fn encode(&self, height: f32) -> [u8; 3] {
+        match self {
+            RgbEncoding::TerrainRgb => {
+                let value = ((height as f64 + 10000.0) * 10.0)
+                    .round()
+                    .clamp(0.0, 16777215.0) as u32;
+                [(value >> 16) as u8, (value >> 8) as u8, value as u8]
+            }
+            RgbEncoding::Terrarium => {
+                let value = (height as f64 + 32768.0).clamp(0.0, 65535.0 + 255.0 / 256.0);
+                let whole = value.floor();
+                let fraction = ((value - whole) * 256.0).floor() as u32;
+                let whole = whole as u32;
+                [(whole >> 8) as u8, whole as u8, fraction as u8]
+            }
+        }
+    }
+}
+
+impl FromStr for RgbEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "terrainrgb" | "terrain-rgb" => Ok(RgbEncoding::TerrainRgb),
+            "terrarium" => Ok(RgbEncoding::Terrarium),
+            _ => anyhow::bail!("unknown rgb height encoding {:?}", s),
+        }
+    }
+}
+
+pub struct RgbEncodedHeightSource {
+    image: Image,
+    width: usize,
+    encoding: RgbEncoding,
+}
+
+impl RgbEncodedHeightSource {
+    pub fn new(image: Image, encoding: RgbEncoding) -> anyhow::Result<Self> {
+        let width = image.texture_descriptor.size.width as usize;
+        match image.texture_descriptor.format {
+            TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm => {}
+            // the channels would not be bytes any more, which makes no sense for these encodings
+            format => anyhow::bail!("unsupported texture format for rgb encoding: {:?}", format),
+        }
+        Ok(Self {
+            image,
+            width,
+            encoding,
+        })
+    }
+}
+
+impl HeightSource for RgbEncodedHeightSource {
+    #[inline]
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        let offset = (x + y * self.width) * 4;
+        let pixel = &self.image.data[offset..offset + 3];
+        self.encoding.decode([pixel[0], pixel[1], pixel[2]])
+    }
+}
+
+/// writes `width` x `height` samples of a height source (in metres) into an image, ready to be
+/// saved as PNG. Lossy formats like JPEG would destroy the encoding.
+pub fn encode_image<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    encoding: RgbEncoding,
+) -> RgbImage {
+    RgbImage::from_fn(width as u32, height as u32, |x, y| {
+        Rgb(encoding.encode(source.sample_height(x as usize, y as usize)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::render::render_resource::{Extent3d, TextureDimension};
+
+    #[test]
+    fn test_decode_known_values() {
+        assert_eq!(-10000.0, RgbEncoding::TerrainRgb.decode([0, 0, 0]));
+        // 100000 * 0.1 - 10000 = 0
+        assert_eq!(0.0, RgbEncoding::TerrainRgb.decode([1, 134, 160]));
+        assert_eq!(-32768.0, RgbEncoding::Terrarium.decode([0, 0, 0]));
+        assert_eq!(0.0, RgbEncoding::Terrarium.decode([128, 0, 0]));
+        assert_eq!(1.5, RgbEncoding::Terrarium.decode([128, 1, 128]));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        for height in [-420.0, -0.5, 0.0, 0.1, 8848.8, 1234.5] {
+            let terrain_rgb = RgbEncoding::TerrainRgb;
+            let decoded = terrain_rgb.decode(terrain_rgb.encode(height));
+            assert!((decoded - height).abs() < 0.05, "{} != {}", decoded, height);
+
+            let terrarium = RgbEncoding::Terrarium;
+            let decoded = terrarium.decode(terrarium.encode(height));
+            assert!(
+                (decoded - height).abs() < 1.0 / 256.0,
+                "{} != {}",
+                decoded,
+                height
+            );
+        }
+    }
+
+    #[test]
+    fn test_only_8_bit_images_decode() {
+        let image = |format| {
+            Image::new_fill(
+                Extent3d {
+                    width: 2,
+                    height: 2,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                &[0; 8],
+                format,
+            )
+        };
+        let source =
+            RgbEncodedHeightSource::new(image(TextureFormat::Rgba8Unorm), RgbEncoding::Terrarium)
+                .unwrap();
+        assert_eq!(-32768.0, source.sample_height(1, 1));
+        // what a 16 bit png turns into
+        let wide = image(TextureFormat::Rgba16Uint);
+        assert!(RgbEncodedHeightSource::new(wide, RgbEncoding::Terrarium).is_err());
+    }
+
+    #[test]
+    fn test_encoding_from_path() {
+        assert_eq!(
+            Some(RgbEncoding::Terrarium),
+            RgbEncoding::from_path(Path::new("tiles/12.terrarium.png"))
+        );
+        assert_eq!(
+            Some(RgbEncoding::TerrainRgb),
+            RgbEncoding::from_path(Path::new("/tmp/dem.TerrainRGB.png"))
+        );
+        assert_eq!(None, RgbEncoding::from_path(Path::new("dem.hm.png")));
+    }
+}
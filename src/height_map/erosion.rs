@@ -0,0 +1,396 @@
+//! erosion filters, to make generated or hand painted height maps look less artificial.
+//!
+//! All of them work on a [`HeightGrid`] in place. Unless they say otherwise, neighbouring
+//! samples are one unit apart.
+use bevy::math::Vec2;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::height_map::grid::HeightGrid;
+
+/// particle based hydraulic erosion, after [Hans Theobald Beyer's thesis][0] (and Sebastian
+/// Lague's take on it). Every iteration drops a single droplet of water at a random position,
+/// which runs downhill, picks up sediment where it speeds up and drops it again where it slows
+/// down or evaporates.
+///
+/// [0]: https://www.firespark.de/resources/downloads/implementation%20of%20a%20methode%20for%20hydraulic%20erosion.pdf
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HydraulicErosion {
+    /// number of droplets
+    pub iterations: usize,
+    /// the same seed and parameters always erode the same way
+    pub seed: u64,
+    /// 0 lets droplets follow the slope exactly, 1 lets them keep their direction forever
+    pub inertia: f32,
+    /// how much sediment a droplet can carry, relative to its speed, water and the slope
+    pub sediment_capacity: f32,
+    /// keeps droplets eroding a little even on flat ground
+    pub min_sediment_capacity: f32,
+    /// share of the free capacity picked up per step
+    pub erode_speed: f32,
+    /// share of the surplus sediment dropped per step
+    pub deposit_speed: f32,
+    /// share of the water that evaporates per step
+    pub evaporate_speed: f32,
+    pub gravity: f32,
+    /// steps after which a droplet is gone for good
+    pub max_lifetime: usize,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 50_000,
+            seed: 0,
+            inertia: 0.05,
+            sediment_capacity: 4.0,
+            min_sediment_capacity: 0.01,
+            erode_speed: 0.3,
+            deposit_speed: 0.3,
+            evaporate_speed: 0.01,
+            gravity: 4.0,
+            max_lifetime: 30,
+        }
+    }
+}
+
+impl HydraulicErosion {
+    pub fn erode(&self, grid: &mut HeightGrid) {
+        if grid.width() < 2 || grid.height() < 2 {
+            return;
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let max_x = (grid.width() - 1) as f32;
+        let max_y = (grid.height() - 1) as f32;
+
+        for _ in 0..self.iterations {
+            let mut position = Vec2::new(rng.gen_range(0.0..max_x), rng.gen_range(0.0..max_y));
+            let mut direction = Vec2::ZERO;
+            let mut speed = 1.0f32;
+            let mut water = 1.0f32;
+            let mut sediment = 0.0f32;
+
+            for _ in 0..self.max_lifetime {
+                let (height, gradient) = height_and_gradient(grid, position);
+                direction = direction * self.inertia - gradient * (1.0 - self.inertia);
+                if direction.length_squared() < 1e-12 {
+                    // a perfectly flat spot, nowhere to go
+                    break;
+                }
+                direction = direction.normalize();
+                let old_position = position;
+                position += direction;
+                if !(0.0..max_x).contains(&position.x) || !(0.0..max_y).contains(&position.y) {
+                    break;
+                }
+
+                let delta = height_and_gradient(grid, position).0 - height;
+                let capacity = (-delta * speed * water * self.sediment_capacity)
+                    .max(self.min_sediment_capacity);
+
+                if sediment > capacity || delta > 0.0 {
+                    // uphill: fill the pit we just left (but not higher than where we are now),
+                    // otherwise drop what we can not carry
+                    let amount = if delta > 0.0 {
+                        delta.min(sediment)
+                    } else {
+                        (sediment - capacity) * self.deposit_speed
+                    };
+                    sediment -= amount;
+                    add_bilinear(grid, old_position, amount);
+                } else {
+                    // never dig deeper than the step we took, or we would create new pits
+                    let amount = ((capacity - sediment) * self.erode_speed).min(-delta);
+                    sediment += amount;
+                    add_bilinear(grid, old_position, -amount);
+                }
+
+                speed = (speed * speed - delta * self.gravity).max(0.0).sqrt();
+                water *= 1.0 - self.evaporate_speed;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Neighbourhood {
+    /// only the direct neighbours, material moves along the grid axes
+    Four,
+    /// diagonals too, which keeps slopes from ending up in a diamond pattern
+    Eight,
+}
+
+impl Neighbourhood {
+    /// offsets of the neighbours and their distance in samples
+    fn offsets(&self) -> &'static [(isize, isize, f32)] {
+        const DIAGONAL: f32 = std::f32::consts::SQRT_2;
+        match self {
+            Neighbourhood::Four => &[(0, -1, 1.0), (-1, 0, 1.0), (1, 0, 1.0), (0, 1, 1.0)],
+            Neighbourhood::Eight => &[
+                (-1, -1, DIAGONAL),
+                (0, -1, 1.0),
+                (1, -1, DIAGONAL),
+                (-1, 0, 1.0),
+                (1, 0, 1.0),
+                (-1, 1, DIAGONAL),
+                (0, 1, 1.0),
+                (1, 1, DIAGONAL),
+            ],
+        }
+    }
+}
+
+/// thermal weathering: wherever the ground is steeper than the talus angle, material crumbles
+/// off and slides to the lower neighbours, until everything rests at (about) that angle. Good
+/// for cliffs that are too sharp and single sample spikes.
+///
+/// Material is only moved around, the total volume stays the same.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThermalErosion {
+    pub iterations: usize,
+    /// steepest slope that stays put, in degrees
+    pub talus_angle: f32,
+    /// horizontal distance between neighbouring samples, in the unit of the heights. A map with
+    /// 30m cells and heights in metres needs 30 here, or the angle means nothing.
+    pub sample_spacing: f32,
+    pub neighbourhood: Neighbourhood,
+    /// share of the excess moved per iteration. Above 0.5 cells start to swap heights back and
+    /// forth instead of settling.
+    pub strength: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 50,
+            talus_angle: 35.0,
+            sample_spacing: 1.0,
+            neighbourhood: Neighbourhood::Eight,
+            strength: 0.5,
+        }
+    }
+}
+
+impl ThermalErosion {
+    pub fn erode(&self, grid: &mut HeightGrid) {
+        let (width, height) = (grid.width(), grid.height());
+        let talus = self.talus_angle.to_radians().tan() * self.sample_spacing;
+        let offsets = self.neighbourhood.offsets();
+        let mut changes = vec![0.0f32; width * height];
+
+        for _ in 0..self.iterations {
+            changes.iter_mut().for_each(|change| *change = 0.0);
+            let mut moved = false;
+
+            // every cell only looks at the heights before this iteration, so the result does not
+            // depend on the order we walk the grid in
+            for y in 0..height {
+                for x in 0..width {
+                    let center = grid.get(x, y);
+                    let neighbours = offsets.iter().filter_map(|(dx, dy, distance)| {
+                        let nx = x as isize + dx;
+                        let ny = y as isize + dy;
+                        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                            return None;
+                        }
+                        let (nx, ny) = (nx as usize, ny as usize);
+                        let excess = center - grid.get(nx, ny) - talus * distance;
+                        if excess > 0.0 {
+                            Some((ny * width + nx, excess))
+                        } else {
+                            None
+                        }
+                    });
+
+                    let (mut total, mut max) = (0.0f32, 0.0f32);
+                    let mut steep = [(0, 0.0f32); 8];
+                    let mut count = 0;
+                    for (offset, excess) in neighbours {
+                        total += excess;
+                        max = max.max(excess);
+                        steep[count] = (offset, excess);
+                        count += 1;
+                    }
+                    if count == 0 {
+                        continue;
+                    }
+
+                    // the steepest drop decides how much goes, it is split by how steep each is
+                    let amount = max * self.strength;
+                    for (offset, excess) in &steep[..count] {
+                        changes[*offset] += amount * excess / total;
+                    }
+                    changes[y * width + x] -= amount;
+                    moved = true;
+                }
+            }
+
+            if !moved {
+                break;
+            }
+            for (sample, change) in grid.data_mut().iter_mut().zip(&changes) {
+                *sample += change;
+            }
+        }
+    }
+}
+
+/// bilinear height and its gradient at a position inside the grid
+fn height_and_gradient(grid: &HeightGrid, position: Vec2) -> (f32, Vec2) {
+    let (x, y) = (position.x as usize, position.y as usize);
+    let (fx, fy) = (position.x - x as f32, position.y - y as f32);
+    let top_left = grid.get(x, y);
+    let top_right = grid.get(x + 1, y);
+    let bottom_left = grid.get(x, y + 1);
+    let bottom_right = grid.get(x + 1, y + 1);
+
+    let gradient = Vec2::new(
+        (top_right - top_left) * (1.0 - fy) + (bottom_right - bottom_left) * fy,
+        (bottom_left - top_left) * (1.0 - fx) + (bottom_right - top_right) * fx,
+    );
+    let height = top_left * (1.0 - fx) * (1.0 - fy)
+        + top_right * fx * (1.0 - fy)
+        + bottom_left * (1.0 - fx) * fy
+        + bottom_right * fx * fy;
+
+    (height, gradient)
+}
+
+/// spreads `amount` over the four samples around `position`
+fn add_bilinear(grid: &mut HeightGrid, position: Vec2, amount: f32) {
+    let (x, y) = (position.x as usize, position.y as usize);
+    let (fx, fy) = (position.x - x as f32, position.y - y as f32);
+    for (sx, sy, weight) in [
+        (x, y, (1.0 - fx) * (1.0 - fy)),
+        (x + 1, y, fx * (1.0 - fy)),
+        (x, y + 1, (1.0 - fx) * fy),
+        (x + 1, y + 1, fx * fy),
+    ] {
+        grid.set(sx, sy, grid.get(sx, sy) + amount * weight);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cone() -> HeightGrid {
+        HeightGrid::from_fn(65, 65, |x, y| {
+            let (dx, dy) = (x as f32 - 32.0, y as f32 - 32.0);
+            // a little noise, so droplets do not all run straight down
+            let noise = ((x * 7919 + y * 104729) % 13) as f32 * 0.05;
+            (20.0 - (dx * dx + dy * dy).sqrt() * 0.6).max(0.0) + noise
+        })
+    }
+
+    fn volume(grid: &HeightGrid) -> f32 {
+        grid.data().iter().sum()
+    }
+
+    fn spike() -> HeightGrid {
+        let mut grid = HeightGrid::filled(15, 15, 1.0);
+        grid.set(7, 7, 21.0);
+        grid.set(3, 11, 6.0);
+        grid
+    }
+
+    fn steepest_slope(grid: &HeightGrid, spacing: f32) -> f32 {
+        let mut steepest = 0.0f32;
+        for y in 0..grid.height() {
+            for x in 1..grid.width() {
+                steepest = steepest.max((grid.get(x, y) - grid.get(x - 1, y)).abs() / spacing);
+                if y > 0 {
+                    steepest = steepest.max((grid.get(x, y) - grid.get(x, y - 1)).abs() / spacing);
+                }
+            }
+        }
+        steepest.atan().to_degrees()
+    }
+
+    #[test]
+    fn test_thermal_erosion_flattens_spikes() {
+        for neighbourhood in [Neighbourhood::Four, Neighbourhood::Eight] {
+            let original = spike();
+            let mut eroded = original.clone();
+            let erosion = ThermalErosion {
+                iterations: 500,
+                talus_angle: 45.0,
+                neighbourhood,
+                ..Default::default()
+            };
+            erosion.erode(&mut eroded);
+
+            assert!(eroded.get(7, 7) < 8.0, "{:?}", neighbourhood);
+            assert!(eroded.get(3, 11) < 3.0, "{:?}", neighbourhood);
+            assert!(steepest_slope(&eroded, 1.0) < 46.0, "{:?}", neighbourhood);
+            // nothing was lost or created on the way
+            assert!(
+                (volume(&original) - volume(&eroded)).abs() < 1e-3,
+                "{:?}",
+                neighbourhood
+            );
+        }
+    }
+
+    #[test]
+    fn test_thermal_erosion_uses_sample_spacing() {
+        // 20 up over 30 metres is about 34 degrees, less than the talus angle
+        let original = spike();
+        let mut eroded = original.clone();
+        ThermalErosion {
+            sample_spacing: 30.0,
+            ..Default::default()
+        }
+        .erode(&mut eroded);
+        assert_eq!(original, eroded);
+
+        ThermalErosion {
+            sample_spacing: 30.0,
+            talus_angle: 20.0,
+            ..Default::default()
+        }
+        .erode(&mut eroded);
+        assert!(eroded.get(7, 7) < original.get(7, 7));
+        assert!(steepest_slope(&eroded, 30.0) < 21.0);
+        assert!((volume(&original) - volume(&eroded)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_hydraulic_erosion_is_deterministic() {
+        let erosion = HydraulicErosion {
+            iterations: 2_000,
+            seed: 39,
+            ..Default::default()
+        };
+        let (mut a, mut b) = (cone(), cone());
+        erosion.erode(&mut a);
+        erosion.erode(&mut b);
+        assert_eq!(a, b);
+
+        let mut c = cone();
+        HydraulicErosion {
+            seed: 40,
+            ..erosion
+        }
+        .erode(&mut c);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_hydraulic_erosion_moves_material_downhill() {
+        let original = cone();
+        let mut eroded = original.clone();
+        HydraulicErosion {
+            iterations: 5_000,
+            ..Default::default()
+        }
+        .erode(&mut eroded);
+
+        assert_ne!(original, eroded);
+        // sediment only gets lost (off the map, or with droplets that die), never created
+        assert!(volume(&eroded) <= volume(&original) + 1e-2);
+        // the flank of the cone lost material
+        let slope = (20..28).map(|x| original.get(x, 32) - eroded.get(x, 32));
+        assert!(slope.sum::<f32>() > 0.0);
+    }
+}
@@ -0,0 +1,223 @@
+//! writers for getting terrain out of bevy and into other tools. Everything in here works on the
+//! plain positions/indices we produce in [`create_geometry`](crate::height_map::create_geometry),
+//! so it does not care whether the indices come from RTIN or from a full grid.
+//!
+//! All formats are written triangle by triangle, with the winding we use for rendering (counter
+//! clockwise when looking down onto the terrain). Use [`SolidBuilder`](super::solid::SolidBuilder)
+//! first if the result should be printable.
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Ascii,
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Obj,
+    Stl(Encoding),
+    Ply(Encoding),
+}
+
+impl ExportFormat {
+    /// guess the format from the file extension. STL and PLY default to their binary flavour, as
+    /// that is what most tools expect (and it is a lot smaller).
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "obj" => Some(ExportFormat::Obj),
+            "stl" => Some(ExportFormat::Stl(Encoding::Binary)),
+            "ply" => Some(ExportFormat::Ply(Encoding::Binary)),
+            _ => None,
+        }
+    }
+
+    pub fn write<W: Write>(
+        &self,
+        out: &mut W,
+        positions: &[[f32; 3]],
+        indices: &[u32],
+    ) -> io::Result<()> {
+        match self {
+            ExportFormat::Obj => write_obj(out, positions, indices),
+            ExportFormat::Stl(encoding) => write_stl(out, positions, indices, *encoding),
+            ExportFormat::Ply(encoding) => write_ply(out, positions, indices, *encoding),
+        }
+    }
+}
+
+pub fn write_obj<W: Write>(out: &mut W, positions: &[[f32; 3]], indices: &[u32]) -> io::Result<()> {
+    writeln!(out, "# terrain exported by venture")?;
+    for [x, y, z] in positions {
+        writeln!(out, "v {} {} {}", x, y, z)?;
+    }
+    // obj indices start at 1
+    for triangle in indices.chunks_exact(3) {
+        writeln!(
+            out,
+            "f {} {} {}",
+            triangle[0] + 1,
+            triangle[1] + 1,
+            triangle[2] + 1
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn write_stl<W: Write>(
+    out: &mut W,
+    positions: &[[f32; 3]],
+    indices: &[u32],
+    encoding: Encoding,
+) -> io::Result<()> {
+    match encoding {
+        Encoding::Ascii => {
+            writeln!(out, "solid terrain")?;
+            for triangle in indices.chunks_exact(3) {
+                let corners = triangle_corners(positions, triangle);
+                let [nx, ny, nz] = face_normal(&corners);
+                writeln!(out, "  facet normal {} {} {}", nx, ny, nz)?;
+                writeln!(out, "    outer loop")?;
+                for [x, y, z] in corners {
+                    writeln!(out, "      vertex {} {} {}", x, y, z)?;
+                }
+                writeln!(out, "    endloop")?;
+                writeln!(out, "  endfacet")?;
+            }
+            writeln!(out, "endsolid terrain")?;
+        }
+        Encoding::Binary => {
+            // 80 byte header that must not start with "solid", or some tools think it is ascii
+            let mut header = [0u8; 80];
+            let title = b"binary terrain exported by venture";
+            header[..title.len()].copy_from_slice(title);
+            out.write_all(&header)?;
+            out.write_all(&((indices.len() / 3) as u32).to_le_bytes())?;
+            for triangle in indices.chunks_exact(3) {
+                let corners = triangle_corners(positions, triangle);
+                for value in face_normal(&corners).iter().chain(corners.iter().flatten()) {
+                    out.write_all(&value.to_le_bytes())?;
+                }
+                // attribute byte count, unused
+                out.write_all(&[0, 0])?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_ply<W: Write>(
+    out: &mut W,
+    positions: &[[f32; 3]],
+    indices: &[u32],
+    encoding: Encoding,
+) -> io::Result<()> {
+    let format = match encoding {
+        Encoding::Ascii => "ascii",
+        Encoding::Binary => "binary_little_endian",
+    };
+    writeln!(out, "ply")?;
+    writeln!(out, "format {} 1.0", format)?;
+    writeln!(out, "comment terrain exported by venture")?;
+    writeln!(out, "element vertex {}", positions.len())?;
+    writeln!(out, "property float x")?;
+    writeln!(out, "property float y")?;
+    writeln!(out, "property float z")?;
+    writeln!(out, "element face {}", indices.len() / 3)?;
+    writeln!(out, "property list uchar uint vertex_indices")?;
+    writeln!(out, "end_header")?;
+
+    match encoding {
+        Encoding::Ascii => {
+            for [x, y, z] in positions {
+                writeln!(out, "{} {} {}", x, y, z)?;
+            }
+            for triangle in indices.chunks_exact(3) {
+                writeln!(out, "3 {} {} {}", triangle[0], triangle[1], triangle[2])?;
+            }
+        }
+        Encoding::Binary => {
+            for value in positions.iter().flatten() {
+                out.write_all(&value.to_le_bytes())?;
+            }
+            for triangle in indices.chunks_exact(3) {
+                out.write_all(&[3])?;
+                for index in triangle {
+                    out.write_all(&index.to_le_bytes())?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn triangle_corners(positions: &[[f32; 3]], triangle: &[u32]) -> [[f32; 3]; 3] {
+    [
+        positions[triangle[0] as usize],
+        positions[triangle[1] as usize],
+        positions[triangle[2] as usize],
+    ]
+}
+
+fn face_normal([a, b, c]: &[[f32; 3]; 3]) -> [f32; 3] {
+    let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+    let n = [
+        u[1] * v[2] - u[2] * v[1],
+        u[2] * v[0] - u[0] * v[2],
+        u[0] * v[1] - u[1] * v[0],
+    ];
+    let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+    if length == 0.0 {
+        return [0.0, 0.0, 0.0];
+    }
+
+    [n[0] / length, n[1] / length, n[2] / length]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // two triangles making up a unit square, as RTIN would build it for a 2x2 grid
+    fn square() -> (Vec<[f32; 3]>, Vec<u32>) {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+        ];
+        let indices = vec![0, 3, 1, 3, 0, 2];
+        (positions, indices)
+    }
+
+    #[test]
+    fn test_binary_stl_has_expected_layout() {
+        let (positions, indices) = square();
+        let mut out = Vec::new();
+        write_stl(&mut out, &positions, &indices, Encoding::Binary).unwrap();
+
+        assert_eq!(80 + 4 + 2 * 50, out.len());
+        assert_eq!(2, u32::from_le_bytes([out[80], out[81], out[82], out[83]]));
+        // the normal of the first facet has to point up
+        let ny = f32::from_le_bytes([out[88], out[89], out[90], out[91]]);
+        assert_eq!(1.0, ny);
+    }
+
+    #[test]
+    fn test_obj_indices_start_at_one() {
+        let (positions, indices) = square();
+        let mut out = Vec::new();
+        write_obj(&mut out, &positions, &indices).unwrap();
+        let obj = String::from_utf8(out).unwrap();
+
+        assert_eq!(4, obj.lines().filter(|l| l.starts_with("v ")).count());
+        assert!(obj.contains("f 1 4 2\n"));
+        assert!(obj.contains("f 4 1 3\n"));
+    }
+}
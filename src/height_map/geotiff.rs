@@ -0,0 +1,508 @@
+//! a small GeoTIFF reader for single band elevation rasters.
+//!
+//! It only understands what our GIS pipeline actually produces: uncompressed, one sample per
+//! pixel, `int16`, `uint16` or `float32`, in strips or tiles. That is a tiny part of TIFF, but it
+//! keeps us away from GDAL. The georeferencing comes from the `ModelPixelScale`, `ModelTiepoint`
+//! and `GeoKeyDirectory` tags, and the nodata value from GDAL's own `GDAL_NODATA` tag.
+use anyhow::{bail, Context};
+use bevy::reflect::TypeUuid;
+
+use crate::height_map::raster::Raster;
+
+const TAG_IMAGE_WIDTH: u16 = 256;
+const TAG_IMAGE_LENGTH: u16 = 257;
+const TAG_BITS_PER_SAMPLE: u16 = 258;
+const TAG_COMPRESSION: u16 = 259;
+const TAG_STRIP_OFFSETS: u16 = 273;
+const TAG_SAMPLES_PER_PIXEL: u16 = 277;
+const TAG_ROWS_PER_STRIP: u16 = 278;
+const TAG_TILE_WIDTH: u16 = 322;
+const TAG_TILE_LENGTH: u16 = 323;
+const TAG_TILE_OFFSETS: u16 = 324;
+const TAG_SAMPLE_FORMAT: u16 = 339;
+const TAG_MODEL_PIXEL_SCALE: u16 = 33550;
+const TAG_MODEL_TIEPOINT: u16 = 33922;
+const TAG_GEO_KEY_DIRECTORY: u16 = 34735;
+const TAG_GDAL_NODATA: u16 = 42113;
+
+const GEO_KEY_MODEL_TYPE: u32 = 1024;
+const GEO_KEY_GEOGRAPHIC_TYPE: u32 = 2048;
+const GEO_KEY_PROJECTED_CS_TYPE: u32 = 3072;
+const MODEL_TYPE_GEOGRAPHIC: u32 = 2;
+
+const SAMPLE_FORMAT_UINT: u32 = 1;
+const SAMPLE_FORMAT_INT: u32 = 2;
+const SAMPLE_FORMAT_FLOAT: u32 = 3;
+
+/// length of a degree along the equator (or a meridian, close enough for our purposes)
+const METRES_PER_DEGREE: f64 = 111_319.49;
+
+/// where a raster is on the earth. The raster loader hands it out as the `georeference` labelled
+/// asset next to the mesh, e.g. `dem.tif#georeference`.
+#[derive(Debug, Clone, PartialEq, TypeUuid)]
+#[uuid = "e4148327-0a66-4f0d-bd5e-9f142df0394f"]
+pub struct GeoReference {
+    /// coordinates of the outer (north west) corner of the first pixel, in CRS units
+    pub origin: [f64; 2],
+    /// width and height of a pixel, in CRS units
+    pub pixel_size: [f64; 2],
+    pub nodata: Option<f32>,
+    /// EPSG code of the coordinate reference system, if the file has one
+    pub crs: Option<u32>,
+    /// whether CRS units are degrees instead of metres
+    pub geographic: bool,
+}
+
+impl GeoReference {
+    /// the distance between two samples in metres. Pixels in geographic rasters are narrower
+    /// than they are tall away from the equator, we use the average of both at `latitude`.
+    pub fn cell_size_metres(&self, latitude: f64) -> f32 {
+        let [width, height] = self.pixel_size;
+        if self.geographic {
+            let width = width * METRES_PER_DEGREE * latitude.to_radians().cos();
+            let height = height * METRES_PER_DEGREE;
+            ((width + height) / 2.0) as f32
+        } else {
+            ((width + height) / 2.0) as f32
+        }
+    }
+
+    /// CRS coordinates of a position in samples. Samples sit in the middle of their pixels, and
+    /// y grows southwards in the raster.
+    pub fn sample_coordinates(&self, x: f64, y: f64) -> [f64; 2] {
+        [
+            self.origin[0] + (x + 0.5) * self.pixel_size[0],
+            self.origin[1] - (y + 0.5) * self.pixel_size[1],
+        ]
+    }
+}
+
+pub fn parse_geotiff(bytes: &[u8]) -> anyhow::Result<(Raster, GeoReference)> {
+    let tiff = Tiff::parse(bytes)?;
+
+    let width = tiff.single(TAG_IMAGE_WIDTH)? as usize;
+    let height = tiff.single(TAG_IMAGE_LENGTH)? as usize;
+    if tiff.single_or(TAG_SAMPLES_PER_PIXEL, 1)? != 1 {
+        bail!("only single band GeoTIFFs are supported");
+    }
+    if tiff.single_or(TAG_COMPRESSION, 1)? != 1 {
+        bail!("compressed GeoTIFFs are not supported, please export them uncompressed");
+    }
+    let sample_type = SampleType::from_tags(
+        tiff.single(TAG_BITS_PER_SAMPLE)?,
+        tiff.single_or(TAG_SAMPLE_FORMAT, SAMPLE_FORMAT_UINT)?,
+    )?;
+    let pixels = tiff.read_pixels(width, height, sample_type.bytes())?;
+    let data = pixels
+        .chunks_exact(sample_type.bytes())
+        .map(|sample| sample_type.decode(sample, tiff.big_endian))
+        .collect();
+
+    let scale = tiff.doubles(TAG_MODEL_PIXEL_SCALE)?;
+    let tiepoint = tiff.doubles(TAG_MODEL_TIEPOINT)?;
+    if scale.len() < 2 || tiepoint.len() < 6 {
+        bail!("GeoTIFF without pixel scale or tie point");
+    }
+    let pixel_size = [scale[0], scale[1]];
+    // the tie point links raster position (i, j) to model position (x, y)
+    let origin = [
+        tiepoint[3] - tiepoint[0] * pixel_size[0],
+        tiepoint[4] + tiepoint[1] * pixel_size[1],
+    ];
+
+    let nodata = match tiff.find(TAG_GDAL_NODATA) {
+        Some(entry) => {
+            let text = tiff.ascii(entry)?;
+            Some(
+                text.trim()
+                    .parse::<f32>()
+                    .with_context(|| format!("invalid nodata value {:?}", text))?,
+            )
+        }
+        None => None,
+    };
+
+    let geo_keys = tiff.geo_keys()?;
+    let projected = geo_keys.get(&GEO_KEY_PROJECTED_CS_TYPE).copied();
+    let geographic_crs = geo_keys.get(&GEO_KEY_GEOGRAPHIC_TYPE).copied();
+    let geographic = match geo_keys.get(&GEO_KEY_MODEL_TYPE) {
+        Some(model_type) => *model_type == MODEL_TYPE_GEOGRAPHIC,
+        None => projected.is_none() && geographic_crs.is_some(),
+    };
+
+    let georeference = GeoReference {
+        origin,
+        pixel_size,
+        nodata,
+        crs: projected.or(geographic_crs),
+        geographic,
+    };
+    let center_latitude = origin[1] - pixel_size[1] * height as f64 / 2.0;
+    let cell_size = georeference.cell_size_metres(center_latitude);
+
+    Ok((
+        Raster::new(width, height, cell_size, nodata, data),
+        georeference,
+    ))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SampleType {
+    I16,
+    U16,
+    F32,
+}
+
+impl SampleType {
+    fn from_tags(bits: u32, format: u32) -> anyhow::Result<Self> {
+        match (bits, format) {
+            (16, SAMPLE_FORMAT_INT) => Ok(SampleType::I16),
+            (16, SAMPLE_FORMAT_UINT) => Ok(SampleType::U16),
+            (32, SAMPLE_FORMAT_FLOAT) => Ok(SampleType::F32),
+            _ => bail!(
+                "unsupported sample type: {} bits, sample format {}",
+                bits,
+                format
+            ),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            SampleType::I16 | SampleType::U16 => 2,
+            SampleType::F32 => 4,
+        }
+    }
+
+    fn decode(&self, sample: &[u8], big_endian: bool) -> f32 {
+        match (self, big_endian) {
+            (SampleType::I16, false) => i16::from_le_bytes([sample[0], sample[1]]) as f32,
+            (SampleType::I16, true) => i16::from_be_bytes([sample[0], sample[1]]) as f32,
+            (SampleType::U16, false) => u16::from_le_bytes([sample[0], sample[1]]) as f32,
+            (SampleType::U16, true) => u16::from_be_bytes([sample[0], sample[1]]) as f32,
+            (SampleType::F32, false) => {
+                f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]])
+            }
+            (SampleType::F32, true) => {
+                f32::from_be_bytes([sample[0], sample[1], sample[2], sample[3]])
+            }
+        }
+    }
+}
+
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    count: usize,
+    /// position of the value (or the offset to it) in the file
+    value_position: usize,
+}
+
+struct Tiff<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+    entries: Vec<Entry>,
+}
+
+impl<'a> Tiff<'a> {
+    /// reads the header and the first IFD, which is all a single raster needs
+    fn parse(bytes: &'a [u8]) -> anyhow::Result<Self> {
+        let big_endian = match bytes.get(0..2) {
+            Some(b"II") => false,
+            Some(b"MM") => true,
+            _ => bail!("not a TIFF file"),
+        };
+        let mut tiff = Self {
+            bytes,
+            big_endian,
+            entries: vec![],
+        };
+        match tiff.u16(2)? {
+            42 => {}
+            43 => bail!("BigTIFF is not supported"),
+            magic => bail!("not a TIFF file (magic number {})", magic),
+        }
+
+        let ifd = tiff.u32(4)? as usize;
+        let count = tiff.u16(ifd)? as usize;
+        for index in 0..count {
+            let position = ifd + 2 + index * 12;
+            let entry = Entry {
+                tag: tiff.u16(position)?,
+                field_type: tiff.u16(position + 2)?,
+                count: tiff.u32(position + 4)? as usize,
+                value_position: position + 8,
+            };
+            tiff.entries.push(entry);
+        }
+
+        Ok(tiff)
+    }
+
+    fn find(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    fn slice(&self, position: usize, length: usize) -> anyhow::Result<&'a [u8]> {
+        self.bytes
+            .get(position..position + length)
+            .with_context(|| format!("TIFF is truncated at byte {}", position))
+    }
+
+    fn u16(&self, position: usize) -> anyhow::Result<u16> {
+        let bytes = self.slice(position, 2)?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, position: usize) -> anyhow::Result<u32> {
+        let bytes = self.slice(position, 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn f64(&self, position: usize) -> anyhow::Result<f64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.slice(position, 8)?);
+        Ok(if self.big_endian {
+            f64::from_be_bytes(bytes)
+        } else {
+            f64::from_le_bytes(bytes)
+        })
+    }
+
+    /// where the values of an entry start: right in the entry if they fit into four bytes,
+    /// somewhere else in the file otherwise
+    fn data_position(&self, entry: &Entry) -> anyhow::Result<usize> {
+        let size = match entry.field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            field_type => bail!("unsupported TIFF field type {}", field_type),
+        };
+        if size * entry.count <= 4 {
+            Ok(entry.value_position)
+        } else {
+            Ok(self.u32(entry.value_position)? as usize)
+        }
+    }
+
+    /// values of an integer entry (BYTE, SHORT or LONG)
+    fn integers(&self, entry: &Entry) -> anyhow::Result<Vec<u32>> {
+        let position = self.data_position(entry)?;
+        (0..entry.count)
+            .map(|index| match entry.field_type {
+                1 => Ok(self.slice(position + index, 1)?[0] as u32),
+                3 => Ok(self.u16(position + index * 2)? as u32),
+                4 => self.u32(position + index * 4),
+                field_type => bail!("expected an integer field, got type {}", field_type),
+            })
+            .collect()
+    }
+
+    fn single(&self, tag: u16) -> anyhow::Result<u32> {
+        let entry = self
+            .find(tag)
+            .with_context(|| format!("TIFF is missing tag {}", tag))?;
+        self.integers(entry)?
+            .first()
+            .copied()
+            .with_context(|| format!("TIFF tag {} is empty", tag))
+    }
+
+    fn single_or(&self, tag: u16, default: u32) -> anyhow::Result<u32> {
+        match self.find(tag) {
+            Some(_) => self.single(tag),
+            None => Ok(default),
+        }
+    }
+
+    fn doubles(&self, tag: u16) -> anyhow::Result<Vec<f64>> {
+        let entry = match self.find(tag) {
+            Some(entry) => entry,
+            None => return Ok(vec![]),
+        };
+        if entry.field_type != 12 {
+            bail!("expected doubles in TIFF tag {}", tag);
+        }
+        let position = self.data_position(entry)?;
+        (0..entry.count)
+            .map(|index| self.f64(position + index * 8))
+            .collect()
+    }
+
+    fn ascii(&self, entry: &Entry) -> anyhow::Result<String> {
+        let bytes = self.slice(self.data_position(entry)?, entry.count)?;
+        Ok(String::from_utf8_lossy(bytes)
+            .trim_end_matches('\0')
+            .to_string())
+    }
+
+    /// the GeoKeyDirectory as `key -> value`, for the keys that store their value inline
+    fn geo_keys(&self) -> anyhow::Result<std::collections::HashMap<u32, u32>> {
+        let mut keys = std::collections::HashMap::new();
+        let entry = match self.find(TAG_GEO_KEY_DIRECTORY) {
+            Some(entry) => entry,
+            None => return Ok(keys),
+        };
+        let directory = self.integers(entry)?;
+        // header: version, revision, minor revision, number of keys. Then four values per key:
+        // id, location (0 = inline), count, value
+        for key in directory.get(4..).unwrap_or_default().chunks_exact(4) {
+            if key[1] == 0 {
+                keys.insert(key[0], key[3]);
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// all pixels, row by row, no matter if the file uses strips or tiles
+    fn read_pixels(
+        &self,
+        width: usize,
+        height: usize,
+        bytes_per_pixel: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let row_length = width
+            .checked_mul(bytes_per_pixel)
+            .context("TIFF is too wide")?;
+        let length = row_length.checked_mul(height).context("TIFF is too big")?;
+        // the pixels are not compressed, so they can not be more than the whole file
+        if length > self.bytes.len() {
+            bail!(
+                "TIFF is truncated, {} x {} pixels do not fit into {} bytes",
+                width,
+                height,
+                self.bytes.len()
+            );
+        }
+        let mut pixels = vec![0u8; length];
+
+        if let Some(tile_offsets) = self.find(TAG_TILE_OFFSETS) {
+            let tile_offsets = self.integers(tile_offsets)?;
+            let tile_width = self.single(TAG_TILE_WIDTH)? as usize;
+            let tile_height = self.single(TAG_TILE_LENGTH)? as usize;
+            if tile_width == 0 || tile_height == 0 {
+                bail!("TIFF has empty {} x {} tiles", tile_width, tile_height);
+            }
+            let tiles_across = (0..width).step_by(tile_width).count();
+            let tile_row_length = tile_width * bytes_per_pixel;
+
+            for (index, offset) in tile_offsets.iter().enumerate() {
+                let tile_x = (index % tiles_across) * tile_width;
+                let tile_y = (index / tiles_across) * tile_height;
+                // tiles on the right and bottom edge are padded, we skip the padding
+                let copy_length = (width - tile_x).min(tile_width) * bytes_per_pixel;
+                for row in 0..tile_height.min(height.saturating_sub(tile_y)) {
+                    let source =
+                        self.slice(*offset as usize + row * tile_row_length, copy_length)?;
+                    let target = (tile_y + row) * row_length + tile_x * bytes_per_pixel;
+                    pixels[target..target + copy_length].copy_from_slice(source);
+                }
+            }
+        } else {
+            let strip_offsets = self
+                .find(TAG_STRIP_OFFSETS)
+                .context("TIFF has neither strips nor tiles")?;
+            let strip_offsets = self.integers(strip_offsets)?;
+            let rows_per_strip = self.single_or(TAG_ROWS_PER_STRIP, height as u32)? as usize;
+            if rows_per_strip == 0 {
+                bail!("TIFF has empty strips");
+            }
+            let strip_length = rows_per_strip * row_length;
+
+            for (index, offset) in strip_offsets.iter().enumerate() {
+                let target = index * strip_length;
+                if target >= pixels.len() {
+                    break;
+                }
+                // the last strip is usually shorter
+                let length = strip_length.min(pixels.len() - target);
+                pixels[target..target + length]
+                    .copy_from_slice(self.slice(*offset as usize, length)?);
+            }
+        }
+
+        Ok(pixels)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::HeightSource;
+
+    #[test]
+    fn test_projected_int16_strips() {
+        let (raster, georeference) =
+            parse_geotiff(include_bytes!("../../assets/small_utm.tif")).unwrap();
+
+        assert_eq!((4, 4), (raster.width, raster.height));
+        assert_eq!(30.0, raster.cell_size);
+        assert_eq!(
+            GeoReference {
+                origin: [500000.0, 5200000.0],
+                pixel_size: [30.0, 30.0],
+                nodata: Some(-9999.0),
+                crs: Some(32632),
+                geographic: false,
+            },
+            georeference
+        );
+        assert_eq!(-5.0, raster.sample_height(0, 0));
+        assert_eq!(150.0, raster.sample_height(3, 3));
+        // the nodata cell was filled with the lowest valid height
+        assert_eq!(-5.0, raster.sample_height(1, 1));
+    }
+
+    #[test]
+    fn test_geographic_float32_tiles() {
+        let (raster, georeference) =
+            parse_geotiff(include_bytes!("../../assets/small_wgs84.tif")).unwrap();
+
+        assert_eq!((5, 3), (raster.width, raster.height));
+        assert_eq!(Some(4326), georeference.crs);
+        assert!(georeference.geographic);
+        assert_eq!([10.0, 46.0], georeference.origin);
+        assert_eq!(0.5, raster.sample_height(0, 0));
+        assert_eq!(14.5, raster.sample_height(4, 2));
+        // a 1/1200 degree pixel at 46 degrees north is about 93 by 65 metres
+        assert!((raster.cell_size - 79.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_rejects_non_tiff() {
+        assert!(parse_geotiff(b"P6 not a tiff").is_err());
+    }
+
+    #[test]
+    fn test_rejects_broken_dimensions() {
+        let fixture = include_bytes!("../../assets/small_wgs84.tif");
+        let tag_position = |tag| {
+            let tiff = Tiff::parse(fixture).unwrap();
+            let position = tiff.find(tag).unwrap().value_position;
+            position
+        };
+
+        let mut empty_tiles = fixture.to_vec();
+        let position = tag_position(TAG_TILE_WIDTH);
+        empty_tiles[position..position + 4].fill(0);
+        assert!(parse_geotiff(&empty_tiles).is_err());
+
+        // way more pixels than there are bytes in the file
+        let mut huge = fixture.to_vec();
+        let position = tag_position(TAG_IMAGE_LENGTH);
+        huge[position..position + 2].fill(0xff);
+        assert!(parse_geotiff(&huge).is_err());
+    }
+}
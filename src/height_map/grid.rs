@@ -0,0 +1,565 @@
+//! an owned grid of heights, for everything that wants to change, resize or keep samples around
+//! instead of asking a [`HeightSource`] for every single one.
+use std::f32::consts::PI;
+use std::ops::RangeInclusive;
+
+use image::{GrayImage, Luma};
+
+use crate::height_map::HeightSource;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightGrid {
+    width: usize,
+    height: usize,
+    /// row by row
+    data: Vec<f32>,
+}
+
+/// a [`HeightGrid`] that still knows which of its samples are voids, for sources with nodata
+/// that get copied (or resampled) and should keep their holes
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaskedGrid {
+    pub grid: HeightGrid,
+    /// `false` for voids, row by row. `None` if there are none.
+    pub mask: Option<Vec<bool>>,
+}
+
+/// how to interpolate between samples when resampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resampling {
+    Bilinear,
+    /// Catmull-Rom, sharper than bilinear and still without much ringing
+    Bicubic,
+    /// Lanczos with three lobes, the sharpest, but it can overshoot at steep edges
+    Lanczos3,
+}
+
+impl Resampling {
+    /// how many samples the kernel reaches out to either side
+    pub(crate) fn radius(&self) -> f32 {
+        match self {
+            Resampling::Bilinear => 1.0,
+            Resampling::Bicubic => 2.0,
+            Resampling::Lanczos3 => 3.0,
+        }
+    }
+
+    /// how far an interpolated height can get above the highest (or below the lowest) sample
+    /// under the kernel, in multiples of their range. That is the sum of the negative weights of
+    /// the 2D kernel, at its worst between the samples.
+    pub(crate) fn overshoot(&self) -> f32 {
+        match self {
+            Resampling::Bilinear => 0.0,
+            Resampling::Bicubic => 0.3,
+            Resampling::Lanczos3 => 0.7,
+        }
+    }
+
+    fn weight(&self, distance: f32) -> f32 {
+        let d = distance.abs();
+        match self {
+            Resampling::Bilinear => (1.0 - d).max(0.0),
+            Resampling::Bicubic => {
+                // Catmull-Rom is the cubic convolution kernel with a = -0.5
+                let a = -0.5;
+                if d < 1.0 {
+                    (a + 2.0) * d * d * d - (a + 3.0) * d * d + 1.0
+                } else if d < 2.0 {
+                    a * d * d * d - 5.0 * a * d * d + 8.0 * a * d - 4.0 * a
+                } else {
+                    0.0
+                }
+            }
+            Resampling::Lanczos3 => {
+                if d < 1e-6 {
+                    1.0
+                } else if d < 3.0 {
+                    3.0 * (PI * d).sin() * (PI * d / 3.0).sin() / (PI * PI * d * d)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// convolves the samples around `x`, `y` with the kernel, stretched by `scale_x` and
+    /// `scale_y`. `sample` has to handle positions outside the grid itself.
+    pub(crate) fn filter(
+        &self,
+        x: f32,
+        y: f32,
+        scale_x: f32,
+        scale_y: f32,
+        sample: impl Fn(isize, isize) -> f32,
+    ) -> f32 {
+        let radius_x = self.radius() * scale_x;
+        let radius_y = self.radius() * scale_y;
+
+        let (mut sum, mut weight_sum) = (0.0, 0.0);
+        for sy in (y - radius_y).floor() as isize..=(y + radius_y).ceil() as isize {
+            let weight_y = self.weight((sy as f32 - y) / scale_y);
+            if weight_y == 0.0 {
+                continue;
+            }
+            for sx in (x - radius_x).floor() as isize..=(x + radius_x).ceil() as isize {
+                let weight = self.weight((sx as f32 - x) / scale_x) * weight_y;
+                sum += sample(sx, sy) * weight;
+                weight_sum += weight;
+            }
+        }
+
+        sum / weight_sum
+    }
+}
+
+/// a rectangle of samples, e.g. the ones an edit touched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GridRect {
+    pub x: RangeInclusive<usize>,
+    pub y: RangeInclusive<usize>,
+}
+
+impl GridRect {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        self.x.contains(&x) && self.y.contains(&y)
+    }
+
+    pub fn overlaps(&self, other: &GridRect) -> bool {
+        self.x.start() <= other.x.end()
+            && other.x.start() <= self.x.end()
+            && self.y.start() <= other.y.end()
+            && other.y.start() <= self.y.end()
+    }
+
+    /// the smallest rectangle covering both
+    pub fn union(&self, other: &GridRect) -> GridRect {
+        GridRect {
+            x: *self.x.start().min(other.x.start())..=*self.x.end().max(other.x.end()),
+            y: *self.y.start().min(other.y.start())..=*self.y.end().max(other.y.end()),
+        }
+    }
+
+    /// `by` samples bigger on every side, but still within a `width` x `height` grid
+    pub fn grow(&self, by: usize, width: usize, height: usize) -> GridRect {
+        GridRect {
+            x: self.x.start().saturating_sub(by)..=(self.x.end() + by).min(width - 1),
+            y: self.y.start().saturating_sub(by)..=(self.y.end() + by).min(height - 1),
+        }
+    }
+}
+
+/// a summary of all samples of a grid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GridStatistics {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std_dev: f32,
+}
+
+impl HeightGrid {
+    /// `data` is row by row and has to hold `width * height` samples
+    pub fn new(width: usize, height: usize, data: Vec<f32>) -> Self {
+        assert_eq!(
+            width * height,
+            data.len(),
+            "a {}x{} grid needs {} samples",
+            width,
+            height,
+            width * height
+        );
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn filled(width: usize, height: usize, value: f32) -> Self {
+        Self::new(width, height, vec![value; width * height])
+    }
+
+    pub fn from_fn(width: usize, height: usize, f: impl Fn(usize, usize) -> f32) -> Self {
+        let data = (0..width * height)
+            .map(|offset| f(offset % width, offset / width))
+            .collect();
+        Self::new(width, height, data)
+    }
+
+    /// copies the first `width` x `height` samples of any source
+    pub fn from_source<H: HeightSource>(source: &H, width: usize, height: usize) -> Self {
+        Self::from_fn(width, height, |x, y| source.sample_height(x, y))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> Vec<f32> {
+        self.data
+    }
+
+    #[inline]
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        debug_assert!(x < self.width && y < self.height);
+        self.data[y * self.width + x]
+    }
+
+    #[inline]
+    pub fn set(&mut self, x: usize, y: usize, value: f32) {
+        debug_assert!(x < self.width && y < self.height);
+        self.data[y * self.width + x] = value;
+    }
+
+    /// the sample at `x`, `y`, or the closest one on the border for positions outside the grid
+    #[inline]
+    fn get_clamped(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.data[y * self.width + x]
+    }
+
+    /// the part of the grid starting at `x`, `y` that is `width` x `height` samples big
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        assert!(
+            x + width <= self.width && y + height <= self.height,
+            "crop is outside of the grid"
+        );
+        Self::from_fn(width, height, |cx, cy| self.get(x + cx, y + cy))
+    }
+
+    /// a new grid with `width` x `height` samples covering the same area. The corner samples
+    /// stay where they are, which is what we want for meshes that use every sample as a vertex
+    /// (like RTIN, which needs 2^n + 1 samples).
+    pub fn resample(&self, width: usize, height: usize, resampling: Resampling) -> Self {
+        let step_x = step(self.width, width);
+        let step_y = step(self.height, height);
+        // when shrinking, the kernel has to get wider, otherwise samples in between get skipped
+        let scale_x = step_x.max(1.0);
+        let scale_y = step_y.max(1.0);
+
+        Self::from_fn(width, height, |x, y| {
+            self.filtered(
+                x as f32 * step_x,
+                y as f32 * step_y,
+                resampling,
+                scale_x,
+                scale_y,
+            )
+        })
+    }
+
+    /// interpolates at a position in grid coordinates, with the kernel stretched by `scale_x`
+    /// and `scale_y`
+    fn filtered(&self, x: f32, y: f32, resampling: Resampling, scale_x: f32, scale_y: f32) -> f32 {
+        resampling.filter(x, y, scale_x, scale_y, |sx, sy| self.get_clamped(sx, sy))
+    }
+
+    /// the height at a position between samples. Positions outside the grid get the height of
+    /// the closest border sample.
+    pub fn sample_interpolated(&self, x: f32, y: f32, resampling: Resampling) -> f32 {
+        self.filtered(x, y, resampling, 1.0, 1.0)
+    }
+
+    /// lowest and highest sample
+    pub fn min_max(&self) -> (f32, f32) {
+        self.data
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), value| {
+                (min.min(*value), max.max(*value))
+            })
+    }
+
+    pub fn statistics(&self) -> GridStatistics {
+        let (min, max) = self.min_max();
+        let count = self.data.len() as f64;
+        let mean = self.data.iter().map(|value| *value as f64).sum::<f64>() / count;
+        let variance = self
+            .data
+            .iter()
+            .map(|value| (*value as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+
+        GridStatistics {
+            min,
+            max,
+            mean: mean as f32,
+            std_dev: variance.sqrt() as f32,
+        }
+    }
+
+    /// a grey image from black (lowest sample) to white (highest), to look at derived grids
+    /// like slopes or flow accumulation in any image viewer
+    pub fn to_image(&self) -> GrayImage {
+        let (min, max) = self.min_max();
+        let range = if max > min { max - min } else { 1.0 };
+        GrayImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let value = (self.get(x as usize, y as usize) - min) / range;
+            Luma([(value * 255.0).round() as u8])
+        })
+    }
+}
+
+impl MaskedGrid {
+    /// copies the first `width` x `height` samples of any source, and which of them are valid
+    pub fn from_source<H: HeightSource>(source: &H, width: usize, height: usize) -> Self {
+        let mask: Vec<bool> = (0..width * height)
+            .map(|offset| source.is_valid(offset % width, offset / width))
+            .collect();
+        Self {
+            grid: HeightGrid::from_source(source, width, height),
+            mask: if mask.contains(&false) {
+                Some(mask)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// like [`HeightGrid::resample`]. A new sample is a void if any of the samples it sits
+    /// between is one, so the made up heights of the voids never leak into valid samples.
+    pub fn resample(&self, width: usize, height: usize, resampling: Resampling) -> Self {
+        let (source_width, source_height) = (self.grid.width, self.grid.height);
+        let step_x = step(source_width, width);
+        let step_y = step(source_height, height);
+        let mask = self.mask.as_ref().map(|mask| {
+            let around = |position: f32, last: usize| {
+                position.floor() as usize..=(position.ceil() as usize).min(last)
+            };
+            (0..width * height)
+                .map(|offset| {
+                    let x = (offset % width) as f32 * step_x;
+                    let y = (offset / width) as f32 * step_y;
+                    around(y, source_height - 1).all(|sy| {
+                        around(x, source_width - 1).all(|sx| mask[sy * source_width + sx])
+                    })
+                })
+                .collect()
+        });
+
+        Self {
+            grid: self.grid.resample(width, height, resampling),
+            mask,
+        }
+    }
+}
+
+impl MaskedGrid {
+    /// grows the grid to `width` x `height` samples (at least as many as it has). The new ones
+    /// are voids, holding the height of the closest old sample.
+    pub fn pad(&self, width: usize, height: usize) -> Self {
+        let (old_width, old_height) = (self.grid.width, self.grid.height);
+        debug_assert!(width >= old_width && height >= old_height);
+        let grid = HeightGrid::from_fn(width, height, |x, y| {
+            self.grid.get(x.min(old_width - 1), y.min(old_height - 1))
+        });
+        let mask: Vec<bool> = (0..width * height)
+            .map(|offset| {
+                let (x, y) = (offset % width, offset / width);
+                x < old_width && y < old_height && self.is_valid(x, y)
+            })
+            .collect();
+
+        Self {
+            grid,
+            mask: if mask.contains(&false) {
+                Some(mask)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl From<HeightGrid> for MaskedGrid {
+    fn from(grid: HeightGrid) -> Self {
+        Self { grid, mask: None }
+    }
+}
+
+impl HeightSource for MaskedGrid {
+    #[inline]
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        self.grid.get(x, y)
+    }
+
+    fn is_valid(&self, x: usize, y: usize) -> bool {
+        match &self.mask {
+            Some(mask) => mask[y * self.grid.width + x],
+            None => true,
+        }
+    }
+}
+
+/// distance in source samples between two target samples
+fn step(source: usize, target: usize) -> f32 {
+    if target > 1 {
+        (source - 1) as f32 / (target - 1) as f32
+    } else {
+        0.0
+    }
+}
+
+impl HeightSource for HeightGrid {
+    #[inline]
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        self.get(x, y)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALL: [Resampling; 3] = [
+        Resampling::Bilinear,
+        Resampling::Bicubic,
+        Resampling::Lanczos3,
+    ];
+
+    fn plane(x: f32, y: f32) -> f32 {
+        x * 0.5 - y * 2.0 + 10.0
+    }
+
+    #[test]
+    fn test_overshoot_is_bounded() {
+        // a checkerboard of 0 and 1 is as bad as it gets for the negative lobes
+        let grid = HeightGrid::from_fn(8, 8, |x, y| ((x + y) % 2) as f32);
+        for resampling in ALL {
+            for step in 0..=70 {
+                let (x, y) = (step as f32 / 10.0, (70 - step) as f32 / 10.0);
+                for (x, y) in [(x, y), (x, 3.5), (3.5, y)] {
+                    let height = grid.sample_interpolated(x, y, resampling);
+                    let limit = resampling.overshoot();
+                    assert!(
+                        (-limit..=1.0 + limit).contains(&height),
+                        "{:?} at {}, {}: {}",
+                        resampling,
+                        x,
+                        y,
+                        height
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_size_resampling_is_identity() {
+        let grid = HeightGrid::from_fn(7, 5, |x, y| ((x * 13 + y * 7) % 5) as f32);
+        for resampling in ALL {
+            let resampled = grid.resample(7, 5, resampling);
+            for (a, b) in grid.data().iter().zip(resampled.data()) {
+                assert!((a - b).abs() < 1e-5, "{:?}: {} != {}", resampling, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_upsampling_keeps_a_plane() {
+        let grid = HeightGrid::from_fn(5, 5, |x, y| plane(x as f32, y as f32));
+        // bilinear and Catmull-Rom reproduce linear functions, Lanczos only gets close
+        for (resampling, tolerance) in [
+            (Resampling::Bilinear, 1e-4),
+            (Resampling::Bicubic, 1e-4),
+            (Resampling::Lanczos3, 0.1),
+        ] {
+            let resampled = grid.resample(9, 9, resampling);
+            for y in 0..9 {
+                for x in 0..9 {
+                    let expected = plane(x as f32 / 2.0, y as f32 / 2.0);
+                    let actual = resampled.get(x, y);
+                    // the border clamps, which bends the plane for the wider kernels
+                    if resampling == Resampling::Bilinear
+                        || (2..7).contains(&x) && (2..7).contains(&y)
+                    {
+                        assert!(
+                            (actual - expected).abs() < tolerance,
+                            "{:?} at {}, {}: {} != {}",
+                            resampling,
+                            x,
+                            y,
+                            actual,
+                            expected
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_downsampling_stays_in_range() {
+        let grid = HeightGrid::from_fn(33, 33, |x, y| (x as f32 * 0.4).sin() + y as f32 * 0.1);
+        let (min, max) = grid.min_max();
+        let resampled = grid.resample(9, 9, Resampling::Bilinear);
+
+        assert_eq!((9, 9), (resampled.width(), resampled.height()));
+        let (resampled_min, resampled_max) = resampled.min_max();
+        assert!(resampled_min >= min && resampled_max <= max);
+    }
+
+    #[test]
+    fn test_resampling_keeps_voids() {
+        let mut source = MaskedGrid::from(HeightGrid::from_fn(5, 5, |x, _| x as f32));
+        assert_eq!(None, MaskedGrid::from_source(&source, 5, 5).mask);
+        let mut mask = vec![true; 25];
+        mask[2 * 5 + 2] = false;
+        source.mask = Some(mask);
+
+        let resampled = source.resample(9, 9, Resampling::Bilinear);
+        assert_eq!(3.0, resampled.sample_height(6, 2));
+        // everything between the void and its neighbours is a void as well
+        for y in 0..9 {
+            for x in 0..9 {
+                let void = (3..=5).contains(&x) && (3..=5).contains(&y);
+                assert_eq!(!void, resampled.is_valid(x, y), "{}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_padding_adds_voids() {
+        let source = MaskedGrid::from(HeightGrid::from_fn(3, 2, |x, y| (x + y * 3) as f32));
+        let padded = source.pad(3, 3);
+
+        assert_eq!(source.grid.data(), &padded.grid.data()[..6]);
+        // the new row repeats the last one
+        assert_eq!(&[3.0, 4.0, 5.0], &padded.grid.data()[6..]);
+        assert_eq!(
+            Some(vec![
+                true, true, true, true, true, true, false, false, false
+            ]),
+            padded.mask
+        );
+        assert_eq!(source, source.pad(3, 2));
+    }
+
+    #[test]
+    fn test_crop_and_statistics() {
+        let grid = HeightGrid::from_fn(4, 3, |x, y| (x + y * 4) as f32);
+        let cropped = grid.crop(1, 1, 2, 2);
+        assert_eq!(&[5.0, 6.0, 9.0, 10.0], cropped.data());
+
+        let statistics = cropped.statistics();
+        assert_eq!((5.0, 10.0), (statistics.min, statistics.max));
+        assert_eq!(7.5, statistics.mean);
+        assert_eq!(
+            ((2.5f32 * 2.5 + 1.5 * 1.5) / 2.0).sqrt(),
+            statistics.std_dev
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! shaded relief maps, rendered on the CPU: a 2D picture of the terrain lit by a low sun, like
+//! on any topographic map. Also good as a small preview of a height map.
+use bevy::math::Vec3;
+use bevy::prelude::Image;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use image::{Rgb, RgbImage};
+
+use crate::height_map::analysis::{gradient, window};
+use crate::height_map::grid::{HeightGrid, MaskedGrid, Resampling};
+use crate::height_map::HeightSource;
+
+/// elevation colours from the lowest to the highest sample, a classic hypsometric tint
+const TINT: [(f32, [f32; 3]); 4] = [
+    (0.0, [0.33, 0.55, 0.33]),
+    (0.4, [0.85, 0.8, 0.55]),
+    (0.75, [0.6, 0.45, 0.3]),
+    (1.0, [0.97, 0.97, 0.97]),
+];
+
+/// light that reaches the tinted terrain even in full shadow, or shadows would turn black
+const AMBIENT: f32 = 0.35;
+
+/// the grey of voids
+const VOID: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hillshade {
+    /// where the light comes from, in degrees clockwise from north (up in the image)
+    pub azimuth: f32,
+    /// degrees above the horizon
+    pub altitude: f32,
+    /// exaggerates the heights, flat terrain needs more than 1 to show anything
+    pub z_factor: f32,
+    /// horizontal distance between neighbouring samples, in the unit of the heights
+    pub cell_size: f32,
+    /// blends four lights, 45° apart around the azimuth, weighted by which way the ground faces
+    /// ([Mark 1992][0]). Slopes parallel to a single light would stay flat grey otherwise.
+    ///
+    /// [0]: https://pubs.usgs.gov/of/1992/of92-422/of92-422.pdf
+    pub multi_directional: bool,
+    /// colours the relief by elevation instead of just grey
+    pub tint: bool,
+}
+
+impl Default for Hillshade {
+    fn default() -> Self {
+        Self {
+            azimuth: 315.0,
+            altitude: 45.0,
+            z_factor: 1.0,
+            cell_size: 1.0,
+            multi_directional: false,
+            tint: false,
+        }
+    }
+}
+
+impl Hillshade {
+    /// how much light every sample gets, from 0 (in shadow) to 1 (facing the sun)
+    pub fn shade<H: HeightSource>(&self, source: &H, width: usize, height: usize) -> HeightGrid {
+        let altitude = self.altitude.to_radians();
+        let light = |azimuth: f32| {
+            let azimuth = azimuth.to_radians();
+            Vec3::new(
+                azimuth.sin() * altitude.cos(),
+                azimuth.cos() * altitude.cos(),
+                altitude.sin(),
+            )
+        };
+        let single = [(light(self.azimuth), 0.0)];
+        let multiple = [-90.0, -45.0, 0.0, 45.0].map(|offset| {
+            let azimuth = self.azimuth + offset;
+            (light(azimuth), azimuth)
+        });
+
+        HeightGrid::from_fn(width, height, |x, y| {
+            let (east, north) = gradient(window(source, width, height, x, y), self.cell_size);
+            let (east, north) = (east * self.z_factor, north * self.z_factor);
+            // in east, north, up
+            let normal = Vec3::new(-east, -north, 1.0).normalize();
+            if !self.multi_directional {
+                return normal.dot(single[0].0).max(0.0);
+            }
+
+            if east == 0.0 && north == 0.0 {
+                return multiple
+                    .iter()
+                    .map(|(light, _)| normal.dot(*light).max(0.0))
+                    .sum::<f32>()
+                    / 4.0;
+            }
+            // the weights of lights 45° apart always add up to 2
+            let aspect = (-east).atan2(-north);
+            multiple
+                .iter()
+                .map(|(light, azimuth)| {
+                    let weight = (aspect - azimuth.to_radians() + 45f32.to_radians())
+                        .sin()
+                        .powi(2);
+                    weight * normal.dot(*light).max(0.0)
+                })
+                .sum::<f32>()
+                / 2.0
+        })
+    }
+
+    /// voids are grey, and do not count for the range of the tint
+    pub fn render<H: HeightSource>(&self, source: &H, width: usize, height: usize) -> RgbImage {
+        let shade = self.shade(source, width, height);
+        let (min, max) = if self.tint {
+            (0..width * height)
+                .map(|offset| (offset % width, offset / width))
+                .filter(|(x, y)| source.is_valid(*x, *y))
+                .map(|(x, y)| source.sample_height(x, y))
+                .fold((f32::MAX, f32::MIN), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                })
+        } else {
+            (0.0, 0.0)
+        };
+
+        RgbImage::from_fn(width as u32, height as u32, |x, y| {
+            let (x, y) = (x as usize, y as usize);
+            let light = shade.get(x, y);
+            let colour = if !source.is_valid(x, y) {
+                Vec3::splat(VOID)
+            } else if self.tint {
+                let elevation = if max > min {
+                    (source.sample_height(x, y) - min) / (max - min)
+                } else {
+                    0.0
+                };
+                tint(elevation) * (AMBIENT + (1.0 - AMBIENT) * light)
+            } else {
+                Vec3::splat(light)
+            };
+            let colour = colour * 255.0;
+            Rgb([
+                colour.x.round() as u8,
+                colour.y.round() as u8,
+                colour.z.round() as u8,
+            ])
+        })
+    }
+
+    /// like [`Hillshade::render`], but at most `max_size` pixels wide and high
+    pub fn thumbnail<H: HeightSource>(
+        &self,
+        source: &H,
+        width: usize,
+        height: usize,
+        max_size: usize,
+    ) -> RgbImage {
+        let scale = (max_size as f32 / width.max(height) as f32).min(1.0);
+        let (thumb_width, thumb_height) = (
+            ((width as f32 * scale).round() as usize).max(1),
+            ((height as f32 * scale).round() as usize).max(1),
+        );
+        let grid = MaskedGrid::from_source(source, width, height).resample(
+            thumb_width,
+            thumb_height,
+            Resampling::Bilinear,
+        );
+        Self {
+            cell_size: self.cell_size / scale,
+            ..*self
+        }
+        .render(&grid, thumb_width, thumb_height)
+    }
+}
+
+fn tint(elevation: f32) -> Vec3 {
+    let elevation = elevation.clamp(0.0, 1.0);
+    for pair in TINT.windows(2) {
+        let ((from, low), (to, high)) = (pair[0], pair[1]);
+        if elevation <= to {
+            let t = (elevation - from) / (to - from);
+            return Vec3::from(low).lerp(Vec3::from(high), t);
+        }
+    }
+    Vec3::from(TINT[TINT.len() - 1].1)
+}
+
+/// a rendered image as a texture, for the ui or a material
+pub fn texture(image: &RgbImage) -> Image {
+    let pixels = image
+        .pixels()
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+        .collect();
+    Image::new(
+        Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        pixels,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// a ridge running north to south, with a western and an eastern flank
+    fn ridge() -> HeightGrid {
+        HeightGrid::from_fn(9, 9, |x, _| 4.0 - (x as f32 - 4.0).abs())
+    }
+
+    #[test]
+    fn test_light_from_the_west() {
+        let hillshade = Hillshade {
+            azimuth: 270.0,
+            altitude: 45.0,
+            ..Default::default()
+        };
+        let shade = hillshade.shade(&ridge(), 9, 9);
+        // 45° slopes, one facing the sun straight on, one turned away
+        assert!((shade.get(2, 4) - 1.0).abs() < 1e-5, "{}", shade.get(2, 4));
+        assert_eq!(0.0, shade.get(6, 4));
+        // flat ground gets sin(altitude)
+        let flat = hillshade.shade(&HeightGrid::filled(3, 3, 1.0), 3, 3);
+        assert!((flat.get(1, 1) - 45f32.to_radians().sin()).abs() < 1e-5);
+
+        // exaggerating the heights or shrinking the cells makes it steeper, so darker
+        let steeper = Hillshade {
+            z_factor: 2.0,
+            ..hillshade
+        };
+        assert!(steeper.shade(&ridge(), 9, 9).get(2, 4) < 1.0);
+        let closer = Hillshade {
+            cell_size: 0.5,
+            ..hillshade
+        };
+        assert_eq!(steeper.shade(&ridge(), 9, 9), closer.shade(&ridge(), 9, 9));
+    }
+
+    #[test]
+    fn test_multi_directional_lights_slopes_along_the_sun() {
+        // the sun in the north shines along both flanks of the ridge, they look the same
+        let single = Hillshade {
+            azimuth: 0.0,
+            ..Default::default()
+        };
+        let multi = Hillshade {
+            multi_directional: true,
+            ..single
+        };
+        let single_shade = single.shade(&ridge(), 9, 9);
+        assert_eq!(single_shade.get(2, 4), single_shade.get(6, 4));
+        let multi_shade = multi.shade(&ridge(), 9, 9);
+        assert!(multi_shade.get(2, 4) != multi_shade.get(6, 4));
+        for value in multi_shade.data() {
+            assert!((0.0..=1.0).contains(value));
+        }
+    }
+
+    #[test]
+    fn test_render_and_thumbnail() {
+        let hillshade = Hillshade {
+            tint: true,
+            ..Default::default()
+        };
+        let image = hillshade.render(&ridge(), 9, 9);
+        assert_eq!((9, 9), image.dimensions());
+        // the (flat) top of the ridge is white, darkened by the light coming in at 45°, the
+        // foot is green
+        let top = image.get_pixel(4, 4);
+        assert_eq!(&Rgb([200, 200, 200]), top);
+        let foot = image.get_pixel(0, 4);
+        assert!(foot[1] > foot[0] && foot[1] > foot[2], "{:?}", foot);
+
+        let thumbnail = hillshade.thumbnail(&ridge(), 9, 9, 4);
+        assert_eq!((4, 4), thumbnail.dimensions());
+        let texture = texture(&thumbnail);
+        assert_eq!(4 * 4 * 4, texture.data.len());
+    }
+
+    #[test]
+    fn test_voids_are_grey() {
+        // a deep void at the western foot of the ridge
+        let mut grid = ridge();
+        grid.set(0, 4, -100.0);
+        let mut mask = vec![true; 81];
+        mask[4 * 9] = false;
+        let holey = MaskedGrid {
+            grid,
+            mask: Some(mask),
+        };
+        let hillshade = Hillshade {
+            tint: true,
+            ..Default::default()
+        };
+
+        let image = hillshade.render(&holey, 9, 9);
+        assert_eq!(&Rgb([128, 128, 128]), image.get_pixel(0, 4));
+        // the tint still spans the valid heights only, and next to the void the slope is about
+        // the one of the ridge, not a cliff
+        assert_eq!(&Rgb([200, 200, 200]), image.get_pixel(4, 4));
+        let (expected, actual) = (
+            hillshade.render(&ridge(), 9, 9).get_pixel(1, 4).0,
+            image.get_pixel(1, 4).0,
+        );
+        for (expected, actual) in expected.iter().zip(actual.iter()) {
+            assert!(
+                (*expected as i32 - *actual as i32).abs() <= 2,
+                "{:?}",
+                actual
+            );
+        }
+
+        // the thumbnail keeps the void
+        let thumbnail = hillshade.thumbnail(&holey, 9, 9, 5);
+        assert_eq!(&Rgb([128, 128, 128]), thumbnail.get_pixel(0, 2));
+    }
+}
@@ -0,0 +1,606 @@
+//! where water goes on a height map, how much of it comes together where, and the rivers it
+//! forms.
+//!
+//! The usual way through is [`fill_depressions`] (or water gets stuck in every little pit),
+//! [`FlowDirections::d8`] or [`FlowDirections::d_infinity`] on the filled grid,
+//! [`FlowDirections::accumulation`] and finally [`FlowDirections::streams`].
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::f32::consts::{FRAC_PI_4, PI, SQRT_2};
+
+use bevy::math::Vec2;
+use image::GrayImage;
+
+use crate::height_map::grid::HeightGrid;
+use crate::height_map::HeightSource;
+
+/// the eight neighbours, clockwise from east (y grows southwards), with their D8 code
+const NEIGHBOURS: [(isize, isize, u8); 8] = [
+    (1, 0, 1),
+    (1, 1, 2),
+    (0, 1, 4),
+    (-1, 1, 8),
+    (-1, 0, 16),
+    (-1, -1, 32),
+    (0, -1, 64),
+    (1, -1, 128),
+];
+
+/// the triangular facets of D-infinity, as indices into `NEIGHBOURS`: a direct neighbour and
+/// the diagonal one next to it
+const FACETS: [(usize, usize); 8] = [
+    (0, 1),
+    (2, 1),
+    (2, 3),
+    (4, 3),
+    (4, 5),
+    (6, 5),
+    (6, 7),
+    (0, 7),
+];
+
+fn neighbour(
+    x: usize,
+    y: usize,
+    dx: isize,
+    dy: isize,
+    width: usize,
+    height: usize,
+) -> Option<(usize, usize)> {
+    let (nx, ny) = (x as isize + dx, y as isize + dy);
+    if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+        None
+    } else {
+        Some((nx as usize, ny as usize))
+    }
+}
+
+/// a cell waiting in the priority flood, lowest first
+#[derive(PartialEq)]
+struct Cell {
+    height: f32,
+    x: usize,
+    y: usize,
+}
+
+impl Eq for Cell {}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap pops the biggest, we want the lowest. NaN goes last instead of panicking.
+        match other.height.partial_cmp(&self.height) {
+            Some(ordering) => ordering,
+            None => other.height.is_nan().cmp(&self.height.is_nan()),
+        }
+    }
+}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// raises every pit to the level of its lowest spill point, so water can run off from
+/// everywhere (priority-flood, after [Barnes et al.][0]).
+///
+/// The flood starts at the border of the map and at the border of voids, where water can leave.
+/// NaN samples count as voids.
+/// With an `epsilon` of 0 filled pits end up perfectly flat, which has no flow direction. A tiny
+/// `epsilon` (small compared to the height differences, but not lost in the float precision of
+/// the heights) instead gives them a slight slope towards their outlet.
+///
+/// [0]: https://arxiv.org/abs/1511.04463
+pub fn fill_depressions<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    epsilon: f32,
+) -> HeightGrid {
+    let mut filled = HeightGrid::from_source(source, width, height);
+    let mut done = vec![false; width * height];
+    let mut queue = BinaryHeap::new();
+    let is_valid = |x, y| source.is_valid(x, y) && !filled.get(x, y).is_nan();
+
+    for y in 0..height {
+        for x in 0..width {
+            if !is_valid(x, y) {
+                done[y * width + x] = true;
+                continue;
+            }
+            let outlet = NEIGHBOURS.iter().any(|(dx, dy, _)| {
+                match neighbour(x, y, *dx, *dy, width, height) {
+                    Some((nx, ny)) => !is_valid(nx, ny),
+                    None => true,
+                }
+            });
+            if outlet {
+                done[y * width + x] = true;
+                queue.push(Cell {
+                    height: filled.get(x, y),
+                    x,
+                    y,
+                });
+            }
+        }
+    }
+
+    while let Some(cell) = queue.pop() {
+        for (dx, dy, _) in NEIGHBOURS {
+            let (nx, ny) = match neighbour(cell.x, cell.y, dx, dy, width, height) {
+                Some(position) if !done[position.1 * width + position.0] => position,
+                _ => continue,
+            };
+            done[ny * width + nx] = true;
+            if filled.get(nx, ny) <= cell.height {
+                filled.set(nx, ny, cell.height + epsilon);
+            }
+            queue.push(Cell {
+                height: filled.get(nx, ny),
+                x: nx,
+                y: ny,
+            });
+        }
+    }
+
+    filled
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowRouting {
+    /// all water goes to the steepest of the eight neighbours
+    D8,
+    /// water flows along the steepest slope in any direction and is split between the two
+    /// neighbours around it ([Tarboton 1997][0])
+    ///
+    /// [0]: https://doi.org/10.1029/96WR03137
+    DInfinity,
+}
+
+/// where the water of every cell goes. Water only ever flows strictly downhill, cells without a
+/// lower neighbour (pits, flats, voids) keep it.
+pub struct FlowDirections {
+    width: usize,
+    height: usize,
+    routing: FlowRouting,
+    valid: Vec<bool>,
+    /// up to two cells (offsets) the water goes to and their share of it. A share of 0 means
+    /// unused, D8 only ever uses the first one.
+    receivers: Vec<[(usize, f32); 2]>,
+    /// see [`FlowDirections::direction_grid`]
+    directions: Vec<f32>,
+}
+
+impl FlowDirections {
+    fn new<H: HeightSource>(
+        source: &H,
+        width: usize,
+        height: usize,
+        routing: FlowRouting,
+        no_flow: f32,
+    ) -> Self {
+        let valid = (0..width * height)
+            .map(|offset| source.is_valid(offset % width, offset / width))
+            .collect();
+        Self {
+            width,
+            height,
+            routing,
+            valid,
+            receivers: vec![[(0, 0.0); 2]; width * height],
+            directions: vec![no_flow; width * height],
+        }
+    }
+
+    pub fn d8<H: HeightSource>(source: &H, width: usize, height: usize) -> Self {
+        let mut flow = Self::new(source, width, height, FlowRouting::D8, 0.0);
+        for y in 0..height {
+            for x in 0..width {
+                if !flow.valid[y * width + x] {
+                    continue;
+                }
+                let center = source.sample_height(x, y);
+                let mut steepest = 0.0;
+                for (dx, dy, code) in NEIGHBOURS {
+                    let (nx, ny) = match neighbour(x, y, dx, dy, width, height) {
+                        Some((nx, ny)) if flow.valid[ny * width + nx] => (nx, ny),
+                        _ => continue,
+                    };
+                    let distance = if dx != 0 && dy != 0 { SQRT_2 } else { 1.0 };
+                    let slope = (center - source.sample_height(nx, ny)) / distance;
+                    if slope > steepest {
+                        steepest = slope;
+                        flow.receivers[y * width + x] = [(ny * width + nx, 1.0), (0, 0.0)];
+                        flow.directions[y * width + x] = code as f32;
+                    }
+                }
+            }
+        }
+
+        flow
+    }
+
+    pub fn d_infinity<H: HeightSource>(source: &H, width: usize, height: usize) -> Self {
+        let mut flow = Self::new(source, width, height, FlowRouting::DInfinity, -1.0);
+        for y in 0..height {
+            for x in 0..width {
+                if !flow.valid[y * width + x] {
+                    continue;
+                }
+                let center = source.sample_height(x, y);
+                // steepest slope, its angle inside the facet (0 is the direct neighbour, 45° the
+                // diagonal one) and the facet
+                let mut steepest: Option<(f32, f32, usize)> = None;
+                for (facet, (direct, diagonal)) in FACETS.iter().enumerate() {
+                    let corners =
+                        [NEIGHBOURS[*direct], NEIGHBOURS[*diagonal]].map(|(dx, dy, _)| {
+                            neighbour(x, y, dx, dy, width, height)
+                                .filter(|(nx, ny)| flow.valid[ny * width + nx])
+                        });
+                    let (e1, e2) = match corners {
+                        [Some((x1, y1)), Some((x2, y2))] => {
+                            (source.sample_height(x1, y1), source.sample_height(x2, y2))
+                        }
+                        _ => continue,
+                    };
+
+                    let (s1, s2) = (center - e1, e1 - e2);
+                    let (angle, slope) = match s2.atan2(s1) {
+                        angle if angle < 0.0 => (0.0, s1),
+                        angle if angle > FRAC_PI_4 => (FRAC_PI_4, (center - e2) / SQRT_2),
+                        angle => (angle, (s1 * s1 + s2 * s2).sqrt()),
+                    };
+                    if slope > steepest.map_or(0.0, |(steepest, ..)| steepest) {
+                        steepest = Some((slope, angle, facet));
+                    }
+                }
+
+                let (_, angle, facet) = match steepest {
+                    Some(steepest) => steepest,
+                    None => continue,
+                };
+                let (direct, diagonal) = FACETS[facet];
+                let to_offset = |index: usize| {
+                    let (dx, dy, _) = NEIGHBOURS[index];
+                    (y as isize + dy) as usize * width + (x as isize + dx) as usize
+                };
+                let diagonal_share = angle / FRAC_PI_4;
+                flow.receivers[y * width + x] = [
+                    (to_offset(direct), 1.0 - diagonal_share),
+                    (to_offset(diagonal), diagonal_share),
+                ];
+
+                // rotate from the direct neighbour towards the diagonal one, (diagonal - direct)
+                // is perpendicular to the direct one
+                let (dx1, dy1, _) = NEIGHBOURS[direct];
+                let (dx2, dy2, _) = NEIGHBOURS[diagonal];
+                let direct = Vec2::new(dx1 as f32, dy1 as f32);
+                let across = Vec2::new(dx2 as f32, dy2 as f32) - direct;
+                let direction = direct * angle.cos() + across * angle.sin();
+                // counter clockwise from east with north up, like everybody else does it
+                let mut angle = (-direction.y).atan2(direction.x);
+                if angle < 0.0 {
+                    angle += 2.0 * PI;
+                }
+                flow.directions[y * width + x] = angle;
+            }
+        }
+
+        flow
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn routing(&self) -> FlowRouting {
+        self.routing
+    }
+
+    /// the cells the water of a cell flows to, with their share of it
+    pub fn receivers(&self, x: usize, y: usize) -> impl Iterator<Item = ((usize, usize), f32)> {
+        let width = self.width;
+        self.receivers[y * width + x]
+            .into_iter()
+            .filter(|(_, share)| *share > 0.0)
+            .map(move |(offset, share)| ((offset % width, offset / width), share))
+    }
+
+    /// the flow direction of every cell. For D8 that is the usual code of the neighbour (1 for
+    /// east, 2 south east, ... 128 north east, 0 for no flow), for D-infinity the angle in
+    /// radians, counter clockwise from east with north (smaller y) up, or -1 for no flow.
+    pub fn direction_grid(&self) -> HeightGrid {
+        HeightGrid::new(self.width, self.height, self.directions.clone())
+    }
+
+    /// how many cells drain through every cell, counting itself. Voids get 0.
+    pub fn accumulation(&self) -> HeightGrid {
+        let mut accumulation: Vec<f32> = self
+            .valid
+            .iter()
+            .map(|valid| if *valid { 1.0 } else { 0.0 })
+            .collect();
+
+        // every cell passes its water on once all cells upstream of it are done
+        let mut upstream = vec![0usize; accumulation.len()];
+        for receivers in &self.receivers {
+            for (offset, share) in receivers {
+                if *share > 0.0 {
+                    upstream[*offset] += 1;
+                }
+            }
+        }
+        let mut ready: VecDeque<usize> = (0..upstream.len())
+            .filter(|offset| upstream[*offset] == 0)
+            .collect();
+        while let Some(offset) = ready.pop_front() {
+            for (receiver, share) in self.receivers[offset] {
+                if share > 0.0 {
+                    accumulation[receiver] += accumulation[offset] * share;
+                    upstream[receiver] -= 1;
+                    if upstream[receiver] == 0 {
+                        ready.push_back(receiver);
+                    }
+                }
+            }
+        }
+
+        HeightGrid::new(self.width, self.height, accumulation)
+    }
+
+    /// the stream network: all cells with at least `threshold` cells draining through them.
+    /// Every polyline (in grid coordinates) runs downstream from a source or a confluence to the
+    /// next confluence, or to where the stream ends. Split D-infinity flow follows the bigger
+    /// share.
+    pub fn streams(&self, accumulation: &HeightGrid, threshold: f32) -> Vec<Vec<Vec2>> {
+        let is_stream: Vec<bool> = accumulation
+            .data()
+            .iter()
+            .map(|cells| *cells >= threshold)
+            .collect();
+        let downstream = |offset: usize| {
+            let [first, second] = self.receivers[offset];
+            let (receiver, share) = if second.1 > first.1 { second } else { first };
+            if share > 0.0 && is_stream[receiver] {
+                Some(receiver)
+            } else {
+                None
+            }
+        };
+
+        let mut inflows = vec![0usize; is_stream.len()];
+        for offset in (0..is_stream.len()).filter(|offset| is_stream[*offset]) {
+            if let Some(receiver) = downstream(offset) {
+                inflows[receiver] += 1;
+            }
+        }
+
+        let position =
+            |offset: usize| Vec2::new((offset % self.width) as f32, (offset / self.width) as f32);
+        let mut streams = vec![];
+        // sources have no inflow, confluences more than one
+        for start in
+            (0..is_stream.len()).filter(|offset| is_stream[*offset] && inflows[*offset] != 1)
+        {
+            let mut line = vec![position(start)];
+            let mut current = start;
+            while let Some(next) = downstream(current) {
+                line.push(position(next));
+                if inflows[next] != 1 {
+                    break;
+                }
+                current = next;
+            }
+            if line.len() > 1 {
+                streams.push(line);
+            }
+        }
+
+        streams
+    }
+
+    /// every cell that drains into the pour point `x`, `y`, including itself. With D-infinity a
+    /// cell counts as soon as any share of its water gets there.
+    pub fn catchment(&self, x: usize, y: usize) -> Catchment {
+        // walk the flow backwards, from every cell to the ones draining into it
+        let mut donors = vec![vec![]; self.receivers.len()];
+        for (offset, receivers) in self.receivers.iter().enumerate() {
+            for (receiver, share) in receivers {
+                if *share > 0.0 {
+                    donors[*receiver].push(offset);
+                }
+            }
+        }
+
+        let mut mask = vec![0.0; self.receivers.len()];
+        let mut cells = 0;
+        let mut todo = vec![y * self.width + x];
+        mask[y * self.width + x] = 1.0;
+        while let Some(offset) = todo.pop() {
+            cells += 1;
+            for donor in &donors[offset] {
+                if mask[*donor] == 0.0 {
+                    mask[*donor] = 1.0;
+                    todo.push(*donor);
+                }
+            }
+        }
+
+        Catchment {
+            mask: HeightGrid::new(self.width, self.height, mask),
+            cells,
+        }
+    }
+}
+
+/// the area draining into a pour point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catchment {
+    /// 1 for cells inside, 0 outside
+    pub mask: HeightGrid,
+    pub cells: usize,
+}
+
+impl Catchment {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        self.mask.get(x, y) > 0.0
+    }
+
+    /// the area, with `cell_size` world units between neighbouring samples
+    pub fn area(&self, cell_size: f32) -> f32 {
+        self.cells as f32 * cell_size * cell_size
+    }
+}
+
+/// the cell with the highest accumulation within `radius` cells of `x`, `y`. A picked pour point
+/// is hardly ever exactly on the stream, and one cell off gives a tiny catchment.
+pub fn snap_pour_point(
+    accumulation: &HeightGrid,
+    x: usize,
+    y: usize,
+    radius: usize,
+) -> (usize, usize) {
+    let xs = x.saturating_sub(radius)..=(x + radius).min(accumulation.width() - 1);
+    let ys = y.saturating_sub(radius)..=(y + radius).min(accumulation.height() - 1);
+    let mut best = (x, y);
+    for sy in ys {
+        for sx in xs.clone() {
+            if accumulation.get(sx, sy) > accumulation.get(best.0, best.1) {
+                best = (sx, sy);
+            }
+        }
+    }
+    best
+}
+
+/// flow accumulation as an image. It spans several orders of magnitude, so it is log scaled.
+pub fn accumulation_image(accumulation: &HeightGrid) -> GrayImage {
+    HeightGrid::from_fn(accumulation.width(), accumulation.height(), |x, y| {
+        accumulation.get(x, y).max(1.0).ln()
+    })
+    .to_image()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// a valley running south, its floor sloping down towards y = 0 with a pit in the middle
+    fn valley() -> HeightGrid {
+        let mut grid = HeightGrid::from_fn(9, 9, |x, y| {
+            (x as f32 - 4.0).abs() * 2.0 + y as f32 * 0.5 + 1.0
+        });
+        grid.set(4, 4, 0.0);
+        grid
+    }
+
+    #[test]
+    fn test_fill_depressions() {
+        let grid = valley();
+        let filled = fill_depressions(&grid, 9, 9, 0.0);
+        // the pit fills up to its spill point, the sample below it
+        assert_eq!(grid.get(4, 3), filled.get(4, 4));
+        for (x, y) in [(0, 0), (4, 0), (3, 6), (8, 8)] {
+            assert_eq!(grid.get(x, y), filled.get(x, y));
+        }
+
+        // an epsilon lets the filled pit drain towards the outlet
+        let filled = fill_depressions(&grid, 9, 9, 1e-3);
+        assert!(filled.get(4, 4) > filled.get(4, 3));
+        assert_eq!(grid.get(4, 3), filled.get(4, 3));
+    }
+
+    #[test]
+    fn test_nan_samples_are_outlets() {
+        let mut grid = valley();
+        grid.set(5, 4, f32::NAN);
+        let filled = fill_depressions(&grid, 9, 9, 0.0);
+        assert!(filled.get(5, 4).is_nan());
+        // the pit is no pit any more, it drains into the hole next to it
+        assert_eq!(grid.get(4, 4), filled.get(4, 4));
+        assert!(filled
+            .data()
+            .iter()
+            .enumerate()
+            .all(|(offset, height)| offset == 4 * 9 + 5 || height.is_finite()));
+    }
+
+    #[test]
+    fn test_d8_accumulates_along_the_valley() {
+        let filled = fill_depressions(&valley(), 9, 9, 1e-3);
+        let flow = FlowDirections::d8(&filled, 9, 9);
+        // north, down the valley
+        assert_eq!(64.0, flow.direction_grid().get(4, 6));
+        assert_eq!(
+            vec![((4, 5), 1.0)],
+            flow.receivers(4, 6).collect::<Vec<_>>()
+        );
+
+        let accumulation = flow.accumulation();
+        // everything ends up at the mouth of the valley
+        assert_eq!(81.0, accumulation.get(4, 0));
+        assert!(accumulation.get(4, 3) > accumulation.get(4, 6));
+
+        let streams = flow.streams(&accumulation, 10.0);
+        assert_eq!(1, streams.len());
+        let stream = &streams[0];
+        assert_eq!(Vec2::new(4.0, 0.0), *stream.last().unwrap());
+        assert!(stream.iter().all(|point| point.x == 4.0));
+    }
+
+    #[test]
+    fn test_catchment_of_two_valleys() {
+        // valleys along x = 2 and x = 6, both running north, with a ridge at x = 4
+        let grid = HeightGrid::from_fn(9, 9, |x, y| {
+            let to_valley = (x as f32 - 2.0).abs().min((x as f32 - 6.0).abs());
+            to_valley * 2.0 + y as f32 * 0.5
+        });
+        let flow = FlowDirections::d8(&grid, 9, 9);
+
+        let west = flow.catchment(2, 0);
+        assert_eq!(36, west.cells);
+        assert_eq!(3600.0, west.area(10.0));
+        assert!(west.contains(0, 8) && west.contains(3, 4));
+        // the ridge drains east, as that is the first of two equally steep neighbours
+        assert!(!west.contains(4, 4) && !west.contains(6, 0));
+        assert_eq!(36.0, west.mask.data().iter().sum::<f32>());
+
+        // half way up the valley only gets what comes from further south
+        let upper = flow.catchment(2, 4);
+        assert_eq!(20, upper.cells);
+        assert!(upper.contains(0, 8) && !upper.contains(2, 3));
+
+        assert_eq!((2, 0), snap_pour_point(&flow.accumulation(), 1, 0, 1));
+    }
+
+    #[test]
+    fn test_d_infinity_splits_flow() {
+        // a plane dipping towards 30° south of east: the flow goes between east and south east
+        let angle = 30f32.to_radians();
+        let plane = HeightGrid::from_fn(5, 5, |x, y| {
+            -(x as f32 * angle.cos() + y as f32 * angle.sin())
+        });
+        let flow = FlowDirections::d_infinity(&plane, 5, 5);
+        // south east is -30° with north up
+        let direction = flow.direction_grid().get(2, 2);
+        assert!(
+            (direction - (2.0 * PI - angle)).abs() < 1e-4,
+            "{}",
+            direction
+        );
+
+        let receivers: Vec<_> = flow.receivers(2, 2).collect();
+        assert_eq!(2, receivers.len());
+        assert_eq!((3, 2), receivers[0].0);
+        assert_eq!((3, 3), receivers[1].0);
+        assert!((receivers[1].1 - angle / FRAC_PI_4).abs() < 1e-4);
+
+        // the lowest corner is the only one without receivers, all water ends up there
+        assert_eq!(0, flow.receivers(4, 4).count());
+        assert!((flow.accumulation().get(4, 4) - 25.0).abs() < 1e-4);
+    }
+}
@@ -1,15 +1,119 @@
 use crate::height_map;
+use crate::height_map::encoding::RgbEncoding;
+use crate::height_map::geotiff;
+use crate::height_map::raster::Raster;
+use crate::height_map::raw::{BitDepth, ByteOrder, RawHeightSource, RawLayout};
+use crate::height_map::terrain::Terrain;
+use crate::height_map::{Triangulation, VoidOptions};
+use anyhow::Context;
 use bevy::{
     asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
     prelude::{FromWorld, Image, World},
     render::renderer::RenderDevice,
     render::texture::{CompressedImageFormats, ImageType},
 };
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// metres to mesh units for height maps that decode to real world heights, when nothing else is
+/// configured. A kilometre per unit looks about right for a typical zoom 12 tile.
+const DEFAULT_METRIC_HEIGHT_SCALE: f32 = 0.001;
 
 pub struct HeightmapMeshLoader {
     supported_compressed_formats: CompressedImageFormats,
 }
 
+/// optional settings for a height map, read from a `<file name>.settings` file next to it.
+///
+/// The file is made of `key = value` lines, `#` starts a comment:
+///
+/// ```text
+/// # this is a terrarium tile, even though it is not named like one
+/// encoding = terrarium
+/// height_scale = 0.002
+/// # samples with this (decoded) value become holes, unless fill_voids is set to linear (or
+/// # true), idw, harmonic or pyramid
+/// nodata = -32768
+/// fill_voids = harmonic
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HeightmapSettings {
+    values: HashMap<String, String>,
+}
+
+impl HeightmapSettings {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut values = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("line {}: expected `key = value`", number + 1))?;
+            values.insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+
+        Ok(Self { values })
+    }
+
+    /// settings for the asset that is currently loading, or the defaults if there are none
+    async fn load(load_context: &LoadContext<'_>) -> anyhow::Result<Self> {
+        let mut path = PathBuf::from(load_context.path());
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".settings");
+        path.set_file_name(file_name);
+
+        match load_context.read_asset_bytes(&path).await {
+            Ok(bytes) => Self::parse(&String::from_utf8(bytes)?)
+                .with_context(|| format!("invalid settings in {:?}", path)),
+            // most height maps do not need any settings
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn get<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.values
+            .get(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|err| anyhow::anyhow!("invalid value for {}: {}", key, err))
+            })
+            .transpose()
+    }
+
+    fn void_options(&self) -> anyhow::Result<VoidOptions> {
+        Ok(VoidOptions {
+            nodata: self.get("nodata")?,
+            fill: match self.values.get("fill_voids").map(String::as_str) {
+                None | Some("false") => None,
+                Some(_) => self.get("fill_voids")?,
+            },
+        })
+    }
+}
+
+/// the mesh is the default asset of every height map, the samples it is made of come along as
+/// the `terrain` labelled asset
+fn set_terrain(
+    load_context: &mut LoadContext,
+    settings: &HeightmapSettings,
+    terrain: Terrain,
+) -> anyhow::Result<()> {
+    let triangulation: Option<Triangulation> = settings.get("triangulation")?;
+    let terrain = terrain.with_triangulation(triangulation.unwrap_or(Triangulation::Rtin));
+    load_context.set_default_asset(LoadedAsset::new(terrain.mesh()));
+    load_context.set_labeled_asset("terrain", LoadedAsset::new(terrain));
+    Ok(())
+}
+
 impl AssetLoader for HeightmapMeshLoader {
     fn load<'a>(
         &'a self,
@@ -18,6 +122,13 @@ impl AssetLoader for HeightmapMeshLoader {
     ) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
         println!("loading height map");
         Box::pin(async move {
+            let settings = HeightmapSettings::load(load_context).await?;
+            // an encoding in the file name wins over the one from the settings
+            let encoding = match RgbEncoding::from_path(load_context.path()) {
+                Some(encoding) => Some(encoding),
+                None => settings.get("encoding")?,
+            };
+
             // use the file extension for the image type
             let ext = load_context.path().extension().unwrap().to_str().unwrap();
 
@@ -31,14 +142,117 @@ impl AssetLoader for HeightmapMeshLoader {
                 true,
             )
             .unwrap();
-            let mesh = height_map::mesh_from_image(dyn_img);
-            load_context.set_default_asset(LoadedAsset::new(mesh));
+            let voids = settings.void_options()?;
+            let terrain = match encoding {
+                Some(encoding) => {
+                    let height_scale = settings
+                        .get("height_scale")?
+                        .unwrap_or(DEFAULT_METRIC_HEIGHT_SCALE);
+                    height_map::terrain_from_encoded_image(dyn_img, encoding, height_scale, voids)?
+                }
+                None => height_map::terrain_from_image(dyn_img, voids),
+            };
+            set_terrain(load_context, &settings, terrain)?;
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["hm.png", "hm.jpg", "terrarium.png", "terrainrgb.png"]
+    }
+}
+
+/// loads elevation rasters (ESRI ASCII grids, SRTM tiles and GeoTIFFs) straight into a mesh
+#[derive(Default)]
+pub struct RasterMeshLoader;
+
+impl AssetLoader for RasterMeshLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        println!("loading raster");
+        Box::pin(async move {
+            let settings = HeightmapSettings::load(load_context).await?;
+            let ext = load_context.path().extension().unwrap().to_str().unwrap();
+            let (raster, georeference) = match ext.to_lowercase().as_str() {
+                "asc" => (Raster::parse_ascii_grid(std::str::from_utf8(bytes)?)?, None),
+                "hgt" => (Raster::parse_hgt(bytes)?, None),
+                "tif" | "tiff" => {
+                    let (raster, georeference) = geotiff::parse_geotiff(bytes)?;
+                    (raster, Some(georeference))
+                }
+                _ => anyhow::bail!("unsupported raster extension {}", ext),
+            };
+
+            let terrain = height_map::terrain_from_raster(raster, settings.void_options()?);
+            set_terrain(load_context, &settings, terrain)?;
+            if let Some(georeference) = georeference {
+                load_context.set_labeled_asset("georeference", LoadedAsset::new(georeference));
+            }
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["asc", "hgt", "tif", "tiff"]
+    }
+}
+
+/// loads headerless `.r16`, `.r32` and `.raw` height maps. `.raw` files are expected to be 16 bit.
+///
+/// Square maps with little endian samples work without any configuration, everything else needs
+/// `width`, `height`, `bit_depth` (16 or 32) and `byte_order` (little or big) in the settings.
+#[derive(Default)]
+pub struct RawHeightmapMeshLoader;
+
+impl AssetLoader for RawHeightmapMeshLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, anyhow::Result<(), anyhow::Error>> {
+        println!("loading raw height map");
+        Box::pin(async move {
+            let settings = HeightmapSettings::load(load_context).await?;
+            let ext = load_context.path().extension().unwrap().to_str().unwrap();
+
+            let bit_depth = match settings.get("bit_depth")? {
+                Some(bit_depth) => bit_depth,
+                None if ext.eq_ignore_ascii_case("r32") => BitDepth::ThirtyTwo,
+                None => BitDepth::Sixteen,
+            };
+            let byte_order = settings.get("byte_order")?.unwrap_or(ByteOrder::Little);
+            let layout = match (settings.get("width")?, settings.get("height")?) {
+                (Some(width), Some(height)) => RawLayout {
+                    width,
+                    height,
+                    bit_depth,
+                    byte_order,
+                },
+                (None, None) => RawLayout::infer_square(bytes.len(), bit_depth, byte_order)?,
+                _ => anyhow::bail!("raw height maps need both width and height, or neither"),
+            };
+            if layout.width < 2 || layout.height < 2 {
+                anyhow::bail!(
+                    "raw height map of {} x {} samples is too small",
+                    layout.width,
+                    layout.height
+                );
+            }
+
+            let height_source = RawHeightSource::new(bytes.to_vec(), layout)?;
+            let height_scale = settings.get("height_scale")?.unwrap_or(1.0);
+            let voids = settings.void_options()?;
+            let terrain = height_map::terrain_from_raw(height_source, height_scale, voids);
+            set_terrain(load_context, &settings, terrain)?;
             Ok(())
         })
     }
 
     fn extensions(&self) -> &[&str] {
-        &["hm.png", "hm.jpg"]
+        &["r16", "r32", "raw"]
     }
 }
 
@@ -0,0 +1,302 @@
+//! lowest and highest elevation of any rectangle of a height map, without scanning all of it.
+//!
+//! Every level of the pyramid halves the resolution of the one below and keeps the minimum and
+//! maximum of the (up to) four cells it covers. A query only has to visit the cells along the
+//! border of the rectangle, everything inside is answered by a few big cells.
+use std::ops::RangeInclusive;
+
+use crate::height_map::grid::{GridRect, HeightGrid};
+use crate::height_map::{HeightMap, HeightSource};
+
+struct Level {
+    width: usize,
+    height: usize,
+    min: Vec<f32>,
+    max: Vec<f32>,
+}
+
+pub struct MinMaxPyramid {
+    /// from full resolution down to a single cell
+    levels: Vec<Level>,
+}
+
+/// samples covered by a chunk and their elevation range
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkBounds {
+    pub x: RangeInclusive<usize>,
+    pub y: RangeInclusive<usize>,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl MinMaxPyramid {
+    pub fn from_source<H: HeightSource>(source: &H, width: usize, height: usize) -> Self {
+        assert!(width > 0 && height > 0);
+        let samples: Vec<f32> = (0..width * height)
+            .map(|offset| source.sample_height(offset % width, offset / width))
+            .collect();
+        let mut levels = vec![Level {
+            width,
+            height,
+            min: samples.clone(),
+            max: samples,
+        }];
+
+        while levels.last().unwrap().width > 1 || levels.last().unwrap().height > 1 {
+            let fine = levels.last().unwrap();
+            let width = fine.width / 2 + fine.width % 2;
+            let height = fine.height / 2 + fine.height % 2;
+            let mut min = vec![f32::INFINITY; width * height];
+            let mut max = vec![f32::NEG_INFINITY; width * height];
+            for y in 0..fine.height {
+                for x in 0..fine.width {
+                    let (fine_offset, offset) = (y * fine.width + x, (y / 2) * width + x / 2);
+                    min[offset] = min[offset].min(fine.min[fine_offset]);
+                    max[offset] = max[offset].max(fine.max[fine_offset]);
+                }
+            }
+            levels.push(Level {
+                width,
+                height,
+                min,
+                max,
+            });
+        }
+
+        Self { levels }
+    }
+
+    /// reads the samples in `region` from `source` again, after they were changed. Only the cells
+    /// above them are updated.
+    pub fn update_region<H: HeightSource>(&mut self, source: &H, region: &GridRect) {
+        let (mut x, mut y) = (region.x.clone(), region.y.clone());
+        let finest = &mut self.levels[0];
+        for sy in y.clone() {
+            for sx in x.clone() {
+                let offset = sy * finest.width + sx;
+                let height = source.sample_height(sx, sy);
+                finest.min[offset] = height;
+                finest.max[offset] = height;
+            }
+        }
+
+        for level in 1..self.levels.len() {
+            x = x.start() / 2..=x.end() / 2;
+            y = y.start() / 2..=y.end() / 2;
+            let (finer, coarser) = self.levels.split_at_mut(level);
+            let (fine, cells) = (&finer[level - 1], &mut coarser[0]);
+            for cell_y in y.clone() {
+                for cell_x in x.clone() {
+                    let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+                    for fine_y in cell_y * 2..(cell_y * 2 + 2).min(fine.height) {
+                        for fine_x in cell_x * 2..(cell_x * 2 + 2).min(fine.width) {
+                            let fine_offset = fine_y * fine.width + fine_x;
+                            min = min.min(fine.min[fine_offset]);
+                            max = max.max(fine.max[fine_offset]);
+                        }
+                    }
+                    let offset = cell_y * cells.width + cell_x;
+                    cells.min[offset] = min;
+                    cells.max[offset] = max;
+                }
+            }
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    /// how many times the resolution was halved, plus one for the full resolution
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// lowest and highest sample of the whole map
+    pub fn min_max(&self) -> (f32, f32) {
+        let top = self.levels.last().unwrap();
+        (top.min[0], top.max[0])
+    }
+
+    /// lowest and highest sample in a rectangle, both ranges are clamped to the map
+    pub fn region_min_max(&self, x: RangeInclusive<usize>, y: RangeInclusive<usize>) -> (f32, f32) {
+        let x = *x.start()..=(*x.end()).min(self.width() - 1);
+        let y = *y.start()..=(*y.end()).min(self.height() - 1);
+        let mut result = (f32::INFINITY, f32::NEG_INFINITY);
+        if !x.is_empty() && !y.is_empty() {
+            self.query(self.levels.len() - 1, 0, 0, &x, &y, &mut result);
+        }
+        result
+    }
+
+    fn query(
+        &self,
+        level: usize,
+        cell_x: usize,
+        cell_y: usize,
+        x: &RangeInclusive<usize>,
+        y: &RangeInclusive<usize>,
+        result: &mut (f32, f32),
+    ) {
+        let size = 1 << level;
+        let (x0, y0) = (cell_x * size, cell_y * size);
+        let x1 = (x0 + size).min(self.width()) - 1;
+        let y1 = (y0 + size).min(self.height()) - 1;
+        if x1 < *x.start() || x0 > *x.end() || y1 < *y.start() || y0 > *y.end() {
+            return;
+        }
+
+        let cells = &self.levels[level];
+        if x.contains(&x0) && x.contains(&x1) && y.contains(&y0) && y.contains(&y1) {
+            let offset = cell_y * cells.width + cell_x;
+            result.0 = result.0.min(cells.min[offset]);
+            result.1 = result.1.max(cells.max[offset]);
+            return;
+        }
+
+        // only partly covered, level 0 never gets here as its cells are single samples
+        let children = &self.levels[level - 1];
+        for child_y in cell_y * 2..(cell_y * 2 + 2).min(children.height) {
+            for child_x in cell_x * 2..(cell_x * 2 + 2).min(children.width) {
+                self.query(level - 1, child_x, child_y, x, y, result);
+            }
+        }
+    }
+
+    /// the bounds of every chunk of `chunk_size` x `chunk_size` cells, row by row. Neighbouring
+    /// chunks share their border samples, like meshes built from them would.
+    pub fn chunk_bounds(&self, chunk_size: usize) -> Vec<ChunkBounds> {
+        assert!(chunk_size > 0);
+        let cells_x = self.width().saturating_sub(1).max(1);
+        let cells_y = self.height().saturating_sub(1).max(1);
+
+        let mut chunks = vec![];
+        for chunk_y in (0..cells_y).step_by(chunk_size) {
+            for chunk_x in (0..cells_x).step_by(chunk_size) {
+                let x = chunk_x..=(chunk_x + chunk_size).min(self.width() - 1);
+                let y = chunk_y..=(chunk_y + chunk_size).min(self.height() - 1);
+                let (min, max) = self.region_min_max(x.clone(), y.clone());
+                chunks.push(ChunkBounds { x, y, min, max });
+            }
+        }
+
+        chunks
+    }
+}
+
+impl<H: HeightSource> HeightMap<H> {
+    /// the pyramid of the (scaled) samples, e.g. for [`HeightMap::raycast_with_bounds`]
+    pub fn min_max_pyramid(&self) -> MinMaxPyramid {
+        let size = self.source_size;
+        let samples = HeightGrid::from_fn(size, size, |x, y| self.sample(x, y));
+        MinMaxPyramid::from_source(&samples, size, size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn brute_force(
+        grid: &HeightGrid,
+        x: RangeInclusive<usize>,
+        y: RangeInclusive<usize>,
+    ) -> (f32, f32) {
+        let mut result = (f32::INFINITY, f32::NEG_INFINITY);
+        for sy in y {
+            for sx in x.clone() {
+                result.0 = result.0.min(grid.get(sx, sy));
+                result.1 = result.1.max(grid.get(sx, sy));
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_regions_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(38);
+        // odd sizes, so the last cells of a level are only partly used
+        let samples = (0..37 * 23).map(|_| rng.gen_range(-100.0..100.0)).collect();
+        let grid = HeightGrid::new(37, 23, samples);
+        let pyramid = MinMaxPyramid::from_source(&grid, 37, 23);
+        assert_eq!(grid.min_max(), pyramid.min_max());
+
+        let mut rng = StdRng::seed_from_u64(83);
+        for _ in 0..500 {
+            let (x0, y0) = (rng.gen_range(0..37), rng.gen_range(0..23));
+            let (x1, y1) = (rng.gen_range(x0..37), rng.gen_range(y0..23));
+            assert_eq!(
+                brute_force(&grid, x0..=x1, y0..=y1),
+                pyramid.region_min_max(x0..=x1, y0..=y1),
+                "{}..={}, {}..={}",
+                x0,
+                x1,
+                y0,
+                y1
+            );
+        }
+    }
+
+    #[test]
+    fn test_updated_regions_match_a_new_pyramid() {
+        let mut rng = StdRng::seed_from_u64(49);
+        let samples = (0..37 * 23).map(|_| rng.gen_range(-100.0..100.0)).collect();
+        let mut grid = HeightGrid::new(37, 23, samples);
+        let mut pyramid = MinMaxPyramid::from_source(&grid, 37, 23);
+
+        for _ in 0..100 {
+            let (x0, y0) = (rng.gen_range(0..37), rng.gen_range(0..23));
+            let (x1, y1) = (rng.gen_range(x0..(x0 + 6).min(37)), rng.gen_range(y0..23));
+            // lowering as well, so a cell can lose its old minimum or maximum
+            let change = rng.gen_range(-50.0..50.0);
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    grid.set(x, y, grid.get(x, y) + change);
+                }
+            }
+            pyramid.update_region(
+                &grid,
+                &GridRect {
+                    x: x0..=x1,
+                    y: y0..=y1,
+                },
+            );
+
+            let expected = MinMaxPyramid::from_source(&grid, 37, 23);
+            for (level, expected) in pyramid.levels.iter().zip(&expected.levels) {
+                assert_eq!(expected.min, level.min);
+                assert_eq!(expected.max, level.max);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunk_bounds() {
+        let grid = HeightGrid::from_fn(9, 9, |x, y| (x * 10 + y) as f32);
+        let pyramid = MinMaxPyramid::from_source(&grid, 9, 9);
+        let chunks = pyramid.chunk_bounds(4);
+
+        assert_eq!(4, chunks.len());
+        assert_eq!(
+            ChunkBounds {
+                x: 4..=8,
+                y: 0..=4,
+                min: 40.0,
+                max: 84.0
+            },
+            chunks[1]
+        );
+        for chunk in chunks {
+            assert_eq!(
+                brute_force(&grid, chunk.x.clone(), chunk.y.clone()),
+                (chunk.min, chunk.max)
+            );
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! finding the point of the terrain a ray hits, mostly to know what is under the mouse cursor.
+//!
+//! There are two ways in: [`intersect_triangles`] works on the actual (simplified) mesh, which is
+//! what the user sees, [`HeightMap::raycast`] marches over the full resolution height map and
+//! does not need a mesh at all.
+use bevy::math::{Mat4, Vec3};
+
+use crate::height_map::grid::Resampling;
+use crate::height_map::min_max::MinMaxPyramid;
+use crate::height_map::{HeightMap, HeightSource};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// does not need to be normalized, hit distances are in multiples of it
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn at(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    /// the ray through a point of the screen, in normalized device coordinates (-1..1, y up).
+    /// `camera_to_world` is the camera transform, `projection` its projection matrix.
+    pub fn from_ndc(ndc_x: f32, ndc_y: f32, camera_to_world: Mat4, projection: Mat4) -> Self {
+        let ndc_to_world = camera_to_world * projection.inverse();
+        // bevy uses reversed z, 1 is the near plane. Far away points are less precise, so we
+        // take a second one not too far behind it.
+        let near = ndc_to_world.project_point3(Vec3::new(ndc_x, ndc_y, 1.0));
+        let further = ndc_to_world.project_point3(Vec3::new(ndc_x, ndc_y, 0.5));
+        Self {
+            origin: near,
+            direction: (further - near).normalize(),
+        }
+    }
+}
+
+/// the point of the terrain under the mouse cursor, if there is one
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TerrainPick(pub Option<RayHit>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    /// along the ray, in multiples of its direction
+    pub distance: f32,
+    pub position: Vec3,
+    pub normal: Vec3,
+}
+
+impl RayHit {
+    /// angle between the surface and the horizontal plane, in degrees
+    pub fn slope_degrees(&self) -> f32 {
+        self.normal.angle_between(Vec3::Y).to_degrees()
+    }
+}
+
+/// the closest hit of a ray with a triangle list. Only front faces (counter clockwise, like the
+/// terrain uses them) are hit.
+pub fn intersect_triangles(ray: &Ray, positions: &[[f32; 3]], indices: &[u32]) -> Option<RayHit> {
+    indices
+        .chunks_exact(3)
+        .filter_map(|triangle| {
+            let [a, b, c] = [triangle[0], triangle[1], triangle[2]]
+                .map(|index| Vec3::from(positions[index as usize]));
+            intersect_triangle(ray, a, b, c)
+        })
+        .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap())
+}
+
+/// Möller–Trumbore
+fn intersect_triangle(ray: &Ray, a: Vec3, b: Vec3, c: Vec3) -> Option<RayHit> {
+    let edge_ab = b - a;
+    let edge_ac = c - a;
+    let p = ray.direction.cross(edge_ac);
+    let determinant = edge_ab.dot(p);
+    // parallel to the triangle, or hitting its back
+    if determinant < f32::EPSILON {
+        return None;
+    }
+
+    let inverse = 1.0 / determinant;
+    let to_origin = ray.origin - a;
+    let u = to_origin.dot(p) * inverse;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = to_origin.cross(edge_ab);
+    let v = ray.direction.dot(q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let distance = edge_ac.dot(q) * inverse;
+    if distance < 0.0 {
+        return None;
+    }
+
+    Some(RayHit {
+        distance,
+        position: ray.at(distance),
+        normal: edge_ab.cross(edge_ac).normalize(),
+    })
+}
+
+impl<H: HeightSource> HeightMap<H> {
+    /// the first point where a ray (in world space) goes below the interpolated surface.
+    ///
+    /// Builds the [`MinMaxPyramid`] of the whole map first, keep one around and use
+    /// [`HeightMap::raycast_with_bounds`] to cast more than a single ray.
+    pub fn raycast(&self, ray: &Ray, resampling: Resampling) -> Option<RayHit> {
+        self.raycast_with_bounds(ray, &self.min_max_pyramid(), resampling)
+    }
+
+    /// like [`HeightMap::raycast`], with the pyramid of this map's (scaled) samples.
+    ///
+    /// We clip the ray to the box around the terrain and walk along it in steps of half a
+    /// sample, refining the first step that ends up below the ground with a few bisections.
+    /// Peaks thinner than half a sample can be missed, which is fine for the mouse cursor.
+    /// Wherever the ray stays above the highest point of a block of the pyramid, it skips the
+    /// whole block instead.
+    pub fn raycast_with_bounds(
+        &self,
+        ray: &Ray,
+        bounds: &MinMaxPyramid,
+        resampling: Resampling,
+    ) -> Option<RayHit> {
+        let (min_height, max_height) = bounds.min_max();
+        let overshoot = (max_height - min_height) * resampling.overshoot();
+        let (min_height, max_height) = (min_height - overshoot, max_height + overshoot);
+        let (min_x, min_z) = self.grid_to_world(0.0, 0.0);
+        let last = (self.source_size - 1) as f32;
+        let (max_x, max_z) = self.grid_to_world(last, last);
+        let (enter, exit) = clip_to_box(
+            ray,
+            Vec3::new(min_x, min_height, min_z),
+            Vec3::new(max_x, max_height, max_z),
+        )?;
+
+        let height_above = |distance: f32| {
+            let point = ray.at(distance);
+            let (x, y) = self.world_to_grid(point.x, point.z);
+            point.y - self.sample_interpolated(x, y, resampling)
+        };
+
+        let sample_distance = self.target_size / self.source_size as f32;
+        let step = sample_distance / 2.0 / ray.direction.length();
+        let mut previous = enter;
+        if height_above(previous) <= 0.0 {
+            // starts below the surface (or right on it)
+            return self.hit_at(ray, previous, resampling);
+        }
+        while previous < exit {
+            if let Some(skip_to) = self.skip_block(ray, previous, exit, step, bounds, resampling) {
+                previous = skip_to;
+                continue;
+            }
+            let next = (previous + step).min(exit);
+            if height_above(next) <= 0.0 {
+                let (mut above, mut below) = (previous, next);
+                for _ in 0..16 {
+                    let middle = (above + below) / 2.0;
+                    if height_above(middle) > 0.0 {
+                        above = middle;
+                    } else {
+                        below = middle;
+                    }
+                }
+                return self.hit_at(ray, below, resampling);
+            }
+            previous = next;
+        }
+
+        None
+    }
+
+    /// where the ray leaves the biggest block around it that it passes above, if there is one.
+    /// Blocks are the cells of the pyramid levels, `2^level` samples wide. The kernel reaches
+    /// into the samples around a position, so only positions far enough from the border of a
+    /// block are sure to stay below its highest sample.
+    fn skip_block(
+        &self,
+        ray: &Ray,
+        distance: f32,
+        exit: f32,
+        step: f32,
+        bounds: &MinMaxPyramid,
+        resampling: Resampling,
+    ) -> Option<f32> {
+        let position = ray.at(distance);
+        let (x, y) = self.world_to_grid(position.x, position.z);
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+        let reach = resampling.radius() as usize - 1;
+
+        for level in (1..bounds.levels()).rev() {
+            let size = 1 << level;
+            if size <= 2 * reach + 1 {
+                break;
+            }
+            let (block_x, block_y) = (x as usize / size * size, y as usize / size * size);
+            let (start_x, start_y) = ((block_x + reach) as f32, (block_y + reach) as f32);
+            let (end_x, end_y) = (
+                (block_x + size - 1 - reach) as f32,
+                (block_y + size - 1 - reach) as f32,
+            );
+            if x < start_x || x > end_x || y < start_y || y > end_y {
+                continue;
+            }
+            let (min, max) =
+                bounds.region_min_max(block_x..=block_x + size - 1, block_y..=block_y + size - 1);
+            let ceiling = max + (max - min) * resampling.overshoot();
+
+            let (min_x, min_z) = self.grid_to_world(start_x, start_y);
+            let (max_x, max_z) = self.grid_to_world(end_x, end_y);
+            let inside = |origin: f32, direction: f32, min: f32, max: f32| {
+                if direction > 0.0 {
+                    (max - origin) / direction
+                } else if direction < 0.0 {
+                    (min - origin) / direction
+                } else {
+                    f32::INFINITY
+                }
+            };
+            let inside_x = inside(position.x, ray.direction.x, min_x, max_x);
+            let inside_z = inside(position.z, ray.direction.z, min_z, max_z);
+            let leave = (distance + inside_x.min(inside_z)).min(exit);
+            // the ray is a straight line, so it is lowest at one of the ends. Not worth it for
+            // less than a step, which also keeps us from getting stuck at the edge of a block.
+            if leave > distance + step && position.y.min(ray.at(leave).y) > ceiling {
+                return Some(leave);
+            }
+        }
+
+        None
+    }
+
+    fn hit_at(&self, ray: &Ray, distance: f32, resampling: Resampling) -> Option<RayHit> {
+        let position = ray.at(distance);
+        let sample = self.sample_world(position.x, position.z, resampling)?;
+        Some(RayHit {
+            distance,
+            position: Vec3::new(position.x, sample.height, position.z),
+            normal: sample.normal,
+        })
+    }
+}
+
+/// distances at which the ray enters and leaves the box from `min` to `max`, if it does
+fn clip_to_box(ray: &Ray, min: Vec3, max: Vec3) -> Option<(f32, f32)> {
+    let (mut enter, mut exit) = (0.0f32, f32::INFINITY);
+    for axis in 0..3 {
+        let (origin, direction) = (ray.origin[axis], ray.direction[axis]);
+        if direction.abs() < f32::EPSILON {
+            if origin < min[axis] || origin > max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t0 = (min[axis] - origin) / direction;
+        let t1 = (max[axis] - origin) / direction;
+        enter = enter.max(t0.min(t1));
+        exit = exit.min(t0.max(t1));
+    }
+
+    if enter <= exit {
+        Some((enter, exit))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::grid::HeightGrid;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn hill() -> HeightMap<HeightGrid> {
+        let grid = HeightGrid::from_fn(17, 17, |x, y| {
+            let (dx, dy) = (x as f32 - 8.0, y as f32 - 8.0);
+            (2.0 - (dx * dx + dy * dy).sqrt() / 4.0).max(0.0)
+        });
+        HeightMap::create(grid, 17, 17.0)
+    }
+
+    #[test]
+    fn test_ray_hits_triangle_front_only() {
+        // the first cell of a 2x2 grid, counter clockwise seen from above
+        let positions = [[0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 0.0, 0.0]];
+        let down = Ray {
+            origin: Vec3::new(0.25, 2.0, 0.25),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+        };
+        let hit = intersect_triangles(&down, &positions, &[0, 1, 2]).unwrap();
+        assert_eq!(2.0, hit.distance);
+        assert_eq!(Vec3::new(0.25, 0.0, 0.25), hit.position);
+        assert_eq!(Vec3::Y, hit.normal);
+        assert_eq!(0.0, hit.slope_degrees());
+
+        let up = Ray {
+            origin: Vec3::new(0.25, -2.0, 0.25),
+            direction: Vec3::Y,
+        };
+        assert_eq!(None, intersect_triangles(&up, &positions, &[0, 1, 2]));
+        let beside = Ray {
+            origin: Vec3::new(0.75, 2.0, 0.75),
+            ..down
+        };
+        assert_eq!(None, intersect_triangles(&beside, &positions, &[0, 1, 2]));
+    }
+
+    #[test]
+    fn test_raycast_height_map() {
+        let hm = hill();
+        // straight down onto the top of the hill, which is at world -0.5, -0.5
+        let ray = Ray {
+            origin: Vec3::new(-0.5, 10.0, -0.5),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+        };
+        let hit = hm.raycast(&ray, Resampling::Bilinear).unwrap();
+        assert!((hit.position.y - 2.0).abs() < 1e-3, "{:?}", hit);
+
+        // flat along the ground from the west, it has to stop at the foot of the hill
+        let ray = Ray {
+            origin: Vec3::new(-20.0, 1.0, -0.5),
+            direction: Vec3::new(1.0, 0.0, 0.0),
+        };
+        let hit = hm.raycast(&ray, Resampling::Bilinear).unwrap();
+        // 1 high means 4 samples away from the top
+        assert!((hit.position.x - -4.5).abs() < 0.05, "{:?}", hit);
+        assert!(hit.slope_degrees() > 5.0);
+
+        // above everything
+        let ray = Ray {
+            origin: Vec3::new(-20.0, 3.0, -0.5),
+            direction: Vec3::new(1.0, 0.0, 0.0),
+        };
+        assert_eq!(None, hm.raycast(&ray, Resampling::Bilinear));
+    }
+
+    #[test]
+    fn test_skipping_blocks_keeps_the_hits() {
+        // a plateau with sharp edges, so the kernels overshoot, in an otherwise flat map
+        let grid = HeightGrid::from_fn(65, 65, |x, y| {
+            if (20..30).contains(&x) && (12..40).contains(&y) {
+                2.0
+            } else {
+                (x + y) as f32 * 0.01
+            }
+        });
+        let hm = HeightMap::create(grid, 65, 65.0);
+        let bounds = hm.min_max_pyramid();
+        // a pyramid where every block has the overall range never skips anything
+        let (min, max) = bounds.min_max();
+        let no_skipping = HeightGrid::from_fn(65, 65, |x, y| [min, max][(x + y) % 2]);
+        let no_skipping = MinMaxPyramid::from_source(&no_skipping, 65, 65);
+
+        let mut rng = StdRng::seed_from_u64(38);
+        let mut hits = 0;
+        for _ in 0..300 {
+            let to = Vec3::new(rng.gen_range(-32.0..32.0), 0.0, rng.gen_range(-32.0..32.0));
+            let from = to + Vec3::new(rng.gen_range(-16.0..16.0), 4.0, rng.gen_range(-16.0..16.0));
+            let ray = Ray {
+                origin: from,
+                direction: (to - from).normalize(),
+            };
+            for resampling in [
+                Resampling::Bilinear,
+                Resampling::Bicubic,
+                Resampling::Lanczos3,
+            ] {
+                let expected = hm.raycast_with_bounds(&ray, &no_skipping, resampling);
+                let hit = hm.raycast_with_bounds(&ray, &bounds, resampling);
+                match (expected, hit) {
+                    // both step along the ray, but not from the same points. Bumps thinner
+                    // than a step can be missed by one of them.
+                    (Some(expected), Some(hit)) => {
+                        assert!(
+                            (expected.distance - hit.distance).abs() < 0.5,
+                            "{:?}: {:?} != {:?}",
+                            resampling,
+                            expected,
+                            hit
+                        );
+                        hits += 1;
+                    }
+                    (None, None) => {}
+                    _ => panic!("{:?}: {:?} != {:?}", resampling, expected, hit),
+                }
+            }
+        }
+        assert!(hits > 500, "{}", hits);
+
+        // just above the plateau, the sharper kernels overshoot at its edge
+        let ray = Ray {
+            origin: Vec3::new(-40.0, 2.03, -7.5),
+            direction: Vec3::X,
+        };
+        for resampling in [Resampling::Bicubic, Resampling::Lanczos3] {
+            let expected = hm.raycast_with_bounds(&ray, &no_skipping, resampling);
+            assert!(expected.is_some());
+            assert_eq!(expected, hm.raycast_with_bounds(&ray, &bounds, resampling));
+        }
+        assert_eq!(
+            None,
+            hm.raycast_with_bounds(&ray, &bounds, Resampling::Bilinear)
+        );
+    }
+
+    #[test]
+    fn test_ray_from_ndc_looks_forward() {
+        let projection = Mat4::perspective_infinite_reverse_rh(1.0, 1.0, 0.1);
+        let camera = Mat4::from_translation(Vec3::new(0.0, 5.0, 0.0));
+        let ray = Ray::from_ndc(0.0, 0.0, camera, projection);
+
+        assert!(ray.origin.abs_diff_eq(Vec3::new(0.0, 5.0, -0.1), 1e-4));
+        assert!(ray.direction.abs_diff_eq(Vec3::new(0.0, 0.0, -1.0), 1e-4));
+    }
+}
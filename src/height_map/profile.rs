@@ -0,0 +1,304 @@
+//! elevation profiles along a path over the terrain: how far, how high, and how steep it gets on
+//! the way.
+use bevy::math::{Vec2, Vec3};
+use image::{Rgb, RgbImage};
+
+use crate::height_map::grid::Resampling;
+use crate::height_map::{HeightMap, HeightSource};
+
+const CHART_BACKGROUND: Rgb<u8> = Rgb([40, 40, 40]);
+const CHART_FILL: Rgb<u8> = Rgb([110, 160, 90]);
+const CHART_LINE: Rgb<u8> = Rgb([240, 220, 120]);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfilePoint {
+    /// along the path from its start, in world units
+    pub distance: f32,
+    /// `None` over voids
+    pub elevation: Option<f32>,
+    /// world x and z
+    pub position: Vec2,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ElevationProfile {
+    pub points: Vec<ProfilePoint>,
+    /// sum of all the climbs along the path, leaving out the gaps over voids
+    pub ascent: f32,
+    /// sum of all the descents along the path, positive
+    pub descent: f32,
+    /// steepest rise (or fall) between two samples, as rise over run. 0.1 is a 10% grade.
+    pub max_grade: f32,
+}
+
+impl ElevationProfile {
+    /// length of the path in world units
+    pub fn length(&self) -> f32 {
+        self.points.last().map_or(0.0, |point| point.distance)
+    }
+
+    /// of the points that are not over a void
+    pub fn min_max(&self) -> (f32, f32) {
+        self.points
+            .iter()
+            .filter_map(|point| point.elevation)
+            .fold((f32::MAX, f32::MIN), |(min, max), elevation| {
+                (min.min(elevation), max.max(elevation))
+            })
+    }
+
+    /// the elevation at some distance along the path, linearly interpolated between samples.
+    /// `None` in the gaps over voids.
+    pub fn elevation_at(&self, distance: f32) -> Option<f32> {
+        let next = self
+            .points
+            .iter()
+            .position(|point| point.distance >= distance)
+            .unwrap_or(self.points.len().checked_sub(1)?);
+        let b = self.points[next];
+        if next == 0 || b.distance <= distance {
+            return b.elevation;
+        }
+        let a = self.points[next - 1];
+        let t = (distance - a.distance) / (b.distance - a.distance);
+        Some(a.elevation? + (b.elevation? - a.elevation?) * t)
+    }
+
+    /// the runs of points between the gaps over voids
+    pub fn sections(&self) -> impl Iterator<Item = &[ProfilePoint]> {
+        self.points
+            .split(|point| point.elevation.is_none())
+            .filter(|section| !section.is_empty())
+    }
+
+    /// a small area chart of the profile, distance to the right and elevation up
+    pub fn chart(&self, width: u32, height: u32) -> RgbImage {
+        let mut image = RgbImage::from_pixel(width, height, CHART_BACKGROUND);
+        let (min, max) = self.min_max();
+        if min > max || width < 2 || height < 2 {
+            return image;
+        }
+        // a bit of room at the top and bottom, and something to show for flat paths
+        let padding = ((max - min) * 0.1).max(1e-3);
+        let (min, max) = (min - padding, max + padding);
+        let row = |elevation: f32| {
+            let t = (elevation - min) / (max - min);
+            ((1.0 - t) * (height - 1) as f32).round() as u32
+        };
+
+        let length = self.length();
+        let mut previous = None;
+        for x in 0..width {
+            let distance = x as f32 / (width - 1) as f32 * length;
+            // gaps stay empty, and the line starts over after them
+            let top = match self.elevation_at(distance) {
+                Some(elevation) => row(elevation),
+                None => {
+                    previous = None;
+                    continue;
+                }
+            };
+            for y in top..height {
+                image.put_pixel(x, y, CHART_FILL);
+            }
+            // connect to the previous column, or steep parts fall apart into dots
+            let (from, to) = match previous {
+                Some(previous) if previous < top => (previous, top),
+                Some(previous) => (top, previous),
+                None => (top, top),
+            };
+            for y in from..=to {
+                image.put_pixel(x, y, CHART_LINE);
+            }
+            previous = Some(top);
+        }
+
+        image
+    }
+}
+
+impl<H: HeightSource> HeightMap<H> {
+    /// samples the terrain along a path of world x, z positions every `step` world units, and at
+    /// each of its corners. Points next to a void get no elevation.
+    pub fn elevation_profile(
+        &self,
+        path: &[Vec2],
+        step: f32,
+        resampling: Resampling,
+    ) -> ElevationProfile {
+        debug_assert!(step > 0.0);
+        let elevation = |position: Vec2| {
+            let (x, y) = self.world_to_grid(position.x, position.y);
+            if self.valid_around(x, y) {
+                Some(self.sample_interpolated(x, y, resampling))
+            } else {
+                None
+            }
+        };
+
+        let mut points: Vec<ProfilePoint> = path
+            .first()
+            .map(|start| ProfilePoint {
+                distance: 0.0,
+                elevation: elevation(*start),
+                position: *start,
+            })
+            .into_iter()
+            .collect();
+        let mut travelled = 0.0;
+        for segment in path.windows(2) {
+            let (a, b) = (segment[0], segment[1]);
+            let length = a.distance(b);
+            let steps = (length / step).ceil().max(1.0) as usize;
+            for i in 1..=steps {
+                let along = if i == steps { length } else { i as f32 * step };
+                let position = a.lerp(b, along / length.max(f32::EPSILON));
+                points.push(ProfilePoint {
+                    distance: travelled + along,
+                    elevation: elevation(position),
+                    position,
+                });
+            }
+            travelled += length;
+        }
+
+        let mut profile = ElevationProfile {
+            points,
+            ..Default::default()
+        };
+        for pair in profile.points.windows(2) {
+            let rise = match (pair[0].elevation, pair[1].elevation) {
+                (Some(from), Some(to)) => to - from,
+                _ => continue,
+            };
+            let run = pair[1].distance - pair[0].distance;
+            if rise > 0.0 {
+                profile.ascent += rise;
+            } else {
+                profile.descent -= rise;
+            }
+            if run > 0.0 {
+                profile.max_grade = profile.max_grade.max(rise.abs() / run);
+            }
+        }
+
+        profile
+    }
+}
+
+/// the path clicked together on the terrain in the viewer, and its profile
+#[derive(Debug, Clone, Default)]
+pub struct TerrainPath {
+    pub points: Vec<Vec3>,
+    pub profile: Option<ElevationProfile>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::grid::{HeightGrid, MaskedGrid};
+
+    /// rises by 0.5 per world unit towards +x
+    fn ramp() -> HeightMap<HeightGrid> {
+        let grid = HeightGrid::from_fn(9, 9, |x, _| x as f32 * 0.5);
+        HeightMap::create(grid, 9, 9.0)
+    }
+
+    #[test]
+    fn test_profile_up_and_down_a_ramp() {
+        let hm = ramp();
+        let path = [
+            Vec2::new(-3.0, 0.0),
+            Vec2::new(2.5, 0.0),
+            Vec2::new(2.5, 2.0),
+            Vec2::new(0.0, 2.0),
+        ];
+        let profile = hm.elevation_profile(&path, 1.0, Resampling::Bilinear);
+
+        assert_eq!(10.0, profile.length());
+        let distances: Vec<f32> = profile.points.iter().map(|point| point.distance).collect();
+        assert_eq!(
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 5.5, 6.5, 7.5, 8.5, 9.5, 10.0],
+            distances
+        );
+        for point in &profile.points {
+            let (x, _) = hm.world_to_grid(point.position.x, point.position.y);
+            assert!((point.elevation.unwrap() - x * 0.5).abs() < 1e-5);
+        }
+
+        assert!((profile.ascent - 2.75).abs() < 1e-5);
+        assert!((profile.descent - 1.25).abs() < 1e-5);
+        assert!((profile.max_grade - 0.5).abs() < 1e-5);
+        assert_eq!(Some(1.5), profile.elevation_at(1.5));
+    }
+
+    #[test]
+    fn test_chart_of_a_climb() {
+        let hm = ramp();
+        let profile = hm.elevation_profile(
+            &[Vec2::new(-4.0, 0.0), Vec2::new(4.0, 0.0)],
+            0.5,
+            Resampling::Bilinear,
+        );
+        let chart = profile.chart(40, 20);
+
+        // the fill reaches higher up on the right
+        let top = |x: u32| (0..20).find(|y| *chart.get_pixel(x, *y) != CHART_BACKGROUND);
+        assert!(top(0).unwrap() > top(20).unwrap());
+        assert!(top(20).unwrap() > top(39).unwrap());
+        assert_eq!(CHART_FILL, *chart.get_pixel(0, 19));
+
+        assert_eq!(
+            RgbImage::from_pixel(4, 4, CHART_BACKGROUND),
+            ElevationProfile::default().chart(4, 4)
+        );
+    }
+
+    #[test]
+    fn test_gaps_over_voids() {
+        // the ramp with a deep void at grid x = 4
+        let mut grid = HeightGrid::from_fn(9, 9, |x, _| x as f32 * 0.5);
+        let mut mask = vec![true; 81];
+        for y in 0..9 {
+            grid.set(4, y, -100.0);
+            mask[y * 9 + 4] = false;
+        }
+        let hm = HeightMap::create(
+            MaskedGrid {
+                grid,
+                mask: Some(mask),
+            },
+            9,
+            9.0,
+        );
+        let profile = hm.elevation_profile(
+            &[Vec2::new(-4.0, 0.0), Vec2::new(4.0, 0.0)],
+            0.5,
+            Resampling::Bilinear,
+        );
+
+        // the path starts at grid x 0.5, everything from grid x 3 up to 5 is next to the void
+        let gaps: Vec<f32> = profile
+            .points
+            .iter()
+            .filter(|point| point.elevation.is_none())
+            .map(|point| point.distance)
+            .collect();
+        assert_eq!(vec![2.5, 3.0, 3.5, 4.0], gaps);
+        assert_eq!(2, profile.sections().count());
+        assert_eq!((0.25, 4.0), profile.min_max());
+        // the climb across the gap is not counted, and nothing goes down into the void
+        assert!((profile.ascent - 2.5).abs() < 1e-5);
+        assert_eq!(0.0, profile.descent);
+        assert!((profile.max_grade - 0.5).abs() < 1e-5);
+        assert_eq!(None, profile.elevation_at(3.2));
+        assert_eq!(None, profile.elevation_at(2.2));
+        assert_eq!(Some(1.25), profile.elevation_at(2.0));
+
+        // the chart has an empty gap in the middle
+        let chart = profile.chart(81, 20);
+        assert_eq!(CHART_BACKGROUND, *chart.get_pixel(40, 19));
+        assert_eq!(CHART_FILL, *chart.get_pixel(10, 19));
+        assert_eq!(CHART_FILL, *chart.get_pixel(70, 19));
+    }
+}
@@ -0,0 +1,828 @@
+//! encoder (and decoder) for Cesium's [quantized-mesh-1.0][0] terrain tiles.
+//!
+//! Quantized mesh servers use RTIN (or something very close to it) to simplify their tiles, so
+//! the indices we get from [`RtinMeshBuilder`] can be used as they are. All that is left to do is
+//! to quantize the vertices into the 0..32767 range of the tile, put the whole thing on the WGS84
+//! ellipsoid for the bounding volumes, and pack everything into the (little endian) binary layout.
+//!
+//! [0]: https://github.com/CesiumGS/quantized-mesh
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::bail;
+
+use crate::height_map::rtin::RtinMeshBuilder;
+use crate::height_map::{HeightMap, HeightSource};
+
+const QUANTIZED_MAX: f64 = 32767.0;
+const OCT_NORMALS_EXTENSION_ID: u8 = 1;
+
+const WGS84_RADII: [f64; 3] = [6378137.0, 6378137.0, 6356752.314245179];
+const WGS84_ECCENTRICITY_SQUARED: f64 = 6.69437999014e-3;
+
+/// a rectangle on the globe, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeographicBounds {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl GeographicBounds {
+    fn center(&self) -> (f64, f64) {
+        (
+            (self.west + self.east) / 2.0,
+            (self.south + self.north) / 2.0,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuantizedMeshOptions {
+    /// passed on to [`RtinMeshBuilder::get_indices`], in units of the height source
+    pub max_error: f32,
+    /// multiplied with every sample of the height source to get to metres
+    pub height_scale: f32,
+    /// add the `octvertexnormals` extension to the tiles
+    pub normals: bool,
+}
+
+impl Default for QuantizedMeshOptions {
+    fn default() -> Self {
+        Self {
+            max_error: 0.002,
+            height_scale: 1.0,
+            normals: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuantizedMeshHeader {
+    pub center: [f64; 3],
+    pub minimum_height: f32,
+    pub maximum_height: f32,
+    pub bounding_sphere_center: [f64; 3],
+    pub bounding_sphere_radius: f64,
+    pub horizon_occlusion_point: [f64; 3],
+}
+
+/// a single tile, with all values in their quantized (but not yet delta or high water mark
+/// encoded) form.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuantizedMeshTile {
+    pub header: QuantizedMeshHeader,
+    pub u: Vec<u16>,
+    pub v: Vec<u16>,
+    pub height: Vec<u16>,
+    pub indices: Vec<u32>,
+    pub west_indices: Vec<u32>,
+    pub south_indices: Vec<u32>,
+    pub east_indices: Vec<u32>,
+    pub north_indices: Vec<u32>,
+    pub normals: Option<Vec<[u8; 2]>>,
+}
+
+impl QuantizedMeshTile {
+    /// builds a tile covering `bounds` from the simplified RTIN mesh. Row 0 of the height map is
+    /// the northern edge of the tile.
+    pub fn from_rtin<T: HeightSource>(
+        rtin: &RtinMeshBuilder<T>,
+        bounds: &GeographicBounds,
+        options: &QuantizedMeshOptions,
+    ) -> Self {
+        let hm = rtin.height_map();
+        let size = hm.source_size;
+        let last = (size - 1) as f64;
+
+        // high water mark encoding only works if vertices appear in the order they are first
+        // used by the index buffer, so we renumber them while collecting them
+        let mut remap = vec![u32::MAX; size * size];
+        let mut grid_offsets = vec![];
+        let indices: Vec<u32> = rtin
+            .get_indices(options.max_error)
+            .into_iter()
+            .map(|offset| {
+                let offset = offset as usize;
+                if remap[offset] == u32::MAX {
+                    remap[offset] = grid_offsets.len() as u32;
+                    grid_offsets.push(offset);
+                }
+                remap[offset]
+            })
+            .collect();
+
+        let heights: Vec<f64> = grid_offsets
+            .iter()
+            .map(|offset| (hm.sample(offset % size, offset / size) * options.height_scale) as f64)
+            .collect();
+        let minimum_height = heights.iter().copied().fold(f64::INFINITY, f64::min);
+        let maximum_height = heights.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let height_range = maximum_height - minimum_height;
+
+        let mut tile = QuantizedMeshTile::default();
+        for (vertex, (offset, h)) in grid_offsets.iter().zip(&heights).enumerate() {
+            let (x, y) = (offset % size, offset / size);
+            let vertex = vertex as u32;
+            tile.u
+                .push((x as f64 / last * QUANTIZED_MAX).round() as u16);
+            tile.v
+                .push(((last - y as f64) / last * QUANTIZED_MAX).round() as u16);
+            tile.height.push(if height_range > 0.0 {
+                ((h - minimum_height) / height_range * QUANTIZED_MAX).round() as u16
+            } else {
+                0
+            });
+
+            if x == 0 {
+                tile.west_indices.push(vertex);
+            }
+            if x == size - 1 {
+                tile.east_indices.push(vertex);
+            }
+            if y == 0 {
+                tile.north_indices.push(vertex);
+            }
+            if y == size - 1 {
+                tile.south_indices.push(vertex);
+            }
+        }
+        tile.indices = indices;
+
+        let (center_lon, center_lat) = bounds.center();
+        let center = geodetic_to_ecef(
+            center_lon,
+            center_lat,
+            (minimum_height + maximum_height) / 2.0,
+        );
+        let ecef_positions: Vec<[f64; 3]> = tile
+            .u
+            .iter()
+            .zip(&tile.v)
+            .zip(&heights)
+            .map(|((u, v), h)| {
+                let lon = bounds.west + (bounds.east - bounds.west) * (*u as f64 / QUANTIZED_MAX);
+                let lat =
+                    bounds.south + (bounds.north - bounds.south) * (*v as f64 / QUANTIZED_MAX);
+                geodetic_to_ecef(lon, lat, *h)
+            })
+            .collect();
+        let bounding_sphere_radius = ecef_positions
+            .iter()
+            .map(|p| length(sub(*p, center)))
+            .fold(0.0, f64::max);
+
+        tile.header = QuantizedMeshHeader {
+            center,
+            minimum_height: minimum_height as f32,
+            maximum_height: maximum_height as f32,
+            bounding_sphere_center: center,
+            bounding_sphere_radius,
+            horizon_occlusion_point: horizon_occlusion_point(center, &ecef_positions),
+        };
+
+        if options.normals {
+            tile.normals = Some(tile.oct_encoded_normals(bounds, &heights));
+        }
+
+        tile
+    }
+
+    /// area weighted vertex normals, calculated in a local east/north/up frame with real metres
+    /// and then rotated onto the ellipsoid.
+    fn oct_encoded_normals(&self, bounds: &GeographicBounds, heights: &[f64]) -> Vec<[u8; 2]> {
+        let (center_lon, center_lat) = bounds.center();
+        let width = (bounds.east - bounds.west).to_radians()
+            * WGS84_RADII[0]
+            * center_lat.to_radians().cos();
+        let depth = (bounds.north - bounds.south).to_radians() * WGS84_RADII[0];
+
+        let local: Vec<[f64; 3]> = self
+            .u
+            .iter()
+            .zip(&self.v)
+            .zip(heights)
+            .map(|((u, v), h)| {
+                [
+                    *u as f64 / QUANTIZED_MAX * width,
+                    *v as f64 / QUANTIZED_MAX * depth,
+                    *h,
+                ]
+            })
+            .collect();
+
+        let mut normals = vec![[0.0; 3]; local.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [
+                local[triangle[0] as usize],
+                local[triangle[1] as usize],
+                local[triangle[2] as usize],
+            ];
+            let face = cross(sub(b, a), sub(c, a));
+            for index in triangle {
+                let normal = &mut normals[*index as usize];
+                *normal = add(*normal, face);
+            }
+        }
+
+        let (lon, lat) = (center_lon.to_radians(), center_lat.to_radians());
+        let east = [-lon.sin(), lon.cos(), 0.0];
+        let north = [-lat.sin() * lon.cos(), -lat.sin() * lon.sin(), lat.cos()];
+        let up = [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()];
+
+        normals
+            .into_iter()
+            .map(|[e, n, u]| {
+                let ecef = add(add(scale(east, e), scale(north, n)), scale(up, u));
+                oct_encode(normalize(ecef))
+            })
+            .collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let header = &self.header;
+        for value in header.center {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&header.minimum_height.to_le_bytes());
+        out.extend_from_slice(&header.maximum_height.to_le_bytes());
+        for value in header.bounding_sphere_center {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&header.bounding_sphere_radius.to_le_bytes());
+        for value in header.horizon_occlusion_point {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let vertex_count = self.u.len();
+        out.extend_from_slice(&(vertex_count as u32).to_le_bytes());
+        for values in [&self.u, &self.v, &self.height] {
+            let mut previous = 0i32;
+            for value in values.iter() {
+                let value = *value as i32;
+                out.extend_from_slice(&zig_zag_encode(value - previous).to_le_bytes());
+                previous = value;
+            }
+        }
+
+        let wide_indices = vertex_count > 65536;
+        if wide_indices {
+            while out.len() % 4 != 0 {
+                out.push(0);
+            }
+        }
+        let push_index = |out: &mut Vec<u8>, index: u32| {
+            if wide_indices {
+                out.extend_from_slice(&index.to_le_bytes());
+            } else {
+                out.extend_from_slice(&(index as u16).to_le_bytes());
+            }
+        };
+
+        out.extend_from_slice(&((self.indices.len() / 3) as u32).to_le_bytes());
+        let mut highest = 0;
+        for index in &self.indices {
+            push_index(&mut out, highest - index);
+            if *index == highest {
+                highest += 1;
+            }
+        }
+
+        for edge in [
+            &self.west_indices,
+            &self.south_indices,
+            &self.east_indices,
+            &self.north_indices,
+        ] {
+            out.extend_from_slice(&(edge.len() as u32).to_le_bytes());
+            for index in edge {
+                push_index(&mut out, *index);
+            }
+        }
+
+        if let Some(normals) = &self.normals {
+            out.push(OCT_NORMALS_EXTENSION_ID);
+            out.extend_from_slice(&((normals.len() * 2) as u32).to_le_bytes());
+            for normal in normals {
+                out.extend_from_slice(normal);
+            }
+        }
+
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader { bytes, position: 0 };
+        let mut tile = QuantizedMeshTile::default();
+
+        let header = &mut tile.header;
+        for value in header.center.iter_mut() {
+            *value = reader.f64()?;
+        }
+        header.minimum_height = reader.f32()?;
+        header.maximum_height = reader.f32()?;
+        for value in header.bounding_sphere_center.iter_mut() {
+            *value = reader.f64()?;
+        }
+        header.bounding_sphere_radius = reader.f64()?;
+        for value in header.horizon_occlusion_point.iter_mut() {
+            *value = reader.f64()?;
+        }
+
+        let vertex_count = reader.u32()? as usize;
+        for values in [&mut tile.u, &mut tile.v, &mut tile.height] {
+            let mut value = 0i32;
+            for _ in 0..vertex_count {
+                value += zig_zag_decode(reader.u16()?);
+                values.push(value as u16);
+            }
+        }
+
+        let wide_indices = vertex_count > 65536;
+        if wide_indices {
+            reader.align(4);
+        }
+        let read_index = |reader: &mut Reader| -> anyhow::Result<u32> {
+            if wide_indices {
+                reader.u32()
+            } else {
+                Ok(reader.u16()? as u32)
+            }
+        };
+
+        let triangle_count = reader.u32()? as usize;
+        let mut highest = 0;
+        for _ in 0..triangle_count * 3 {
+            let code = read_index(&mut reader)?;
+            if code > highest {
+                bail!(
+                    "invalid high water mark code {} (highest {})",
+                    code,
+                    highest
+                );
+            }
+            tile.indices.push(highest - code);
+            if code == 0 {
+                highest += 1;
+            }
+        }
+
+        for edge in [
+            &mut tile.west_indices,
+            &mut tile.south_indices,
+            &mut tile.east_indices,
+            &mut tile.north_indices,
+        ] {
+            let count = reader.u32()?;
+            for _ in 0..count {
+                edge.push(read_index(&mut reader)?);
+            }
+        }
+
+        while reader.position < bytes.len() {
+            let id = reader.u8()?;
+            let length = reader.u32()? as usize;
+            if id == OCT_NORMALS_EXTENSION_ID {
+                let mut normals = Vec::with_capacity(vertex_count);
+                for _ in 0..vertex_count {
+                    normals.push([reader.u8()?, reader.u8()?]);
+                }
+                tile.normals = Some(normals);
+            } else {
+                // unknown extensions are skipped, that is what they have a length for
+                reader.skip(length)?;
+            }
+        }
+
+        Ok(tile)
+    }
+}
+
+/// writes a TMS pyramid (`{z}/{x}/{y}.terrain` plus `layer.json`) in the geographic tiling scheme
+/// Cesium uses by default: two tiles at level 0, each level splitting every tile into four.
+pub struct QuantizedMeshTiler {
+    bounds: GeographicBounds,
+    /// samples per tile edge, has to be a power of two plus one for RTIN
+    tile_size: usize,
+    max_level: u32,
+    options: QuantizedMeshOptions,
+}
+
+impl QuantizedMeshTiler {
+    pub fn new(bounds: GeographicBounds, max_level: u32) -> Self {
+        Self {
+            bounds,
+            tile_size: 65,
+            max_level,
+            options: QuantizedMeshOptions::default(),
+        }
+    }
+
+    pub fn tile_size(mut self, tile_size: usize) -> Self {
+        debug_assert!((tile_size - 1).is_power_of_two());
+        self.tile_size = tile_size;
+        self
+    }
+
+    pub fn options(mut self, options: QuantizedMeshOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// `source` is a square grid of `source_size` samples covering the bounds of the tiler, with
+    /// row 0 being the northern edge.
+    pub fn write_tiles<H: HeightSource>(
+        &self,
+        source: &H,
+        source_size: usize,
+        out_dir: &Path,
+    ) -> io::Result<()> {
+        let mut available = vec![];
+        for level in 0..=self.max_level {
+            let (x_range, y_range) = self.tile_range(level);
+            for x in x_range.clone() {
+                for y in y_range.clone() {
+                    let bounds = tile_bounds(level, x, y);
+                    let tile_source = TileHeightSource {
+                        source,
+                        source_size,
+                        source_bounds: &self.bounds,
+                        tile_bounds: &bounds,
+                        tile_size: self.tile_size,
+                    };
+                    let hm = HeightMap::create(tile_source, self.tile_size, 1.0);
+                    let rtin = RtinMeshBuilder::from_height_map(hm);
+                    let tile = QuantizedMeshTile::from_rtin(&rtin, &bounds, &self.options);
+
+                    let dir = out_dir.join(level.to_string()).join(x.to_string());
+                    fs::create_dir_all(&dir)?;
+                    fs::write(dir.join(format!("{}.terrain", y)), tile.encode())?;
+                }
+            }
+            available.push(format!(
+                "[{{\"startX\":{},\"startY\":{},\"endX\":{},\"endY\":{}}}]",
+                x_range.start(),
+                y_range.start(),
+                x_range.end(),
+                y_range.end()
+            ));
+        }
+
+        let extensions = if self.options.normals {
+            "\"octvertexnormals\""
+        } else {
+            ""
+        };
+        let layer = format!(
+            r#"{{
+  "tilejson": "2.1.0",
+  "name": "venture",
+  "version": "1.0.0",
+  "format": "quantized-mesh-1.0",
+  "scheme": "tms",
+  "projection": "EPSG:4326",
+  "tiles": ["{{z}}/{{x}}/{{y}}.terrain?v={{version}}"],
+  "minzoom": 0,
+  "maxzoom": {},
+  "bounds": [{}, {}, {}, {}],
+  "extensions": [{}],
+  "available": [{}]
+}}
+"#,
+            self.max_level,
+            self.bounds.west,
+            self.bounds.south,
+            self.bounds.east,
+            self.bounds.north,
+            extensions,
+            available.join(", ")
+        );
+        fs::write(out_dir.join("layer.json"), layer)
+    }
+
+    /// all tiles on `level` that overlap with our bounds
+    fn tile_range(
+        &self,
+        level: u32,
+    ) -> (std::ops::RangeInclusive<u32>, std::ops::RangeInclusive<u32>) {
+        let tile_degrees = 180.0 / (1u32 << level) as f64;
+        let tiles_x = 2u32 << level;
+        let tiles_y = 1u32 << level;
+        let to_tile = |degrees: f64, tiles: u32| ((degrees / tile_degrees) as u32).min(tiles - 1);
+
+        let x_range = to_tile(self.bounds.west + 180.0, tiles_x)
+            ..=to_tile((self.bounds.east + 180.0 - 1e-9).max(0.0), tiles_x);
+        let y_range = to_tile(self.bounds.south + 90.0, tiles_y)
+            ..=to_tile((self.bounds.north + 90.0 - 1e-9).max(0.0), tiles_y);
+
+        (x_range, y_range)
+    }
+}
+
+fn tile_bounds(level: u32, x: u32, y: u32) -> GeographicBounds {
+    let tile_degrees = 180.0 / (1u32 << level) as f64;
+    GeographicBounds {
+        west: -180.0 + x as f64 * tile_degrees,
+        south: -90.0 + y as f64 * tile_degrees,
+        east: -180.0 + (x + 1) as f64 * tile_degrees,
+        north: -90.0 + (y + 1) as f64 * tile_degrees,
+    }
+}
+
+/// resamples the part of a larger height source that is covered by a single tile, clamping to
+/// the edge of the source for everything outside of it.
+struct TileHeightSource<'a, H: HeightSource> {
+    source: &'a H,
+    source_size: usize,
+    source_bounds: &'a GeographicBounds,
+    tile_bounds: &'a GeographicBounds,
+    tile_size: usize,
+}
+
+impl<'a, H: HeightSource> HeightSource for TileHeightSource<'a, H> {
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        let last = (self.tile_size - 1) as f64;
+        let (t, s) = (self.tile_bounds, self.source_bounds);
+        let lon = t.west + (t.east - t.west) * (x as f64 / last);
+        let lat = t.north - (t.north - t.south) * (y as f64 / last);
+
+        let source_last = (self.source_size - 1) as f64;
+        let sx = ((lon - s.west) / (s.east - s.west) * source_last).clamp(0.0, source_last);
+        let sy = ((s.north - lat) / (s.north - s.south) * source_last).clamp(0.0, source_last);
+
+        let (x0, y0) = (sx.floor() as usize, sy.floor() as usize);
+        let (x1, y1) = (
+            (x0 + 1).min(self.source_size - 1),
+            (y0 + 1).min(self.source_size - 1),
+        );
+        let (fx, fy) = ((sx - x0 as f64) as f32, (sy - y0 as f64) as f32);
+
+        let top =
+            self.source.sample_height(x0, y0) * (1.0 - fx) + self.source.sample_height(x1, y0) * fx;
+        let bottom =
+            self.source.sample_height(x0, y1) * (1.0 - fx) + self.source.sample_height(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+fn zig_zag_encode(value: i32) -> u16 {
+    ((value << 1) ^ (value >> 31)) as u16
+}
+
+fn zig_zag_decode(value: u16) -> i32 {
+    let value = value as i32;
+    (value >> 1) ^ -(value & 1)
+}
+
+fn oct_encode(normal: [f64; 3]) -> [u8; 2] {
+    let sum = normal[0].abs() + normal[1].abs() + normal[2].abs();
+    let (mut x, mut y) = (normal[0] / sum, normal[1] / sum);
+    if normal[2] < 0.0 {
+        let (old_x, old_y) = (x, y);
+        x = (1.0 - old_y.abs()) * sign_not_zero(old_x);
+        y = (1.0 - old_x.abs()) * sign_not_zero(old_y);
+    }
+    let to_snorm = |value: f64| ((value.clamp(-1.0, 1.0) * 0.5 + 0.5) * 255.0).round() as u8;
+
+    [to_snorm(x), to_snorm(y)]
+}
+
+fn sign_not_zero(value: f64) -> f64 {
+    if value < 0.0 {
+        -1.0
+    } else {
+        1.0
+    }
+}
+
+fn geodetic_to_ecef(lon: f64, lat: f64, height: f64) -> [f64; 3] {
+    let (lon, lat) = (lon.to_radians(), lat.to_radians());
+    let n = WGS84_RADII[0] / (1.0 - WGS84_ECCENTRICITY_SQUARED * lat.sin().powi(2)).sqrt();
+    [
+        (n + height) * lat.cos() * lon.cos(),
+        (n + height) * lat.cos() * lon.sin(),
+        (n * (1.0 - WGS84_ECCENTRICITY_SQUARED) + height) * lat.sin(),
+    ]
+}
+
+/// the same thing Cesium's `EllipsoidalOccluder.computeHorizonCullingPoint` does: find the point
+/// along the direction to the tile center, in ellipsoid scaled space, from which all vertices are
+/// hidden behind the horizon.
+fn horizon_occlusion_point(center: [f64; 3], positions: &[[f64; 3]]) -> [f64; 3] {
+    let to_scaled = |p: [f64; 3]| {
+        [
+            p[0] / WGS84_RADII[0],
+            p[1] / WGS84_RADII[1],
+            p[2] / WGS84_RADII[2],
+        ]
+    };
+    let direction = normalize(to_scaled(center));
+
+    let magnitude = positions
+        .iter()
+        .map(|position| {
+            let scaled = to_scaled(*position);
+            let magnitude_squared = dot(scaled, scaled).max(1.0);
+            let magnitude = magnitude_squared.sqrt();
+            let position_direction = scale(scaled, 1.0 / magnitude);
+
+            let cos_alpha = dot(position_direction, direction);
+            let sin_alpha = length(cross(position_direction, direction));
+            let cos_beta = 1.0 / magnitude;
+            let sin_beta = (magnitude_squared - 1.0).sqrt() * cos_beta;
+
+            1.0 / (cos_alpha * cos_beta - sin_alpha * sin_beta)
+        })
+        .filter(|magnitude| magnitude.is_finite() && *magnitude > 0.0)
+        .fold(0.0, f64::max);
+
+    scale(direction, magnitude)
+}
+
+fn add(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f64; 3], s: f64) -> [f64; 3] {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn length(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let length = length(a);
+    if length == 0.0 {
+        return [0.0, 0.0, 1.0];
+    }
+    scale(a, 1.0 / length)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        if self.position + N > self.bytes.len() {
+            bail!("unexpected end of tile at byte {}", self.position);
+        }
+        let mut value = [0u8; N];
+        value.copy_from_slice(&self.bytes[self.position..self.position + N]);
+        self.position += N;
+        Ok(value)
+    }
+
+    fn skip(&mut self, length: usize) -> anyhow::Result<()> {
+        if self.position + length > self.bytes.len() {
+            bail!("unexpected end of tile at byte {}", self.position);
+        }
+        self.position += length;
+        Ok(())
+    }
+
+    fn align(&mut self, alignment: usize) {
+        self.position += (alignment - self.position % alignment) % alignment;
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    fn f64(&mut self) -> anyhow::Result<f64> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct SlopeHeightSource;
+
+    impl HeightSource for SlopeHeightSource {
+        fn sample_height(&self, x: usize, y: usize) -> f32 {
+            (x as f32 * 0.3).sin() + y as f32 * 0.1
+        }
+    }
+
+    const BOUNDS: GeographicBounds = GeographicBounds {
+        west: 10.0,
+        south: 45.0,
+        east: 10.5,
+        north: 45.5,
+    };
+
+    #[test]
+    fn test_zig_zag_round_trip() {
+        for value in [0, 1, -1, 2, -2, 16383, -16384, 32767, -32767] {
+            assert_eq!(value, zig_zag_decode(zig_zag_encode(value)));
+        }
+        assert_eq!(1, zig_zag_encode(-1));
+        assert_eq!(2, zig_zag_encode(1));
+    }
+
+    #[test]
+    fn test_tile_survives_encode_decode() {
+        let hm = HeightMap::create(SlopeHeightSource, 17, 1.0);
+        let rtin = RtinMeshBuilder::from_height_map(hm);
+        let options = QuantizedMeshOptions {
+            max_error: 0.01,
+            height_scale: 100.0,
+            normals: true,
+        };
+        let tile = QuantizedMeshTile::from_rtin(&rtin, &BOUNDS, &options);
+
+        let decoded = QuantizedMeshTile::decode(&tile.encode()).unwrap();
+        assert_eq!(tile, decoded);
+
+        // the north western corner is on both edges
+        let corner = (0..tile.u.len() as u32)
+            .find(|i| tile.u[*i as usize] == 0 && tile.v[*i as usize] == 32767)
+            .unwrap();
+        assert!(tile.west_indices.contains(&corner));
+        assert!(tile.north_indices.contains(&corner));
+        for index in &tile.west_indices {
+            assert_eq!(0, tile.u[*index as usize]);
+        }
+        for index in &tile.north_indices {
+            assert_eq!(32767, tile.v[*index as usize]);
+        }
+        assert!(tile.header.minimum_height < tile.header.maximum_height);
+        assert_eq!(tile.u.len(), tile.normals.unwrap().len());
+    }
+
+    #[test]
+    fn test_vertices_are_ordered_for_high_water_mark() {
+        let hm = HeightMap::create(SlopeHeightSource, 9, 1.0);
+        let rtin = RtinMeshBuilder::from_height_map(hm);
+        let tile = QuantizedMeshTile::from_rtin(&rtin, &BOUNDS, &QuantizedMeshOptions::default());
+
+        let mut highest = 0;
+        for index in &tile.indices {
+            assert!(*index <= highest);
+            if *index == highest {
+                highest += 1;
+            }
+        }
+        assert_eq!(tile.u.len() as u32, highest);
+    }
+
+    #[test]
+    fn test_tiler_writes_tms_pyramid() {
+        let out_dir = std::env::temp_dir().join("venture-quantized-mesh-test");
+        let _ = fs::remove_dir_all(&out_dir);
+
+        QuantizedMeshTiler::new(BOUNDS, 3)
+            .tile_size(9)
+            .write_tiles(&SlopeHeightSource, 33, &out_dir)
+            .unwrap();
+
+        // level 0 has two tiles of 180 degrees, we are in the eastern one
+        assert!(out_dir.join("0/1/0.terrain").exists());
+        assert!(!out_dir.join("0/0/0.terrain").exists());
+        // 22.5 degree tiles at level 3
+        let bytes = fs::read(out_dir.join("3/8/6.terrain")).unwrap();
+        let tile = QuantizedMeshTile::decode(&bytes).unwrap();
+        assert!(!tile.indices.is_empty());
+
+        let layer = fs::read_to_string(out_dir.join("layer.json")).unwrap();
+        assert!(layer.contains("\"format\": \"quantized-mesh-1.0\""));
+        assert!(layer.contains("{\"startX\":8,\"startY\":6,\"endX\":8,\"endY\":6}"));
+
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//! elevation rasters as they come out of GIS tools, as opposed to images that happen to contain
+//! heights. Samples are in metres, and we know how far apart they are.
+use anyhow::{bail, Context};
+
+use crate::height_map::HeightSource;
+
+/// void value used by SRTM tiles
+const HGT_VOID: i16 = -32768;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Raster {
+    pub width: usize,
+    pub height: usize,
+    /// distance between two neighbouring samples, in metres
+    pub cell_size: f32,
+    /// the value the source used for cells without data. Those cells have already been replaced
+    /// by the lowest valid height when loading, [`Raster::mask`] still knows where they were.
+    pub nodata: Option<f32>,
+    /// row by row, starting in the north west
+    pub data: Vec<f32>,
+    /// `false` for cells without data, `None` if there are none
+    pub mask: Option<Vec<bool>>,
+}
+
+impl Raster {
+    /// `data` has to contain `width * height` samples. Samples equal to `nodata` get masked and
+    /// replaced by the lowest valid height.
+    pub fn new(
+        width: usize,
+        height: usize,
+        cell_size: f32,
+        nodata: Option<f32>,
+        data: Vec<f32>,
+    ) -> Self {
+        debug_assert_eq!(width * height, data.len());
+        let mut raster = Self {
+            width,
+            height,
+            cell_size,
+            nodata,
+            data,
+            mask: None,
+        };
+        raster.fill_nodata();
+        raster
+    }
+
+    /// parses an [ESRI ASCII grid][0]. The cell size is expected to be in metres.
+    ///
+    /// [0]: https://desktop.arcgis.com/en/arcmap/latest/manage-data/raster-and-images/esri-ascii-raster-format.htm
+    pub fn parse_ascii_grid(text: &str) -> anyhow::Result<Self> {
+        let mut tokens = text.split_whitespace().peekable();
+
+        let mut width = None;
+        let mut height = None;
+        let mut cell_size = None;
+        let mut nodata = None;
+        // header lines are `key value`, the data starts with the first token that is a number
+        while let Some(key) = tokens.next_if(|token| token.parse::<f32>().is_err()) {
+            let value = tokens
+                .next()
+                .with_context(|| format!("missing value for {}", key))?;
+            match key.to_lowercase().as_str() {
+                "ncols" => width = Some(value.parse::<usize>()?),
+                "nrows" => height = Some(value.parse::<usize>()?),
+                "cellsize" => cell_size = Some(value.parse::<f32>()?),
+                "nodata_value" => nodata = Some(value.parse::<f32>()?),
+                // we do not georeference (yet), but they are valid header entries
+                "xllcorner" | "yllcorner" | "xllcenter" | "yllcenter" => {}
+                _ => bail!("unknown ascii grid header entry {}", key),
+            }
+        }
+
+        let width = width.context("ascii grid without ncols")?;
+        let height = height.context("ascii grid without nrows")?;
+        let cell_size = cell_size.context("ascii grid without cellsize")?;
+        // a mesh needs at least one cell
+        if width < 2 || height < 2 {
+            bail!("ascii grid of {} x {} samples is too small", width, height);
+        }
+        let data = tokens
+            .map(|token| token.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if data.len() != width * height {
+            bail!(
+                "ascii grid has {} values, expected {} x {}",
+                data.len(),
+                width,
+                height
+            );
+        }
+
+        Ok(Self::new(width, height, cell_size, nodata, data))
+    }
+
+    /// parses an SRTM `.hgt` tile: a square of big endian `i16` metres, without any header. The
+    /// official tiles are 1201 (3 arc seconds) or 3601 (1 arc second) samples wide, but any
+    /// square works.
+    ///
+    /// The cell size is the north-south distance between samples, the east-west distance shrinks
+    /// towards the poles but we ignore that for now.
+    pub fn parse_hgt(bytes: &[u8]) -> anyhow::Result<Self> {
+        let samples = bytes.len() / 2;
+        let size = (samples as f64).sqrt().round() as usize;
+        if size * size * 2 != bytes.len() || size < 2 {
+            bail!("hgt file with {} bytes is not a square grid", bytes.len());
+        }
+
+        let data = bytes
+            .chunks_exact(2)
+            .map(|value| i16::from_be_bytes([value[0], value[1]]) as f32)
+            .collect();
+
+        // a tile always covers one degree
+        let arc_seconds = 3600.0 / (size - 1) as f32;
+        let metres_per_arc_second = 6371008.8 * std::f32::consts::PI / 180.0 / 3600.0;
+
+        Ok(Self::new(
+            size,
+            size,
+            arc_seconds * metres_per_arc_second,
+            Some(HGT_VOID as f32),
+            data,
+        ))
+    }
+
+    /// lowest and highest sample
+    pub fn min_max(&self) -> (f32, f32) {
+        self.data
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), value| {
+                (min.min(*value), max.max(*value))
+            })
+    }
+
+    fn is_nodata(&self, value: f32) -> bool {
+        self.nodata == Some(value) || value.is_nan()
+    }
+
+    /// mask voids and replace them with the lowest valid sample, so they do not show up as giant
+    /// spikes
+    fn fill_nodata(&mut self) {
+        let lowest = self
+            .data
+            .iter()
+            .copied()
+            .filter(|value| !self.is_nodata(*value))
+            .fold(f32::INFINITY, f32::min);
+        let lowest = if lowest.is_finite() { lowest } else { 0.0 };
+
+        let mask: Vec<bool> = self
+            .data
+            .iter()
+            .map(|value| !self.is_nodata(*value))
+            .collect();
+        for (value, valid) in self.data.iter_mut().zip(&mask) {
+            if !valid {
+                *value = lowest;
+            }
+        }
+        if mask.contains(&false) {
+            self.mask = Some(mask);
+        }
+    }
+}
+
+impl HeightSource for Raster {
+    #[inline]
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    fn is_valid(&self, x: usize, y: usize) -> bool {
+        match &self.mask {
+            Some(mask) => mask[y * self.width + x],
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::picking::{intersect_triangles, Ray};
+    use crate::height_map::{terrain_from_raster, VoidOptions, MAX_ERROR};
+    use bevy::math::Vec3;
+    use bevy::prelude::Mesh;
+    use bevy::render::mesh::{Indices, VertexAttributeValues};
+
+    #[test]
+    fn test_parse_ascii_grid_fixture() {
+        let raster = Raster::parse_ascii_grid(include_str!("../../assets/small_grid.asc")).unwrap();
+
+        assert_eq!(5, raster.width);
+        assert_eq!(5, raster.height);
+        assert_eq!(30.0, raster.cell_size);
+        assert_eq!(Some(-9999.0), raster.nodata);
+        assert_eq!(100.0, raster.sample_height(0, 0));
+        assert_eq!(140.0, raster.sample_height(4, 0));
+        assert_eq!(180.0, raster.sample_height(4, 4));
+        // the single nodata cell got the lowest height, but is still known to be a void
+        assert_eq!(100.0, raster.sample_height(2, 2));
+        assert!(!raster.is_valid(2, 2));
+        assert!(raster.is_valid(1, 2));
+        assert_eq!((100.0, 180.0), raster.min_max());
+    }
+
+    #[test]
+    fn test_parse_hgt_fixture() {
+        let raster = Raster::parse_hgt(include_bytes!("../../assets/N00E000.hgt")).unwrap();
+
+        assert_eq!(5, raster.width);
+        assert_eq!(5, raster.height);
+        // 900 arc seconds between samples
+        assert!((raster.cell_size - 27798.0).abs() < 1.0);
+        assert_eq!(-10.0, raster.sample_height(0, 0));
+        assert_eq!(300.0, raster.sample_height(1, 0));
+        assert_eq!(1000.0, raster.sample_height(4, 4));
+        // the void became the lowest valid sample instead of -32768
+        assert_eq!(-10.0, raster.sample_height(2, 2));
+    }
+
+    #[test]
+    fn test_odd_sized_rasters_keep_every_sample() {
+        let raster = Raster::parse_ascii_grid(include_str!("../../assets/odd_grid.asc")).unwrap();
+        assert_eq!((6, 6), (raster.width, raster.height));
+        let mesh = terrain_from_raster(raster, VoidOptions::default()).mesh();
+
+        // RTIN can not split 5 cells in halves, 9 samples it can
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions,
+            _ => panic!("the terrain has positions"),
+        };
+        assert_eq!(9 * 9, positions.len());
+        let indices = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices,
+            _ => panic!("the terrain has 32 bit indices"),
+        };
+
+        // the surface has to go through every sample (give or take the simplification), which
+        // it does not if parts of the grid are left out of the bintree
+        for position in positions {
+            let ray = Ray {
+                origin: Vec3::from(*position) + Vec3::new(1e-4, 100.0, 1e-4),
+                direction: Vec3::new(0.0, -1.0, 0.0),
+            };
+            let hit = match intersect_triangles(&ray, positions, indices) {
+                Some(hit) => hit,
+                // the last row and column, the nudge moved the ray off the terrain
+                None => continue,
+            };
+            assert!(
+                (hit.position.y - position[1]).abs() < MAX_ERROR * 2.0,
+                "{:?} is not on the surface ({})",
+                position,
+                hit.position.y
+            );
+        }
+    }
+
+    #[test]
+    fn test_wide_rasters_are_padded_not_cropped() {
+        let data = (0..5 * 3).map(|offset| offset as f32).collect();
+        let raster = Raster::new(5, 3, 2.0, None, data);
+        let terrain = terrain_from_raster(raster, VoidOptions::default());
+        let grid = terrain.grid();
+
+        // 5 samples make 10 world units, 2 metres apart
+        let units_per_metre = 10.0 / 5.0 / 2.0;
+        assert_eq!(5, grid.grid.width());
+        for y in 0..5 {
+            for x in 0..5 {
+                assert_eq!(y < 3, grid.is_valid(x, y), "{}, {}", x, y);
+                if y < 3 {
+                    let expected = (y * 5 + x) as f32 * units_per_metre;
+                    assert_eq!(expected, grid.grid.get(x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hgt_must_be_square() {
+        assert!(Raster::parse_hgt(&[0; 10]).is_err());
+        assert!(Raster::parse_hgt(&[0; 9]).is_err());
+    }
+
+    #[test]
+    fn test_ascii_grid_value_count_is_checked() {
+        let text = "ncols 2\nnrows 2\ncellsize 1\n1 2 3\n";
+        assert!(Raster::parse_ascii_grid(text).is_err());
+    }
+
+    #[test]
+    fn test_ascii_grid_needs_two_samples_each_way() {
+        for (width, height) in [(0, 0), (0, 2), (1, 3), (3, 1)] {
+            let values = vec!["1"; width * height].join(" ");
+            let text = format!(
+                "ncols {}\nnrows {}\ncellsize 1\n{}\n",
+                width, height, values
+            );
+            assert!(Raster::parse_ascii_grid(&text).is_err(), "{}", text);
+        }
+        let text = "ncols 2\nnrows 2\ncellsize 1\n1 2 3 4\n";
+        assert!(Raster::parse_ascii_grid(text).is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+//! headerless height maps, as exported by World Machine, Gaea, Unity and friends. The file is
+//! nothing but samples, row by row, so everything else has to be guessed or configured.
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::height_map::HeightSource;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    /// unsigned integers, normalized to 0..1
+    Sixteen,
+    /// floats, used as they are
+    ThirtyTwo,
+}
+
+impl BitDepth {
+    fn bytes_per_sample(&self) -> usize {
+        match self {
+            BitDepth::Sixteen => 2,
+            BitDepth::ThirtyTwo => 4,
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "16" => Ok(BitDepth::Sixteen),
+            "32" => Ok(BitDepth::ThirtyTwo),
+            _ => bail!("unsupported bit depth {:?}, expected 16 or 32", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl FromStr for ByteOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "little" | "le" | "windows" => Ok(ByteOrder::Little),
+            "big" | "be" | "mac" => Ok(ByteOrder::Big),
+            _ => bail!("unknown byte order {:?}, expected little or big", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawLayout {
+    pub width: usize,
+    pub height: usize,
+    pub bit_depth: BitDepth,
+    pub byte_order: ByteOrder,
+}
+
+impl RawLayout {
+    /// most raw height maps are square, which is enough to get the size from the file length
+    pub fn infer_square(
+        length: usize,
+        bit_depth: BitDepth,
+        byte_order: ByteOrder,
+    ) -> anyhow::Result<Self> {
+        let samples = length / bit_depth.bytes_per_sample();
+        let size = (samples as f64).sqrt().round() as usize;
+        // a mesh needs at least one cell, so an empty (or single sample) file is no height map
+        if size < 2 || size * size * bit_depth.bytes_per_sample() != length {
+            bail!(
+                "{} bytes are not a square height map with {} byte samples, width and height \
+                 need to be configured",
+                length,
+                bit_depth.bytes_per_sample()
+            );
+        }
+
+        Ok(Self {
+            width: size,
+            height: size,
+            bit_depth,
+            byte_order,
+        })
+    }
+}
+
+pub struct RawHeightSource {
+    data: Vec<u8>,
+    layout: RawLayout,
+}
+
+impl RawHeightSource {
+    pub fn new(data: Vec<u8>, layout: RawLayout) -> anyhow::Result<Self> {
+        let expected = layout.width * layout.height * layout.bit_depth.bytes_per_sample();
+        if data.len() < expected {
+            bail!(
+                "raw height map has {} bytes, but {}x{} samples need {}",
+                data.len(),
+                layout.width,
+                layout.height,
+                expected
+            );
+        }
+
+        Ok(Self { data, layout })
+    }
+
+    pub fn layout(&self) -> &RawLayout {
+        &self.layout
+    }
+}
+
+impl HeightSource for RawHeightSource {
+    #[inline]
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        let layout = &self.layout;
+        let size = layout.bit_depth.bytes_per_sample();
+        let offset = (y * layout.width + x) * size;
+        let bytes = &self.data[offset..offset + size];
+
+        match (layout.bit_depth, layout.byte_order) {
+            (BitDepth::Sixteen, ByteOrder::Little) => {
+                u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32
+            }
+            (BitDepth::Sixteen, ByteOrder::Big) => {
+                u16::from_be_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32
+            }
+            (BitDepth::ThirtyTwo, ByteOrder::Little) => {
+                f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+            (BitDepth::ThirtyTwo, ByteOrder::Big) => {
+                f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_square_size_is_inferred() {
+        let layout =
+            RawLayout::infer_square(2 * 17 * 17, BitDepth::Sixteen, ByteOrder::Little).unwrap();
+        assert_eq!((17, 17), (layout.width, layout.height));
+
+        let layout =
+            RawLayout::infer_square(4 * 9 * 9, BitDepth::ThirtyTwo, ByteOrder::Big).unwrap();
+        assert_eq!((9, 9), (layout.width, layout.height));
+
+        assert!(RawLayout::infer_square(2 * 12, BitDepth::Sixteen, ByteOrder::Little).is_err());
+        assert!(RawLayout::infer_square(0, BitDepth::Sixteen, ByteOrder::Little).is_err());
+        assert!(RawLayout::infer_square(4, BitDepth::ThirtyTwo, ByteOrder::Little).is_err());
+    }
+
+    #[test]
+    fn test_samples_keep_full_precision() {
+        let layout = RawLayout {
+            width: 2,
+            height: 1,
+            bit_depth: BitDepth::Sixteen,
+            byte_order: ByteOrder::Big,
+        };
+        let source = RawHeightSource::new(vec![0x00, 0x01, 0xff, 0xff], layout).unwrap();
+        // a single step of a 16 bit map, which 8 bit images could never represent
+        assert_eq!(1.0 / 65535.0, source.sample_height(0, 0));
+        assert_eq!(1.0, source.sample_height(1, 0));
+
+        let mut data = 1234.5f32.to_le_bytes().to_vec();
+        data.extend_from_slice(&(-2.25f32).to_le_bytes());
+        let layout = RawLayout {
+            bit_depth: BitDepth::ThirtyTwo,
+            byte_order: ByteOrder::Little,
+            ..layout
+        };
+        let source = RawHeightSource::new(data, layout).unwrap();
+        assert_eq!(1234.5, source.sample_height(0, 0));
+        assert_eq!(-2.25, source.sample_height(1, 0));
+    }
+
+    #[test]
+    fn test_too_short_data_is_rejected() {
+        let layout = RawLayout {
+            width: 4,
+            height: 4,
+            bit_depth: BitDepth::Sixteen,
+            byte_order: ByteOrder::Little,
+        };
+        assert!(RawHeightSource::new(vec![0; 31], layout).is_err());
+    }
+}
@@ -8,6 +8,7 @@
 ///
 /// [0]: https://observablehq.com/@mourner/martin-real-time-rtin-terrain-mesh
 /// [1]: https://www.cs.ubc.ca/~will/papers/rtin.pdf
+use crate::height_map::grid::GridRect;
 use crate::height_map::{HeightMap, HeightSource};
 
 #[derive(Debug, Clone, Copy)]
@@ -33,88 +34,229 @@ impl UXY {
     }
 }
 
-struct ErrorMap {
+/// the biggest error of every triangle of the RTIN bintree (including all the triangles inside of
+/// it), stored at the middle of its hypotenuse. The two triangles sharing a hypotenuse share
+/// the entry as well.
+pub struct ErrorMap {
     data: Vec<f32>,
     grid_size: usize,
+    /// which samples hold data, see [`HeightSource::is_valid`]
+    valid: Vec<bool>,
 }
 
 impl ErrorMap {
-    pub(crate) fn get_error(&self, pos: &UXY) -> f32 {
+    fn get_error(&self, pos: &UXY) -> f32 {
         self.data[pos.as_offset(self.grid_size)]
     }
+
+    fn is_valid(&self, pos: &UXY) -> bool {
+        self.valid[pos.as_offset(self.grid_size)]
+    }
+
+    /// the error stored for sample `x`, `y`
+    pub fn error(&self, x: usize, y: usize) -> f32 {
+        self.get_error(&UXY::new(x, y))
+    }
 }
 
 impl ErrorMap {
-    fn from_height_map<T: HeightSource>(hm: &HeightMap<T>) -> ErrorMap {
+    pub fn from_height_map<T: HeightSource>(hm: &HeightMap<T>) -> ErrorMap {
         let grid_size = hm.source_size;
-        let tile_size = hm.source_size - 1;
-        let mut errors = vec![0.0; grid_size * grid_size];
+        let valid: Vec<bool> = (0..grid_size * grid_size)
+            .map(|offset| hm.is_valid(offset % grid_size, offset / grid_size))
+            .collect();
+        let mut error_map = Self {
+            data: vec![0.0; grid_size * grid_size],
+            grid_size,
+            valid,
+        };
 
-        let number_of_smallest_triangles = tile_size * tile_size;
-        let number_of_all_triangles = number_of_smallest_triangles * 2 - 2;
-        let last_level_index = number_of_all_triangles - number_of_smallest_triangles;
+        let tile_size = grid_size - 1;
+        let number_of_all_triangles = tile_size * tile_size * 2 - 2;
+        for idx in (0..number_of_all_triangles).rev() {
+            let (a, b, c) = error_map.triangle(idx);
+            error_map.update_triangle(hm, a, b, c);
+        }
+
+        error_map
+    }
 
-        for idx in (0..=number_of_all_triangles).rev() {
-            let mut id = idx + 2;
-            let mut a = UXY::new(0, 0);
-            let mut b = UXY::new(0, 0);
-            let mut c = UXY::new(0, 0);
+    /// the corners of triangle `idx`, with `c` at the right angle. Children come after their
+    /// parents, the smallest triangles are the last ones.
+    fn triangle(&self, idx: usize) -> (UXY, UXY, UXY) {
+        let tile_size = self.grid_size - 1;
+        let mut id = idx + 2;
+        let mut a = UXY::new(0, 0);
+        let mut b = UXY::new(0, 0);
+        let mut c = UXY::new(0, 0);
+
+        if id & 1 == 1 {
+            b.x = tile_size;
+            b.y = tile_size;
+            c.x = tile_size;
+        } else {
+            a.x = tile_size;
+            a.y = tile_size;
+            c.y = tile_size;
+        }
+
+        while id / 2 > 1 {
+            id /= 2;
+
+            let m = UXY::new((a.x + b.x) / 2, (a.y + b.y) / 2);
 
             if id & 1 == 1 {
-                b.x = tile_size;
-                b.y = tile_size;
-                c.x = tile_size;
+                b = a;
+                a = c;
             } else {
-                a.x = tile_size;
-                a.y = tile_size;
-                c.y = tile_size;
+                a = b;
+                b = c;
             }
 
-            while id / 2 > 1 {
-                id = id / 2;
+            c = m;
+        }
 
-                let m = UXY::new((a.x + b.x) / 2, (a.y + b.y) / 2);
+        (a, b, c)
+    }
 
-                if id & 1 == 1 {
-                    b = a;
-                    a = c;
-                } else {
-                    a = b;
-                    b = c;
-                }
+    /// the smallest triangles in the map cover a single cell, their hypotenuse is 2 samples long
+    fn is_smallest(a: &UXY, b: &UXY) -> bool {
+        (a.x as isize - b.x as isize).abs() + (a.y as isize - b.y as isize).abs() == 2
+    }
 
-                c = m;
+    /// folds the error of the triangle `a`, `b`, `c` into the one stored at the middle of its
+    /// hypotenuse. Its children have to be up to date already.
+    fn update_triangle<T: HeightSource>(&mut self, hm: &HeightMap<T>, a: UXY, b: UXY, c: UXY) {
+        let grid_size = self.grid_size;
+        let errors = &mut self.data;
+
+        let center = UXY::middle_of(&a, &b);
+
+        let interpolated_height = (hm.sample(a.x, a.y) + hm.sample(b.x, b.y)) / 2.0;
+        let center_height = hm.sample(center.x, center.y);
+        let center_error = (interpolated_height - center_height).abs();
+
+        let new_error = if Self::is_smallest(&a, &b) {
+            // triangles touching a void must never be merged into bigger ones, so the void
+            // cannot be covered. Both triangles of a cell share this error, hence the max.
+            let touches_void = [a, b, c]
+                .iter()
+                .any(|corner| !self.valid[corner.as_offset(grid_size)]);
+            if touches_void {
+                f32::INFINITY
+            } else {
+                f32::max(errors[center.as_offset(grid_size)], center_error)
             }
+        } else {
+            let left_child = UXY::middle_of(&a, &c);
+            let left_child_error = errors[left_child.as_offset(grid_size)];
+            let right_child = UXY::middle_of(&b, &c);
+            let right_child_error = errors[right_child.as_offset(grid_size)];
+            f32::max(
+                f32::max(errors[center.as_offset(grid_size)], center_error),
+                f32::max(left_child_error, right_child_error),
+            )
+        };
 
-            let center = UXY::middle_of(&a, &b);
+        errors[center.as_offset(grid_size)] = new_error;
+    }
 
-            let interpolated_height = (hm.sample(a.x, a.y) + hm.sample(b.x, b.y)) / 2.0;
-            let center_height = hm.sample(center.x, center.y);
-            let center_error = (interpolated_height - center_height).abs();
+    /// brings the errors up to date after the samples in `region` of `hm` changed, without
+    /// going over the whole map again.
+    ///
+    /// The triangle on the other side of the hypotenuse shares the stored error, and the
+    /// parents of both of them read it. So the error of a triangle does not only depend on the
+    /// samples inside of it, but also on the ones a bit outside, up to the length of its
+    /// hypotenuse (each level down reaches out half as far, roughly). Triangles that reach the
+    /// region are found by walking down the bintree from the roots, skipping every one (and so
+    /// all the ones inside of it) that stays clear of it, and are then updated from the
+    /// smallest up to the roots, together with their neighbours across the hypotenuse.
+    pub fn update_region<T: HeightSource>(&mut self, hm: &HeightMap<T>, region: &GridRect) {
+        debug_assert_eq!(self.grid_size, hm.source_size);
+        let tile_size = self.grid_size - 1;
+        for y in region.y.clone() {
+            for x in region.x.clone() {
+                self.valid[y * self.grid_size + x] = hm.is_valid(x, y);
+            }
+        }
 
-            let new_error = if idx >= last_level_index {
-                center_error
-            } else {
-                let left_child = UXY::middle_of(&a, &c);
-                let left_child_error = errors[left_child.as_offset(grid_size)];
-                let right_child = UXY::middle_of(&b, &c);
-                let right_child_error = errors[right_child.as_offset(grid_size)];
-                f32::max(
-                    f32::max(errors[center.as_offset(grid_size)], center_error),
-                    f32::max(left_child_error, right_child_error),
-                )
+        let reaches = |a: &UXY, b: &UXY, c: &UXY| {
+            let bounds = GridRect {
+                x: a.x.min(b.x).min(c.x)..=a.x.max(b.x).max(c.x),
+                y: a.y.min(b.y).min(c.y)..=a.y.max(b.y).max(c.y),
             };
-
-            errors[center.as_offset(grid_size)] = new_error;
+            let hypotenuse =
+                (a.x as isize - b.x as isize).abs() + (a.y as isize - b.y as isize).abs();
+            bounds
+                .grow(hypotenuse as usize, self.grid_size, self.grid_size)
+                .overlaps(region)
+        };
+        let mut affected = vec![];
+        let mut pending = vec![
+            (
+                UXY::new(0, 0),
+                UXY::new(tile_size, tile_size),
+                UXY::new(tile_size, 0),
+            ),
+            (
+                UXY::new(tile_size, tile_size),
+                UXY::new(0, 0),
+                UXY::new(0, tile_size),
+            ),
+        ];
+        while let Some((a, b, c)) = pending.pop() {
+            if !reaches(&a, &b, &c) {
+                continue;
+            }
+            affected.push((a, b, c));
+            if !Self::is_smallest(&a, &b) {
+                let m = UXY::middle_of(&a, &b);
+                pending.push((c, a, m));
+                pending.push((b, c, m));
+            }
         }
 
-        Self {
-            data: errors,
-            grid_size,
+        let neighbours: Vec<(UXY, UXY, UXY)> = affected
+            .iter()
+            .filter_map(|(a, b, c)| {
+                // c mirrored at the hypotenuse
+                let x = (a.x + b.x).checked_sub(c.x).filter(|x| *x <= tile_size)?;
+                let y = (a.y + b.y).checked_sub(c.y).filter(|y| *y <= tile_size)?;
+                let neighbour = (*b, *a, UXY::new(x, y));
+                // the ones reaching the region are updated anyway
+                if reaches(&neighbour.0, &neighbour.1, &neighbour.2) {
+                    None
+                } else {
+                    Some(neighbour)
+                }
+            })
+            .collect();
+        affected.extend(neighbours);
+
+        for (a, b, _) in &affected {
+            self.data[UXY::middle_of(a, b).as_offset(self.grid_size)] = 0.0;
+        }
+        // the shorter the hypotenuse, the further down the bintree
+        affected.sort_by_key(|(a, b, _)| {
+            let (dx, dy) = (a.x as isize - b.x as isize, a.y as isize - b.y as isize);
+            dx * dx + dy * dy
+        });
+        for (a, b, c) in affected {
+            self.update_triangle(hm, a, b, c);
         }
     }
 }
 
+/// the bintree halves the grid all the way down to single cells, so it only works for squares of
+/// 2^k + 1 samples. This is the smallest of them that is at least `size` samples wide.
+pub fn supported_grid_size(size: usize) -> usize {
+    let mut supported = 2;
+    while supported < size {
+        supported = supported * 2 - 1;
+    }
+    supported
+}
+
 pub struct RtinMeshBuilder<T: HeightSource> {
     height_map: HeightMap<T>,
     error_map: ErrorMap,
@@ -122,6 +264,11 @@ pub struct RtinMeshBuilder<T: HeightSource> {
 
 impl<T: HeightSource> RtinMeshBuilder<T> {
     pub fn from_height_map(height_map: HeightMap<T>) -> Self {
+        debug_assert_eq!(
+            supported_grid_size(height_map.source_size),
+            height_map.source_size,
+            "RTIN needs 2^k + 1 samples, resample the grid first"
+        );
         let error_map = ErrorMap::from_height_map(&height_map);
         Self {
             height_map,
@@ -129,6 +276,23 @@ impl<T: HeightSource> RtinMeshBuilder<T> {
         }
     }
 
+    pub fn height_map(&self) -> &HeightMap<T> {
+        &self.height_map
+    }
+
+    pub fn error_map(&self) -> &ErrorMap {
+        &self.error_map
+    }
+
+    /// lets `edit` change the height source, and updates the error map for the samples it
+    /// says it changed. Anything that only changes part of the terrain (brushes, erosion of a
+    /// region, a patch of streamed data) is a lot cheaper this way than building it again.
+    pub fn edit(&mut self, edit: impl FnOnce(&mut T) -> Option<GridRect>) -> Option<GridRect> {
+        let region = edit(&mut self.height_map.height_source)?;
+        self.error_map.update_region(&self.height_map, &region);
+        Some(region)
+    }
+
     pub fn get_indices(&self, max_error: f32) -> Vec<u32> {
         let builder = IndexBuilder::create(&self.error_map, self.height_map.source_size, max_error);
 
@@ -181,7 +345,10 @@ impl<'e> IndexBuilder<'e> {
         {
             self.process_triangle(c, a, m);
             self.process_triangle(b, c, m);
-        } else {
+        } else if self.error_map.is_valid(&a)
+            && self.error_map.is_valid(&b)
+            && self.error_map.is_valid(&c)
+        {
             self.push_index(a);
             self.push_index(b);
             self.push_index(c);
@@ -198,23 +365,15 @@ impl<'e> IndexBuilder<'e> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::height_map::grid::HeightGrid;
+    use crate::height_map::NodataHeightSource;
     use bevy::winit::winit_runner;
-
-    struct TestHeightSource<const W: usize> {
-        data: Vec<f32>,
-    }
-
-    impl<const W: usize> HeightSource for TestHeightSource<W> {
-        fn sample_height(&self, x: usize, y: usize) -> f32 {
-            self.data[y * W + x]
-        }
-    }
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     fn test_error_map_is_build_correctly_small_dataset() {
-        let source: TestHeightSource<3> = TestHeightSource {
-            data: vec![0.0, 1.0, 1.0, 2.0, 0.0, 3.0, 0.0, 0.0, 0.0],
-        };
+        let source = HeightGrid::new(3, 3, vec![0.0, 1.0, 1.0, 2.0, 0.0, 3.0, 0.0, 0.0, 0.0]);
         let hm = HeightMap::create(source, 3, 1.0);
 
         let expected_result = vec![0.0, 0.5, 0.0, 2.0, 2.5, 2.5, 0.0, 0.0, 0.0];
@@ -226,9 +385,7 @@ mod test {
 
     #[test]
     fn test_indices_are_build_correctly_small_dataset() {
-        let source: TestHeightSource<3> = TestHeightSource {
-            data: vec![0.0, 1.0, 1.0, 2.0, 0.0, 3.0, 0.0, 0.0, 0.0],
-        };
+        let source = HeightGrid::new(3, 3, vec![0.0, 1.0, 1.0, 2.0, 0.0, 3.0, 0.0, 0.0, 0.0]);
         let hm = HeightMap::create(source, 3, 1.0);
 
         let expected_result = vec![
@@ -240,4 +397,111 @@ mod test {
         let indices = rtin.get_indices(0.0);
         assert_eq!(expected_result, indices);
     }
+
+    #[test]
+    fn test_error_map_only_covers_the_triangles_of_the_bintree() {
+        // a peak in the middle, the borders are flat
+        let source = HeightGrid::new(3, 3, vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+        let hm = HeightMap::create(source, 3, 1.0);
+        let rtin = RtinMeshBuilder::from_height_map(hm);
+
+        // one past the last triangle is a cell of the (non existent) next level, its error must
+        // not end up on the border
+        assert_eq!(0.0, rtin.error_map().error(0, 1));
+        assert_eq!(1.0, rtin.error_map().error(1, 1));
+        // the peak needs the four triangles around it, the flat borders do not
+        assert_eq!(4 * 3, rtin.get_indices(0.1).len());
+    }
+
+    #[test]
+    fn test_triangles_touching_voids_are_left_out() {
+        struct HoleHeightSource;
+
+        impl HeightSource for HoleHeightSource {
+            fn sample_height(&self, _: usize, _: usize) -> f32 {
+                0.0
+            }
+
+            fn is_valid(&self, x: usize, y: usize) -> bool {
+                (x, y) != (4, 4)
+            }
+        }
+
+        let hm = HeightMap::create(HoleHeightSource, 9, 1.0);
+        let indices = RtinMeshBuilder::from_height_map(hm).get_indices(0.1);
+
+        // flat terrain would be two triangles, the hole forces a subdivision down to single
+        // cells around it, and the cells touching it are missing
+        assert!(indices.len() / 3 > 2);
+        assert!(!indices.contains(&(4 * 9 + 4)));
+        // every other sample is still covered
+        let area: f32 = indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let [ax, ay, bx, by, cx, cy] = [
+                    triangle[0] % 9,
+                    triangle[0] / 9,
+                    triangle[1] % 9,
+                    triangle[1] / 9,
+                    triangle[2] % 9,
+                    triangle[2] / 9,
+                ]
+                .map(|v| v as f32);
+                ((bx - ax) * (cy - ay) - (cx - ax) * (by - ay)).abs() / 2.0
+            })
+            .sum();
+        // the hole takes away the 8 triangles around the center sample (4 cells)
+        assert_eq!(64.0 - 4.0, area);
+    }
+
+    #[test]
+    fn test_incremental_error_map_matches_a_full_rebuild() {
+        let mut rng = StdRng::seed_from_u64(50);
+        for size in [3, 17, 33] {
+            let samples = (0..size * size).map(|_| rng.gen_range(0.0..1.0)).collect();
+            let grid = HeightGrid::new(size, size, samples);
+            let mut rtin = RtinMeshBuilder::from_height_map(HeightMap::create(grid, size, 1.0));
+
+            for _ in 0..50 {
+                let (x, y) = (rng.gen_range(0..size), rng.gen_range(0..size));
+                let (width, height) = (rng.gen_range(1..=size / 2 + 1), rng.gen_range(1..=4));
+                let region = GridRect {
+                    x: x..=(x + width - 1).min(size - 1),
+                    y: y..=(y + height - 1).min(size - 1),
+                };
+                let change = rng.gen_range(-0.5..0.5);
+                rtin.edit(|grid| {
+                    for y in region.y.clone() {
+                        for x in region.x.clone() {
+                            grid.set(x, y, grid.get(x, y) + change);
+                        }
+                    }
+                    Some(region.clone())
+                });
+
+                let rebuilt = ErrorMap::from_height_map(rtin.height_map());
+                assert_eq!(rebuilt.data, rtin.error_map().data, "{:?}", region);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_update_of_voids() {
+        let mut grid = HeightGrid::from_fn(9, 9, |x, y| (x * y) as f32 * 0.01);
+        let hm = HeightMap::create(
+            NodataHeightSource::new(grid.clone(), Some(-1.0), 9, 9),
+            9,
+            1.0,
+        );
+        let mut error_map = ErrorMap::from_height_map(&hm);
+
+        // a streamed patch that turns out to be a void
+        grid.set(6, 2, -1.0);
+        let hm = HeightMap::create(NodataHeightSource::new(grid, Some(-1.0), 9, 9), 9, 1.0);
+        let region = GridRect { x: 6..=6, y: 2..=2 };
+        error_map.update_region(&hm, &region);
+
+        assert_eq!(ErrorMap::from_height_map(&hm).data, error_map.data);
+        assert_eq!(f32::INFINITY, error_map.error(7, 2));
+    }
 }
@@ -0,0 +1,144 @@
+//! heights and normals anywhere on the terrain, not just at the samples. This is what gameplay
+//! code wants: "how high is the ground under this unit, and which way does it face".
+use bevy::math::Vec3;
+
+use crate::height_map::grid::Resampling;
+use crate::height_map::{HeightMap, HeightSource};
+
+/// distance (in samples) used for the finite differences of the normal
+const NORMAL_EPSILON: f32 = 0.05;
+
+/// the terrain surface at some position, in world units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainSample {
+    pub height: f32,
+    pub normal: Vec3,
+}
+
+impl<H: HeightSource> HeightMap<H> {
+    /// the (scaled) height at a position between samples. Positions outside the map get the
+    /// height of the closest border sample.
+    pub fn sample_interpolated(&self, x: f32, y: f32, resampling: Resampling) -> f32 {
+        let last = self.source_size as isize - 1;
+        resampling.filter(x, y, 1.0, 1.0, |sx, sy| {
+            self.sample(sx.clamp(0, last) as usize, sy.clamp(0, last) as usize)
+        })
+    }
+
+    /// whether the four samples around a position are all valid, so its interpolated height
+    /// does not depend on a void
+    pub fn valid_around(&self, x: f32, y: f32) -> bool {
+        let last = self.source_size as isize - 1;
+        let (x0, y0) = (x.floor() as isize, y.floor() as isize);
+        [(x0, y0), (x0 + 1, y0), (x0, y0 + 1), (x0 + 1, y0 + 1)]
+            .iter()
+            .all(|(sx, sy)| {
+                self.is_valid((*sx).clamp(0, last) as usize, (*sy).clamp(0, last) as usize)
+            })
+    }
+
+    /// how many world units two neighbouring samples are apart, same as in
+    /// [`create_geometry`](crate::height_map::create_geometry)
+    fn world_units_per_sample(&self) -> f32 {
+        self.target_size / self.source_size as f32
+    }
+
+    /// maps a world position to grid coordinates. The mesh is centered around the origin, with
+    /// sample `x`, `y` at world `x`, `z`.
+    pub fn world_to_grid(&self, world_x: f32, world_z: f32) -> (f32, f32) {
+        let half = self.source_size as f32 / 2.0;
+        let scale = self.world_units_per_sample();
+        (world_x / scale + half, world_z / scale + half)
+    }
+
+    /// the reverse of [`HeightMap::world_to_grid`]
+    pub fn grid_to_world(&self, x: f32, y: f32) -> (f32, f32) {
+        let half = self.source_size as f32 / 2.0;
+        let scale = self.world_units_per_sample();
+        ((x - half) * scale, (y - half) * scale)
+    }
+
+    /// the normal of the interpolated surface at a position in grid coordinates
+    pub fn normal_interpolated(&self, x: f32, y: f32, resampling: Resampling) -> Vec3 {
+        let e = NORMAL_EPSILON;
+        let height = |x, y| self.sample_interpolated(x, y, resampling);
+        let world_distance = 2.0 * e * self.world_units_per_sample();
+        let slope_x = (height(x + e, y) - height(x - e, y)) / world_distance;
+        let slope_z = (height(x, y + e) - height(x, y - e)) / world_distance;
+
+        Vec3::new(-slope_x, 1.0, -slope_z).normalize()
+    }
+
+    /// height and normal of the terrain at a world position, or `None` if the position is not
+    /// above the terrain
+    pub fn sample_world(
+        &self,
+        world_x: f32,
+        world_z: f32,
+        resampling: Resampling,
+    ) -> Option<TerrainSample> {
+        let (x, y) = self.world_to_grid(world_x, world_z);
+        let last = (self.source_size - 1) as f32;
+        if !(0.0..=last).contains(&x) || !(0.0..=last).contains(&y) {
+            return None;
+        }
+
+        Some(TerrainSample {
+            height: self.sample_interpolated(x, y, resampling),
+            normal: self.normal_interpolated(x, y, resampling),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::grid::HeightGrid;
+
+    fn plane(x: f32, y: f32) -> f32 {
+        x * 0.25 - y * 0.5 + 1.0
+    }
+
+    fn plane_map() -> HeightMap<HeightGrid> {
+        let grid = HeightGrid::from_fn(9, 9, |x, y| plane(x as f32, y as f32));
+        HeightMap::create(grid, 9, 9.0).with_height_scale(2.0)
+    }
+
+    #[test]
+    fn test_interpolation_between_samples() {
+        let hm = plane_map();
+        for resampling in [Resampling::Bilinear, Resampling::Bicubic] {
+            for (x, y) in [(3.27, 5.91), (2.0, 2.0), (4.5, 4.5), (6.1, 2.9)] {
+                let height = hm.sample_interpolated(x, y, resampling);
+                assert!(
+                    (height - plane(x, y) * 2.0).abs() < 1e-4,
+                    "{:?} at {}, {}: {}",
+                    resampling,
+                    x,
+                    y,
+                    height
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_world_sample_of_a_plane() {
+        let hm = plane_map();
+        // one world unit per sample, sample 4.5, 4.5 is the center
+        assert_eq!((4.5, 4.5), hm.world_to_grid(0.0, 0.0));
+        assert_eq!((-1.5, 2.0), hm.grid_to_world(3.0, 6.5));
+
+        let sample = hm.sample_world(-1.0, 0.5, Resampling::Bilinear).unwrap();
+        assert!((sample.height - plane(3.5, 5.0) * 2.0).abs() < 1e-4);
+        // the height grows by 0.5 per unit towards +x and falls by 1 per unit towards +z
+        let expected = Vec3::new(-0.5, 1.0, 1.0).normalize();
+        assert!(
+            sample.normal.abs_diff_eq(expected, 1e-3),
+            "{:?}",
+            sample.normal
+        );
+
+        assert_eq!(None, hm.sample_world(10.0, 0.0, Resampling::Bilinear));
+    }
+}
@@ -0,0 +1,456 @@
+//! changing the terrain after it was built: brushes that raise, lower, smooth, flatten, roughen
+//! or ramp the samples around a point, and a [`SculptedTerrain`] that keeps its mesh up to date
+//! with them without building it from scratch.
+use bevy::math::Vec2;
+use bevy::prelude::Mesh;
+use bevy::render::mesh::{Indices, VertexAttributeValues};
+
+use crate::height_map::grid::{GridRect, HeightGrid, MaskedGrid, Resampling};
+use crate::height_map::rtin::RtinMeshBuilder;
+use crate::height_map::{
+    build_normal, HeightMap, HeightSource, Triangulation, MAX_ERROR, TERRAIN_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrushKind {
+    Raise,
+    Lower,
+    /// towards the average of the neighbours
+    Smooth,
+    /// towards the height where the stroke started
+    Flatten,
+    /// random bumps, the same ones for every dab with the same seed
+    Noise,
+    /// towards a straight slope from where the stroke started to the brush
+    Ramp,
+}
+
+/// how the effect of a brush fades from its center to its edge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Falloff {
+    Constant,
+    Linear,
+    /// smoothstep, no visible edge at the center or the border
+    Smooth,
+}
+
+impl Falloff {
+    /// `distance` from the center, in multiples of the radius
+    pub fn weight(&self, distance: f32) -> f32 {
+        if distance >= 1.0 {
+            return 0.0;
+        }
+        let t = 1.0 - distance;
+        match self {
+            Falloff::Constant => 1.0,
+            Falloff::Linear => t,
+            Falloff::Smooth => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Brush {
+    pub kind: BrushKind,
+    /// in samples
+    pub radius: f32,
+    /// what a single dab does at the center of the brush: the height added or removed for
+    /// raise, lower and noise, the share of the way to the target (0 to 1) for the others
+    pub strength: f32,
+    pub falloff: Falloff,
+    pub seed: u64,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            kind: BrushKind::Raise,
+            radius: 8.0,
+            strength: 0.01,
+            falloff: Falloff::Smooth,
+            seed: 0,
+        }
+    }
+}
+
+/// what a brush remembers between the dabs of one stroke (from pressing the button to letting
+/// go of it)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stroke {
+    /// in grid coordinates
+    pub start: Vec2,
+    pub start_height: f32,
+}
+
+impl Stroke {
+    pub fn begin(grid: &HeightGrid, start: Vec2) -> Self {
+        Self {
+            start,
+            start_height: grid.sample_interpolated(start.x, start.y, Resampling::Bilinear),
+        }
+    }
+}
+
+impl Brush {
+    /// one dab of the brush at `center` (grid coordinates), returns the samples it touched. Voids
+    /// are left as they are, and smoothing does not look at them either.
+    pub fn apply(
+        &self,
+        masked: &mut MaskedGrid,
+        stroke: &Stroke,
+        center: Vec2,
+    ) -> Option<GridRect> {
+        let grid = &masked.grid;
+        let (width, height) = (grid.width(), grid.height());
+        // the ramp reaches all the way back to the start of the stroke
+        let from = match self.kind {
+            BrushKind::Ramp => stroke.start,
+            _ => center,
+        };
+        let min = from.min(center) - Vec2::splat(self.radius);
+        let max = from.max(center) + Vec2::splat(self.radius);
+        if max.x < 0.0 || max.y < 0.0 || min.x > (width - 1) as f32 || min.y > (height - 1) as f32 {
+            return None;
+        }
+        let region = GridRect {
+            x: min.x.max(0.0).ceil() as usize..=(max.x.floor() as usize).min(width - 1),
+            y: min.y.max(0.0).ceil() as usize..=(max.y.floor() as usize).min(height - 1),
+        };
+        let end_height = grid.sample_interpolated(center.x, center.y, Resampling::Bilinear);
+
+        // collected first, so smoothing sees the heights from before this dab
+        let mut changes = vec![];
+        for y in region.y.clone() {
+            for x in region.x.clone() {
+                let position = Vec2::new(x as f32, y as f32);
+                let (distance, along) = closest_on_segment(from, center, position);
+                let weight = self.falloff.weight(distance / self.radius);
+                if weight <= 0.0 || !masked.is_valid(x, y) {
+                    continue;
+                }
+                let current = grid.get(x, y);
+                let towards =
+                    |target: f32| current + (target - current) * (self.strength * weight).min(1.0);
+                let new = match self.kind {
+                    BrushKind::Raise => current + self.strength * weight,
+                    BrushKind::Lower => current - self.strength * weight,
+                    BrushKind::Noise => current + self.strength * weight * noise(x, y, self.seed),
+                    BrushKind::Smooth => towards(neighbour_mean(masked, x, y)),
+                    BrushKind::Flatten => towards(stroke.start_height),
+                    BrushKind::Ramp => {
+                        towards(stroke.start_height + (end_height - stroke.start_height) * along)
+                    }
+                };
+                changes.push((x, y, new));
+            }
+        }
+        if changes.is_empty() {
+            return None;
+        }
+        for (x, y, new) in changes {
+            masked.grid.set(x, y, new);
+        }
+
+        Some(region)
+    }
+}
+
+/// distance of `position` to the segment from `a` to `b`, and how far along the segment (0 to 1)
+/// the closest point on it is
+fn closest_on_segment(a: Vec2, b: Vec2, position: Vec2) -> (f32, f32) {
+    let direction = b - a;
+    let length_squared = direction.length_squared();
+    let along = if length_squared > 0.0 {
+        ((position - a).dot(direction) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (position.distance(a + direction * along), along)
+}
+
+/// of the valid samples around `x`, `y` (which has to be valid itself)
+fn neighbour_mean(masked: &MaskedGrid, x: usize, y: usize) -> f32 {
+    let grid = &masked.grid;
+    let (mut sum, mut count) = (0.0, 0.0);
+    for ny in y.saturating_sub(1)..=(y + 1).min(grid.height() - 1) {
+        for nx in x.saturating_sub(1)..=(x + 1).min(grid.width() - 1) {
+            if masked.is_valid(nx, ny) {
+                sum += grid.get(nx, ny);
+                count += 1.0;
+            }
+        }
+    }
+    sum / count
+}
+
+/// a random value from -1 to 1 for every sample, hashed from its position (splitmix64)
+fn noise(x: usize, y: usize, seed: u64) -> f32 {
+    let mut hash = seed
+        ^ (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+}
+
+/// a terrain mesh that can be sculpted. Every dab only touches the vertices under the brush and
+/// the part of the RTIN error map above them, instead of building the whole mesh again.
+pub struct SculptedTerrain {
+    rtin: RtinMeshBuilder<MaskedGrid>,
+    triangulation: Triangulation,
+}
+
+impl SculptedTerrain {
+    /// `grid` is square, 2^k + 1 samples wide and in world units, like the
+    /// [`Terrain::grid`](super::terrain::Terrain::grid) the mesh was made from, with the same
+    /// `triangulation`
+    pub fn new(grid: MaskedGrid, triangulation: Triangulation) -> Self {
+        let size = grid.grid.width();
+        Self {
+            rtin: RtinMeshBuilder::from_height_map(HeightMap::create(grid, size, TERRAIN_SIZE)),
+            triangulation,
+        }
+    }
+
+    pub fn height_map(&self) -> &HeightMap<MaskedGrid> {
+        self.rtin.height_map()
+    }
+
+    pub fn grid(&self) -> &MaskedGrid {
+        &self.rtin.height_map().height_source
+    }
+
+    /// one dab of `brush` at `center` (grid coordinates). `mesh` has to be the mesh of this
+    /// terrain, its positions, normals and indices are updated to match.
+    pub fn apply(
+        &mut self,
+        brush: &Brush,
+        stroke: &Stroke,
+        center: Vec2,
+        mesh: &mut Mesh,
+    ) -> Option<GridRect> {
+        let region = self.rtin.edit(|grid| brush.apply(grid, stroke, center))?;
+        let hm = self.rtin.height_map();
+        let size = hm.source_size;
+
+        if let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        {
+            for y in region.y.clone() {
+                for x in region.x.clone() {
+                    positions[y * size + x][1] = hm.sample(x, y);
+                }
+            }
+        }
+        // normals depend on the neighbours, so the ones around the edit change too
+        let around = region.grow(1, size, size);
+        if let Some(VertexAttributeValues::Float32x3(normals)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_NORMAL)
+        {
+            for y in around.y.clone() {
+                for x in around.x.clone() {
+                    normals[y * size + x] = build_normal(x, y, hm);
+                }
+            }
+        }
+        // the full grid only depends on the voids, which brushes leave alone
+        if self.triangulation == Triangulation::Rtin {
+            mesh.set_indices(Some(Indices::U32(self.rtin.get_indices(MAX_ERROR))));
+        }
+
+        Some(region)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::rtin::ErrorMap;
+    use crate::height_map::terrain::Terrain;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn hills() -> MaskedGrid {
+        MaskedGrid::from(HeightGrid::from_fn(17, 17, |x, y| {
+            ((x as f32 * 0.7).sin() + (y as f32 * 0.4).cos()) * 0.1
+        }))
+    }
+
+    /// the hills, with a void at `x`, `y` that holds a height way off the others
+    fn holey_hills(x: usize, y: usize) -> MaskedGrid {
+        let mut masked = hills();
+        masked.grid.set(x, y, -100.0);
+        masked.mask = Some((0..17 * 17).map(|offset| offset != y * 17 + x).collect());
+        masked
+    }
+
+    #[test]
+    fn test_raise_with_falloff() {
+        let mut grid = MaskedGrid::from(HeightGrid::filled(17, 17, 0.0));
+        let brush = Brush {
+            radius: 4.0,
+            strength: 1.0,
+            falloff: Falloff::Linear,
+            ..Default::default()
+        };
+        let center = Vec2::new(2.0, 8.0);
+        let stroke = Stroke::begin(&grid.grid, center);
+        let region = brush.apply(&mut grid, &stroke, center).unwrap();
+
+        assert_eq!(
+            GridRect {
+                x: 0..=6,
+                y: 4..=12
+            },
+            region
+        );
+        assert_eq!(1.0, grid.grid.get(2, 8));
+        assert_eq!(0.5, grid.grid.get(4, 8));
+        assert_eq!(0.0, grid.grid.get(6, 8));
+        assert_eq!(0.0, grid.grid.get(9, 8));
+
+        let lower = Brush {
+            kind: BrushKind::Lower,
+            ..brush
+        };
+        lower.apply(&mut grid, &stroke, center);
+        assert!(grid.grid.data().iter().all(|height| height.abs() < 1e-6));
+        assert_eq!(None, brush.apply(&mut grid, &stroke, Vec2::new(-5.0, 8.0)));
+    }
+
+    #[test]
+    fn test_levelling_brushes() {
+        let flatten = Brush {
+            kind: BrushKind::Flatten,
+            radius: 20.0,
+            strength: 1.0,
+            falloff: Falloff::Constant,
+            ..Default::default()
+        };
+        let mut grid = hills();
+        let stroke = Stroke::begin(&grid.grid, Vec2::new(8.0, 8.0));
+        flatten.apply(&mut grid, &stroke, Vec2::new(8.0, 8.0));
+        assert!(grid
+            .grid
+            .data()
+            .iter()
+            .all(|height| (height - stroke.start_height).abs() < 1e-6));
+
+        let mut grid = hills();
+        let before = grid.grid.statistics().std_dev;
+        let smooth = Brush {
+            kind: BrushKind::Smooth,
+            ..flatten
+        };
+        smooth.apply(&mut grid, &stroke, Vec2::new(8.0, 8.0));
+        assert!(grid.grid.statistics().std_dev < before);
+
+        // a ramp from 0 at x = 2 to 1 at x = 14, along the middle row
+        let mut grid =
+            MaskedGrid::from(HeightGrid::from_fn(
+                17,
+                17,
+                |x, _| {
+                    if x > 12 {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                },
+            ));
+        let ramp = Brush {
+            kind: BrushKind::Ramp,
+            radius: 2.0,
+            ..flatten
+        };
+        let stroke = Stroke::begin(&grid.grid, Vec2::new(2.0, 8.0));
+        ramp.apply(&mut grid, &stroke, Vec2::new(14.0, 8.0));
+        for x in 2..=14 {
+            let expected = (x - 2) as f32 / 12.0;
+            assert!((grid.grid.get(x, 8) - expected).abs() < 1e-6, "{}", x);
+        }
+        assert_eq!(0.0, grid.grid.get(8, 2));
+    }
+
+    #[test]
+    fn test_brushes_leave_voids_alone() {
+        let smooth = Brush {
+            kind: BrushKind::Smooth,
+            radius: 4.0,
+            strength: 1.0,
+            falloff: Falloff::Constant,
+            ..Default::default()
+        };
+        let mut grid = holey_hills(8, 8);
+        let stroke = Stroke::begin(&grid.grid, Vec2::new(7.0, 7.0));
+        smooth.apply(&mut grid, &stroke, Vec2::new(7.0, 7.0));
+
+        assert_eq!(-100.0, grid.grid.get(8, 8));
+        // its neighbours are not dragged down by it
+        let (min, _) = grid.grid.min_max();
+        assert_eq!(-100.0, min);
+        for (x, y) in [(7, 7), (8, 7), (9, 9)] {
+            assert!(grid.grid.get(x, y) > -0.3, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_sculpted_mesh_matches_a_new_one() {
+        let mut rng = StdRng::seed_from_u64(49);
+        let kinds = [
+            BrushKind::Raise,
+            BrushKind::Lower,
+            BrushKind::Smooth,
+            BrushKind::Flatten,
+            BrushKind::Noise,
+            BrushKind::Ramp,
+        ];
+        let mut terrain = SculptedTerrain::new(holey_hills(5, 11), Triangulation::Rtin);
+        let mut mesh = Terrain::new(holey_hills(5, 11)).mesh();
+        let indices = |mesh: &Mesh| match mesh.indices() {
+            Some(Indices::U32(indices)) => indices.clone(),
+            _ => panic!("the terrain has 32 bit indices"),
+        };
+
+        for _ in 0..100 {
+            let brush = Brush {
+                kind: kinds[rng.gen_range(0..kinds.len())],
+                radius: rng.gen_range(1.0..5.0),
+                strength: rng.gen_range(0.0..0.5),
+                seed: rng.gen(),
+                ..Default::default()
+            };
+            let start = Vec2::new(rng.gen_range(-2.0..18.0), rng.gen_range(-2.0..18.0));
+            let center = start + Vec2::new(rng.gen_range(-4.0..4.0), rng.gen_range(-4.0..4.0));
+            let stroke = Stroke::begin(
+                &terrain.grid().grid,
+                start.clamp(Vec2::ZERO, Vec2::splat(16.0)),
+            );
+            terrain.apply(&brush, &stroke, center, &mut mesh);
+
+            let rebuilt = ErrorMap::from_height_map(terrain.height_map());
+            for y in 0..17 {
+                for x in 0..17 {
+                    assert_eq!(
+                        rebuilt.error(x, y),
+                        terrain.rtin.error_map().error(x, y),
+                        "{:?} at {} {}",
+                        brush,
+                        x,
+                        y
+                    );
+                }
+            }
+            let expected = Terrain::new(terrain.grid().clone()).mesh();
+            for attribute in [Mesh::ATTRIBUTE_POSITION, Mesh::ATTRIBUTE_NORMAL] {
+                assert_eq!(
+                    expected.attribute(attribute.clone()).unwrap().get_bytes(),
+                    mesh.attribute(attribute).unwrap().get_bytes()
+                );
+            }
+            assert_eq!(indices(&expected), indices(&mesh));
+        }
+        assert_eq!(-100.0, terrain.grid().grid.get(5, 11));
+    }
+}
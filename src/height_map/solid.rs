@@ -0,0 +1,182 @@
+//! turns the terrain surface into a closed, printable solid.
+//!
+//! The RTIN surface is crack free, so the only open edges are the ones on the outline of the
+//! grid. We walk that outline, skipping every sample the triangulation dropped, and hang a wall
+//! from each of the remaining edges down to a flat base. The base itself is a fan around its
+//! center, so no triangle on the bottom degenerates when many outline samples are collinear.
+//!
+//! Holes from voids in the height map are not closed, fill the voids before building a solid.
+use crate::height_map::TerrainGeometry;
+
+pub struct SolidBuilder {
+    geometry: TerrainGeometry,
+    base_thickness: f32,
+}
+
+impl SolidBuilder {
+    pub fn new(geometry: TerrainGeometry) -> Self {
+        Self {
+            geometry,
+            base_thickness: 0.5,
+        }
+    }
+
+    /// distance between the lowest point of the surface and the bottom of the solid
+    pub fn base_thickness(mut self, base_thickness: f32) -> Self {
+        debug_assert!(base_thickness > 0.0);
+        self.base_thickness = base_thickness;
+        self
+    }
+
+    pub fn build(self) -> TerrainGeometry {
+        let mut geometry = self.geometry;
+        let outline = outline(&geometry);
+        if outline.len() < 3 {
+            return geometry;
+        }
+
+        let lowest = surface_min_height(&geometry);
+        let base_height = lowest - self.base_thickness;
+
+        // one vertex below every outline vertex
+        let first_bottom = geometry.positions.len() as u32;
+        for &top in &outline {
+            let [x, _, z] = geometry.positions[top as usize];
+            let uv = geometry.uvs[top as usize];
+            geometry.positions.push([x, base_height, z]);
+            geometry.normals.push([0.0, -1.0, 0.0]);
+            geometry.uvs.push(uv);
+        }
+
+        let (sum_x, sum_z) = outline.iter().fold((0.0, 0.0), |(sx, sz), top| {
+            let [x, _, z] = geometry.positions[*top as usize];
+            (sx + x, sz + z)
+        });
+        let (sum_u, sum_v) = outline.iter().fold((0.0, 0.0), |(su, sv), top| {
+            let [u, v] = geometry.uvs[*top as usize];
+            (su + u, sv + v)
+        });
+        let count = outline.len() as f32;
+        geometry
+            .positions
+            .push([sum_x / count, base_height, sum_z / count]);
+        geometry.normals.push([0.0, -1.0, 0.0]);
+        geometry.uvs.push([sum_u / count, sum_v / count]);
+        let center = geometry.positions.len() as u32 - 1;
+
+        for i in 0..outline.len() {
+            let next = (i + 1) % outline.len();
+            // `a -> b` is the direction the surface triangles use this edge in, so the wall has
+            // to use it as `b -> a`, and the base has to use the bottom edge the other way round
+            // than the wall does.
+            let (a, b) = (outline[i], outline[next]);
+            let (bottom_a, bottom_b) = (first_bottom + i as u32, first_bottom + next as u32);
+            geometry
+                .indices
+                .extend_from_slice(&[b, a, bottom_a, b, bottom_a, bottom_b]);
+            geometry
+                .indices
+                .extend_from_slice(&[bottom_b, bottom_a, center]);
+        }
+
+        geometry
+    }
+}
+
+/// all grid samples on the border of the map that are used by at least one triangle, in the
+/// order the surface triangles traverse them.
+fn outline(geometry: &TerrainGeometry) -> Vec<u32> {
+    let size = geometry.grid_size;
+    if size < 2 {
+        return vec![];
+    }
+
+    let mut used = vec![false; size * size];
+    for index in &geometry.indices {
+        used[*index as usize] = true;
+    }
+
+    let last = size - 1;
+    // top edge right to left, left edge downwards, bottom edge left to right, right edge upwards
+    let border = (0..last)
+        .rev()
+        .map(|x| (x + 1, 0))
+        .chain((0..last).map(|y| (0, y)))
+        .chain((0..last).map(|x| (x, last)))
+        .chain((0..last).rev().map(|y| (last, y + 1)));
+
+    border
+        .map(|(x, y)| y * size + x)
+        .filter(|offset| used[*offset])
+        .map(|offset| offset as u32)
+        .collect()
+}
+
+fn surface_min_height(geometry: &TerrainGeometry) -> f32 {
+    geometry
+        .positions
+        .iter()
+        .map(|[_, y, _]| *y)
+        .fold(f32::INFINITY, f32::min)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::{create_geometry, HeightMap, HeightSource, Triangulation};
+    use std::collections::HashMap;
+
+    struct WavyHeightSource;
+
+    impl HeightSource for WavyHeightSource {
+        fn sample_height(&self, x: usize, y: usize) -> f32 {
+            ((x as f32 * 0.7).sin() + (y as f32 * 0.3).cos()) * 0.2
+        }
+    }
+
+    fn assert_closed_manifold(geometry: &TerrainGeometry) {
+        let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+        for triangle in geometry.indices.chunks_exact(3) {
+            for (a, b) in [
+                (triangle[0], triangle[1]),
+                (triangle[1], triangle[2]),
+                (triangle[2], triangle[0]),
+            ] {
+                *edges.entry((a, b)).or_default() += 1;
+            }
+        }
+
+        for ((a, b), count) in &edges {
+            // consistent winding: every directed edge exists once, and so does its twin
+            assert_eq!(1, *count, "edge {} -> {} is used {} times", a, b, count);
+            assert!(edges.contains_key(&(*b, *a)), "edge {} -> {} is open", a, b);
+        }
+    }
+
+    #[test]
+    fn test_solid_is_closed_manifold() {
+        for size in [3, 9, 33] {
+            let hm = HeightMap::create(WavyHeightSource, size, 10.0);
+            let solid = SolidBuilder::new(create_geometry(hm, Triangulation::Rtin))
+                .base_thickness(1.0)
+                .build();
+
+            assert_closed_manifold(&solid);
+        }
+    }
+
+    #[test]
+    fn test_base_is_below_the_lowest_point() {
+        let hm = HeightMap::create(WavyHeightSource, 9, 10.0);
+        let geometry = create_geometry(hm, Triangulation::Rtin);
+        let lowest = surface_min_height(&geometry);
+        let solid = SolidBuilder::new(geometry).base_thickness(2.0).build();
+
+        let bottom = solid
+            .positions
+            .iter()
+            .map(|[_, y, _]| *y)
+            .fold(f32::INFINITY, f32::min);
+        assert_eq!(lowest - 2.0, bottom);
+    }
+}
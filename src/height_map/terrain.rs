@@ -0,0 +1,204 @@
+//! the samples a terrain mesh was built from. The loaders hand them out as the `terrain` labelled
+//! asset next to the mesh (e.g. `dem.tif#terrain`), so tools that look at or change the terrain
+//! get the full resolution heights and the voids, instead of reading them back from the mesh.
+use bevy::prelude::Mesh;
+use bevy::reflect::TypeUuid;
+
+use crate::height_map::grid::{GridRect, HeightGrid, MaskedGrid, Resampling};
+use crate::height_map::min_max::MinMaxPyramid;
+use crate::height_map::picking::{Ray, RayHit};
+use crate::height_map::{create_mesh, HeightMap, HeightSource, Triangulation, TERRAIN_SIZE};
+
+#[derive(TypeUuid)]
+#[uuid = "e19e1dae-8e59-4aaf-b82d-0074791f4766"]
+pub struct Terrain {
+    height_map: HeightMap<MaskedGrid>,
+    bounds: MinMaxPyramid,
+    triangulation: Triangulation,
+}
+
+impl Terrain {
+    /// `grid` is square, 2^k + 1 samples wide and in world units
+    pub fn new(grid: MaskedGrid) -> Self {
+        let size = grid.grid.width();
+        let bounds = MinMaxPyramid::from_source(&grid, size, size);
+        Self {
+            height_map: HeightMap::create(grid, size, TERRAIN_SIZE),
+            bounds,
+            triangulation: Triangulation::Rtin,
+        }
+    }
+
+    /// how [`Terrain::mesh`] triangulates the samples
+    pub fn with_triangulation(mut self, triangulation: Triangulation) -> Self {
+        self.triangulation = triangulation;
+        self
+    }
+
+    pub fn triangulation(&self) -> Triangulation {
+        self.triangulation
+    }
+
+    pub fn size(&self) -> usize {
+        self.height_map.source_size
+    }
+
+    pub fn height_map(&self) -> &HeightMap<MaskedGrid> {
+        &self.height_map
+    }
+
+    pub fn grid(&self) -> &MaskedGrid {
+        &self.height_map.height_source
+    }
+
+    /// changes the samples, e.g. to erode them. The voids stay where they are.
+    pub fn edit<R>(&mut self, edit: impl FnOnce(&mut HeightGrid) -> R) -> R {
+        let result = edit(&mut self.height_map.height_source.grid);
+        let size = self.size();
+        self.bounds = MinMaxPyramid::from_source(self.grid(), size, size);
+        result
+    }
+
+    /// like [`Terrain::edit`], for edits that only change the samples in the rectangle they
+    /// return (e.g. a brush). Only the bounds above those are updated.
+    pub fn edit_region(
+        &mut self,
+        edit: impl FnOnce(&mut HeightGrid) -> Option<GridRect>,
+    ) -> Option<GridRect> {
+        let region = edit(&mut self.height_map.height_source.grid)?;
+        self.bounds
+            .update_region(&self.height_map.height_source, &region);
+        Some(region)
+    }
+
+    /// builds the mesh again, with holes where the voids are
+    pub fn mesh(&self) -> Mesh {
+        create_mesh(
+            HeightMap::create(
+                self.grid().clone(),
+                self.size(),
+                self.height_map.target_size,
+            ),
+            self.triangulation,
+        )
+    }
+
+    /// where a ray (in mesh space) hits the terrain. Rays through a hole of the mesh do not hit
+    /// anything.
+    pub fn raycast(&self, ray: &Ray) -> Option<RayHit> {
+        let hit = self
+            .height_map
+            .raycast_with_bounds(ray, &self.bounds, Resampling::Bilinear)?;
+        let (x, y) = self
+            .height_map
+            .world_to_grid(hit.position.x, hit.position.z);
+        let last = self.size() - 1;
+        let (x, y) = (
+            (x.max(0.0) as usize).min(last),
+            (y.max(0.0) as usize).min(last),
+        );
+        let corners = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)];
+        if corners
+            .iter()
+            .all(|(x, y)| self.grid().is_valid((*x).min(last), (*y).min(last)))
+        {
+            Some(hit)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::math::Vec3;
+    use bevy::render::mesh::{Indices, VertexAttributeValues};
+
+    /// a slope with a void in the middle
+    fn holey_slope() -> Terrain {
+        let grid = HeightGrid::from_fn(9, 9, |x, _| x as f32 * 0.1);
+        let mask = (0..81).map(|offset| offset != 4 * 9 + 4).collect();
+        Terrain::new(MaskedGrid {
+            grid,
+            mask: Some(mask),
+        })
+    }
+
+    #[test]
+    fn test_rays_fall_through_voids() {
+        let terrain = holey_slope();
+        let down = |x: f32, y: f32| {
+            let (x, z) = terrain.height_map().grid_to_world(x, y);
+            Ray {
+                origin: Vec3::new(x, 5.0, z),
+                direction: -Vec3::Y,
+            }
+        };
+
+        let hit = terrain.raycast(&down(1.5, 6.5)).unwrap();
+        assert!((hit.position.y - 0.15).abs() < 1e-3, "{:?}", hit);
+        // every cell around the void is a hole in the mesh
+        assert_eq!(None, terrain.raycast(&down(3.5, 4.5)));
+        assert_eq!(None, terrain.raycast(&down(4.5, 3.5)));
+        assert!(terrain.raycast(&down(5.5, 5.5 + 1.0)).is_some());
+    }
+
+    #[test]
+    fn test_full_grid_triangulation() {
+        let indices = |terrain: Terrain| match terrain
+            .with_triangulation(Triangulation::Grid)
+            .mesh()
+            .indices()
+        {
+            Some(Indices::U32(indices)) => indices.clone(),
+            _ => panic!("no indices"),
+        };
+
+        // flat enough for RTIN to get away with two triangles, the grid still has all of them
+        let grid = HeightGrid::from_fn(9, 9, |x, _| x as f32 * 0.1);
+        assert_eq!(
+            2 * 8 * 8,
+            indices(Terrain::new(MaskedGrid::from(grid))).len() / 3
+        );
+
+        // the six triangles around the void are gone, nothing else
+        let indices = indices(holey_slope());
+        assert_eq!(2 * 8 * 8 - 6, indices.len() / 3);
+        assert!(!indices.contains(&(4 * 9 + 4)));
+    }
+
+    #[test]
+    fn test_edits_keep_the_voids() {
+        let mut terrain = holey_slope();
+        terrain.edit(|grid| {
+            for height in grid.data_mut() {
+                *height += 1.0;
+            }
+        });
+
+        let mesh = terrain.mesh();
+        let indices = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices,
+            _ => panic!("no indices"),
+        };
+        assert!(!indices.is_empty());
+        assert!(!indices.contains(&(4 * 9 + 4)));
+        // still one texel per sample, so colourings of the terrain survive the edit
+        match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => {
+                assert_eq!([3.5 / 9.0, 5.5 / 9.0], uvs[5 * 9 + 3])
+            }
+            _ => panic!("no uvs"),
+        }
+
+        // above all the samples the terrain had before the edit
+        let (x, z) = terrain.height_map().grid_to_world(1.5, 6.5);
+        let down = Ray {
+            origin: Vec3::new(x, 5.0, z),
+            direction: -Vec3::Y,
+        };
+        let hit = terrain.raycast(&down).unwrap();
+        assert!((hit.position.y - 1.15).abs() < 1e-3, "{:?}", hit);
+    }
+}
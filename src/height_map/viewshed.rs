@@ -0,0 +1,300 @@
+//! what an observer standing on the terrain can see.
+//!
+//! [`Viewshed::line_of_sight`] checks a single pair of points by walking along the line between
+//! them. [`Viewshed::compute`] does that for the whole map at once: it casts a ray to every
+//! border sample and keeps track of the steepest angle any terrain along the ray has reached so
+//! far, everything below it is hidden. Samples crossed by several rays are visible if any of
+//! them sees them (R2, after [Franklin & Ray][0]), which is a tiny bit more generous than
+//! checking every sample on its own, but a lot faster.
+//!
+//! Voids are neither seen nor block the view: there is no telling how high the ground there is.
+//!
+//! [0]: https://www.ecse.rpi.edu/~wrf/Research/Short_Notes/viewshed.pdf
+use bevy::math::Vec2;
+
+use crate::height_map::grid::{HeightGrid, Resampling};
+use crate::height_map::HeightSource;
+
+/// mean radius of the earth, in metres
+const EARTH_RADIUS: f32 = 6_371_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewshed {
+    /// eye height of the observer (or the antenna) above the ground
+    pub observer_height: f32,
+    /// height above the ground a target has to be seen at. 0 asks whether the ground itself is
+    /// visible.
+    pub target_height: f32,
+    /// horizontal distance between neighbouring samples, in the unit of the heights
+    pub cell_size: f32,
+    /// nothing further away than this (in the unit of the heights) is visible
+    pub max_distance: Option<f32>,
+    /// lets far away terrain sink below the horizon. Only makes sense with heights and
+    /// `cell_size` in metres.
+    pub earth_curvature: bool,
+    /// how much the atmosphere bends light back down, and so reduces the curvature. 0.13 is the
+    /// usual value for visible light, radio waves are more like 0.25.
+    pub refraction: f32,
+}
+
+impl Default for Viewshed {
+    fn default() -> Self {
+        Self {
+            observer_height: 1.7,
+            target_height: 0.0,
+            cell_size: 1.0,
+            max_distance: None,
+            earth_curvature: false,
+            refraction: 0.13,
+        }
+    }
+}
+
+impl Viewshed {
+    /// how far the ground has dropped below the horizontal plane of the observer at a distance
+    fn curvature_drop(&self, distance: f32) -> f32 {
+        if self.earth_curvature {
+            distance * distance / (2.0 * EARTH_RADIUS) * (1.0 - self.refraction)
+        } else {
+            0.0
+        }
+    }
+
+    fn in_range(&self, distance: f32) -> bool {
+        match self.max_distance {
+            Some(max) => distance <= max,
+            None => true,
+        }
+    }
+
+    /// whether a target at `to` can be seen from an observer at `from`, both in grid
+    /// coordinates and at their heights above the ground. Never from or to a void.
+    pub fn line_of_sight<H: HeightSource>(
+        &self,
+        source: &H,
+        width: usize,
+        height: usize,
+        from: Vec2,
+        to: Vec2,
+    ) -> bool {
+        let ground = |point: Vec2| ground_height(source, width, height, point);
+        let distance = from.distance(to) * self.cell_size;
+        if !self.in_range(distance) {
+            return false;
+        }
+        let (eye, target) = match (ground(from), ground(to)) {
+            (Some(from), Some(to)) => (
+                from + self.observer_height,
+                to + self.target_height - self.curvature_drop(distance),
+            ),
+            _ => return false,
+        };
+
+        // half a sample apart, so we do not jump over a single sample wide ridge
+        let steps = (from.distance(to) * 2.0).ceil() as usize;
+        (1..steps).all(|step| {
+            let t = step as f32 / steps as f32;
+            match ground(from.lerp(to, t)) {
+                Some(ground) => {
+                    ground - self.curvature_drop(distance * t) <= eye + (target - eye) * t
+                }
+                None => true,
+            }
+        })
+    }
+
+    /// the visibility of every sample from an observer at `observer`: 1 where it can be seen,
+    /// 0 where it is hidden (or a void). Nothing can be seen from a void.
+    pub fn compute<H: HeightSource>(
+        &self,
+        source: &H,
+        width: usize,
+        height: usize,
+        observer: (usize, usize),
+    ) -> HeightGrid {
+        let mut visible = HeightGrid::filled(width, height, 0.0);
+        if !source.is_valid(observer.0, observer.1) {
+            return visible;
+        }
+        let origin = Vec2::new(observer.0 as f32, observer.1 as f32);
+        let eye = source.sample_height(observer.0, observer.1) + self.observer_height;
+        visible.set(observer.0, observer.1, 1.0);
+
+        let (last_x, last_y) = (width - 1, height - 1);
+        let border = (0..width)
+            .flat_map(|x| [(x, 0), (x, last_y)])
+            .chain((0..height).flat_map(|y| [(0, y), (last_x, y)]));
+        for (border_x, border_y) in border {
+            let direction = Vec2::new(border_x as f32, border_y as f32) - origin;
+            let steps = direction.x.abs().max(direction.y.abs()) as usize;
+            let mut steepest = f32::NEG_INFINITY;
+            for step in 1..=steps {
+                // every step crosses one grid line of the major axis
+                let point = origin + direction * (step as f32 / steps as f32);
+                let distance = (point - origin).length() * self.cell_size;
+                if !self.in_range(distance) {
+                    break;
+                }
+                let ground = match ground_height(source, width, height, point) {
+                    Some(ground) => ground - self.curvature_drop(distance),
+                    None => continue,
+                };
+                let target_angle = (ground + self.target_height - eye) / distance;
+                if target_angle >= steepest {
+                    visible.set(point.x.round() as usize, point.y.round() as usize, 1.0);
+                }
+                steepest = steepest.max((ground - eye) / distance);
+            }
+        }
+
+        visible
+    }
+}
+
+/// the interpolated height at `point`, `None` if any of the samples around it is a void
+fn ground_height<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    point: Vec2,
+) -> Option<f32> {
+    let (last_x, last_y) = (width as isize - 1, height as isize - 1);
+    let clamp = |x: isize, y: isize| (x.clamp(0, last_x) as usize, y.clamp(0, last_y) as usize);
+    let (x0, y0) = (point.x.floor() as isize, point.y.floor() as isize);
+    let corners = [(x0, y0), (x0 + 1, y0), (x0, y0 + 1), (x0 + 1, y0 + 1)];
+    if corners.iter().any(|(x, y)| {
+        let (x, y) = clamp(*x, *y);
+        !source.is_valid(x, y)
+    }) {
+        return None;
+    }
+    Some(
+        Resampling::Bilinear.filter(point.x, point.y, 1.0, 1.0, |x, y| {
+            let (x, y) = clamp(x, y);
+            source.sample_height(x, y)
+        }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::height_map::grid::MaskedGrid;
+
+    /// flat ground with a 5 high wall running north to south at x = 10
+    fn wall() -> HeightGrid {
+        HeightGrid::from_fn(21, 21, |x, _| if x == 10 { 5.0 } else { 0.0 })
+    }
+
+    #[test]
+    fn test_line_of_sight_over_a_wall() {
+        let grid = wall();
+        let viewshed = Viewshed::default();
+        let observer = Vec2::new(2.0, 10.0);
+        assert!(viewshed.line_of_sight(&grid, 21, 21, observer, Vec2::new(8.0, 3.0)));
+        assert!(!viewshed.line_of_sight(&grid, 21, 21, observer, Vec2::new(18.0, 10.0)));
+        // the top of the wall itself is visible
+        assert!(viewshed.line_of_sight(&grid, 21, 21, observer, Vec2::new(10.0, 10.0)));
+
+        // high up enough to look over it
+        let tower = Viewshed {
+            observer_height: 20.0,
+            ..viewshed
+        };
+        assert!(tower.line_of_sight(&grid, 21, 21, observer, Vec2::new(18.0, 10.0)));
+        let limited = Viewshed {
+            max_distance: Some(10.0),
+            ..tower
+        };
+        assert!(!limited.line_of_sight(&grid, 21, 21, observer, Vec2::new(18.0, 10.0)));
+    }
+
+    #[test]
+    fn test_viewshed_behind_a_wall() {
+        let grid = wall();
+        let viewshed = Viewshed::default();
+        let visible = viewshed.compute(&grid, 21, 21, (2, 10));
+
+        for y in 0..21 {
+            for x in 0..=10 {
+                assert_eq!(1.0, visible.get(x, y), "{}, {} should be visible", x, y);
+            }
+        }
+        // right behind the wall is in its shadow, only the far corners peek out at the ends
+        for x in 11..21 {
+            assert_eq!(0.0, visible.get(x, 10), "{}, 10 should be hidden", x);
+        }
+
+        // every sample the viewshed calls hidden also has no clear line of sight
+        for y in 0..21 {
+            for x in 0..21 {
+                let target = Vec2::new(x as f32, y as f32);
+                if visible.get(x, y) == 0.0 {
+                    assert!(!viewshed.line_of_sight(&grid, 21, 21, Vec2::new(2.0, 10.0), target));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_voids_do_not_hide_anything() {
+        // a void right in front of the wall, filled with a spike that would hide the wall if it
+        // were terrain
+        let mut grid = wall();
+        grid.set(9, 10, 100.0);
+        let mut mask = vec![true; 21 * 21];
+        mask[10 * 21 + 9] = false;
+        let holey = MaskedGrid {
+            grid,
+            mask: Some(mask),
+        };
+        let viewshed = Viewshed::default();
+        let observer = Vec2::new(2.0, 10.0);
+
+        assert!(viewshed.line_of_sight(&holey, 21, 21, observer, Vec2::new(10.0, 10.0)));
+        assert!(!viewshed.line_of_sight(&holey, 21, 21, observer, Vec2::new(9.0, 10.0)));
+        assert!(!viewshed.line_of_sight(&holey, 21, 21, Vec2::new(9.0, 10.0), observer));
+
+        let visible = viewshed.compute(&holey, 21, 21, (2, 10));
+        assert_eq!(0.0, visible.get(9, 10));
+        assert_eq!(1.0, visible.get(10, 10));
+        for x in 11..21 {
+            assert_eq!(0.0, visible.get(x, 10), "{}, 10 should be hidden", x);
+        }
+        // the samples around the void are seen the same way as without it
+        let without = viewshed.compute(&wall(), 21, 21, (2, 10));
+        assert_eq!(without.get(9, 9), visible.get(9, 9));
+        assert!(viewshed
+            .compute(&holey, 21, 21, (9, 10))
+            .data()
+            .iter()
+            .all(|visible| *visible == 0.0));
+    }
+
+    #[test]
+    fn test_earth_curvature_hides_the_distance() {
+        // 1 km samples of perfectly flat ground, an observer 2 m above it sees about 5.4 km far
+        let flat = HeightGrid::filled(21, 1, 0.0);
+        let viewshed = Viewshed {
+            observer_height: 2.0,
+            cell_size: 1000.0,
+            earth_curvature: true,
+            ..Default::default()
+        };
+        let visible = viewshed.compute(&flat, 21, 1, (0, 0));
+        for x in 0..=5 {
+            assert_eq!(1.0, visible.get(x, 0), "{} km", x);
+        }
+        for x in 6..21 {
+            assert_eq!(0.0, visible.get(x, 0), "{} km", x);
+        }
+        assert!(!viewshed.line_of_sight(&flat, 21, 1, Vec2::ZERO, Vec2::new(10.0, 0.0)));
+
+        let flat_earth = Viewshed {
+            earth_curvature: false,
+            ..viewshed
+        };
+        let visible = flat_earth.compute(&flat, 21, 1, (0, 0));
+        assert!(visible.data().iter().all(|visible| *visible == 1.0));
+    }
+}
@@ -0,0 +1,498 @@
+//! repairs voids in height sources, for when holes in the mesh are not wanted.
+//!
+//! Every method takes any [`HeightSource`] with a validity mask and produces a [`HeightGrid`]
+//! without voids, which can go straight into a
+//! [`HeightMap`](crate::height_map::HeightMap) and the
+//! [`RtinMeshBuilder`](crate::height_map::rtin::RtinMeshBuilder). Valid samples are never changed.
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::height_map::grid::HeightGrid;
+use crate::height_map::HeightSource;
+
+/// stop the harmonic fill once no sample changes more than this in an iteration
+const HARMONIC_TOLERANCE: f32 = 1e-5;
+const HARMONIC_MAX_ITERATIONS: usize = 10_000;
+/// over-relaxation factor, makes Gauss-Seidel converge a lot faster on big voids
+const HARMONIC_RELAXATION: f32 = 1.8;
+/// the inverse distance weighting uses (at least) this many valid samples per void
+const IDW_NEIGHBOURS: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoidFill {
+    /// [`interpolate_voids`]
+    Linear,
+    /// [`inverse_distance_weighting`] with the given power
+    InverseDistance { power: f32 },
+    /// [`harmonic_fill`]
+    Harmonic,
+    /// [`pyramid_fill`]
+    Pyramid,
+}
+
+impl VoidFill {
+    pub fn fill<H: HeightSource>(&self, source: &H, width: usize, height: usize) -> HeightGrid {
+        match self {
+            VoidFill::Linear => interpolate_voids(source, width, height),
+            VoidFill::InverseDistance { power } => {
+                inverse_distance_weighting(source, width, height, *power)
+            }
+            VoidFill::Harmonic => harmonic_fill(source, width, height),
+            VoidFill::Pyramid => pyramid_fill(source, width, height),
+        }
+    }
+}
+
+impl FromStr for VoidFill {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" | "true" => Ok(VoidFill::Linear),
+            "idw" | "inverse_distance" => Ok(VoidFill::InverseDistance { power: 2.0 }),
+            "harmonic" | "laplace" => Ok(VoidFill::Harmonic),
+            "pyramid" => Ok(VoidFill::Pyramid),
+            _ => bail!(
+                "unknown void fill {:?}, expected linear, idw, harmonic or pyramid",
+                s
+            ),
+        }
+    }
+}
+
+/// samples and mask of a source, to not go through the trait for every single access
+struct Grid {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+    valid: Vec<bool>,
+}
+
+impl Grid {
+    fn read<H: HeightSource>(source: &H, width: usize, height: usize) -> Self {
+        let positions = || (0..width * height).map(|offset| (offset % width, offset / width));
+        Self {
+            width,
+            height,
+            data: positions()
+                .map(|(x, y)| source.sample_height(x, y))
+                .collect(),
+            valid: positions().map(|(x, y)| source.is_valid(x, y)).collect(),
+        }
+    }
+
+    /// lowest valid sample, the last resort for voids without any data around them
+    fn lowest(&self) -> f32 {
+        let lowest = self
+            .data
+            .iter()
+            .zip(&self.valid)
+            .filter(|(_, valid)| **valid)
+            .map(|(value, _)| *value)
+            .fold(f32::INFINITY, f32::min);
+        if lowest.is_finite() {
+            lowest
+        } else {
+            0.0
+        }
+    }
+
+    fn into_filled(self) -> HeightGrid {
+        HeightGrid::new(self.width, self.height, self.data)
+    }
+}
+
+/// fills every void with the average of a linear interpolation along its row and one along its
+/// column, between the closest valid samples on either side. Where only one side has data, that
+/// sample is used as it is. Cheap, and good enough for small voids.
+pub fn interpolate_voids<H: HeightSource>(source: &H, width: usize, height: usize) -> HeightGrid {
+    let mut grid = Grid::read(source, width, height);
+    let lowest = grid.lowest();
+    let original = grid.data.clone();
+    let valid = &grid.valid;
+
+    for y in 0..height {
+        for x in 0..width {
+            if valid[y * width + x] {
+                continue;
+            }
+
+            let along_row = interpolate_line(x, width, |i| {
+                let offset = y * width + i;
+                valid[offset].then(|| original[offset])
+            });
+            let along_column = interpolate_line(y, height, |i| {
+                let offset = i * width + x;
+                valid[offset].then(|| original[offset])
+            });
+
+            grid.data[y * width + x] = match (along_row, along_column) {
+                (Some(row), Some(column)) => (row + column) / 2.0,
+                (Some(value), None) | (None, Some(value)) => value,
+                // nothing in this row or column, the same fallback the loaders use
+                (None, None) => lowest,
+            };
+        }
+    }
+
+    grid.into_filled()
+}
+
+/// interpolates position `at` of a line with `length` samples from the closest valid ones
+fn interpolate_line(
+    at: usize,
+    length: usize,
+    sample: impl Fn(usize) -> Option<f32>,
+) -> Option<f32> {
+    let before = (0..at)
+        .rev()
+        .find_map(|i| sample(i).map(|value| (i, value)));
+    let after = (at + 1..length).find_map(|i| sample(i).map(|value| (i, value)));
+
+    match (before, after) {
+        (Some((i0, v0)), Some((i1, v1))) => {
+            let t = (at - i0) as f32 / (i1 - i0) as f32;
+            Some(v0 + (v1 - v0) * t)
+        }
+        (Some((_, value)), None) | (None, Some((_, value))) => Some(value),
+        (None, None) => None,
+    }
+}
+
+/// fills every void with a weighted average of the valid samples around it, weighted by
+/// `1 / distance^power`. We search in growing square rings until we have enough samples, so
+/// voids far away from any data get expensive. Higher powers keep the fill closer to the
+/// nearest samples.
+pub fn inverse_distance_weighting<H: HeightSource>(
+    source: &H,
+    width: usize,
+    height: usize,
+    power: f32,
+) -> HeightGrid {
+    let mut grid = Grid::read(source, width, height);
+    let lowest = grid.lowest();
+    let original = grid.data.clone();
+    let max_radius = width.max(height);
+
+    for y in 0..height {
+        for x in 0..width {
+            if grid.valid[y * width + x] {
+                continue;
+            }
+
+            let mut found = 0;
+            let (mut weighted_sum, mut weight_sum) = (0.0, 0.0);
+            for radius in 1..=max_radius {
+                for (nx, ny) in ring(x, y, radius, width, height) {
+                    let offset = ny * width + nx;
+                    if !grid.valid[offset] {
+                        continue;
+                    }
+                    let dx = nx as f32 - x as f32;
+                    let dy = ny as f32 - y as f32;
+                    let weight = 1.0 / (dx * dx + dy * dy).sqrt().powf(power);
+                    weighted_sum += original[offset] * weight;
+                    weight_sum += weight;
+                    found += 1;
+                }
+                if found >= IDW_NEIGHBOURS {
+                    break;
+                }
+            }
+
+            grid.data[y * width + x] = if found > 0 {
+                weighted_sum / weight_sum
+            } else {
+                lowest
+            };
+        }
+    }
+
+    grid.into_filled()
+}
+
+/// all positions inside the grid at exactly `radius` steps (chebyshev distance) from `x`, `y`
+fn ring(
+    x: usize,
+    y: usize,
+    radius: usize,
+    width: usize,
+    height: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    let (x, y, radius) = (x as isize, y as isize, radius as isize);
+    (y - radius..=y + radius)
+        .flat_map(move |ny| (x - radius..=x + radius).map(move |nx| (nx, ny)))
+        .filter(move |(nx, ny)| (nx - x).abs() == radius || (ny - y).abs() == radius)
+        .filter(move |(nx, ny)| {
+            *nx >= 0 && *ny >= 0 && (*nx as usize) < width && (*ny as usize) < height
+        })
+        .map(|(nx, ny)| (nx as usize, ny as usize))
+}
+
+/// solves the laplace equation over the voids, with the valid samples around them as boundary.
+/// The result is the smoothest surface through the edges of a void, without any creases, which
+/// looks a lot more natural than the other methods for big voids. Planes are restored exactly,
+/// unless the void touches the border.
+pub fn harmonic_fill<H: HeightSource>(source: &H, width: usize, height: usize) -> HeightGrid {
+    // a good first guess saves most of the iterations
+    let mut data = interpolate_voids(source, width, height).into_data();
+    let valid = Grid::read(source, width, height).valid;
+    let voids: Vec<usize> = (0..width * height)
+        .filter(|offset| !valid[*offset])
+        .collect();
+    if voids.len() == width * height {
+        return HeightGrid::new(width, height, data);
+    }
+
+    for _ in 0..HARMONIC_MAX_ITERATIONS {
+        let mut max_change = 0.0f32;
+        for offset in &voids {
+            let (x, y) = (offset % width, offset / width);
+            // the border of the grid mirrors, so it does not pull anything towards it
+            let neighbours = [
+                (x > 0).then(|| offset - 1),
+                (x + 1 < width).then(|| offset + 1),
+                (y > 0).then(|| offset - width),
+                (y + 1 < height).then(|| offset + width),
+            ];
+            let (sum, count) = neighbours
+                .iter()
+                .flatten()
+                .fold((0.0, 0), |(sum, count), n| (sum + data[*n], count + 1));
+            if count == 0 {
+                continue;
+            }
+
+            let change = (sum / count as f32 - data[*offset]) * HARMONIC_RELAXATION;
+            data[*offset] += change;
+            max_change = max_change.max(change.abs());
+        }
+        if max_change < HARMONIC_TOLERANCE {
+            break;
+        }
+    }
+
+    HeightGrid::new(width, height, data)
+}
+
+/// push-pull fill: averages the valid samples into ever coarser levels until a level has no
+/// voids left, then fills the voids of every finer level from the (bilinear upsampled) level
+/// below it. Fast even for huge voids, though a bit blurry.
+pub fn pyramid_fill<H: HeightSource>(source: &H, width: usize, height: usize) -> HeightGrid {
+    let grid = Grid::read(source, width, height);
+    let lowest = grid.lowest();
+    let weights = grid.valid.iter().map(|valid| *valid as u8 as f32).collect();
+    let mut levels = vec![Level {
+        width,
+        height,
+        data: grid.data,
+        weights,
+    }];
+
+    // push: average down until there are no voids left
+    while levels.last().unwrap().has_voids() {
+        let fine = levels.last().unwrap();
+        if fine.width == 1 && fine.height == 1 {
+            // there is no data at all
+            let level = levels.last_mut().unwrap();
+            level.data[0] = lowest;
+            level.weights[0] = 1.0;
+            break;
+        }
+        let coarse = fine.downsample();
+        levels.push(coarse);
+    }
+
+    // pull: fill the voids of each level from the one below
+    for index in (0..levels.len() - 1).rev() {
+        let (fine, coarse) = levels.split_at_mut(index + 1);
+        fine[index].pull_from(&coarse[0]);
+    }
+
+    let level = levels.swap_remove(0);
+    HeightGrid::new(width, height, level.data)
+}
+
+/// a level of the push-pull pyramid. Weights are 1 for valid samples, 0 for voids and
+/// somewhere in between for coarse samples that only partly cover valid ones.
+struct Level {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+    weights: Vec<f32>,
+}
+
+impl Level {
+    fn has_voids(&self) -> bool {
+        self.weights.contains(&0.0)
+    }
+
+    fn downsample(&self) -> Level {
+        let width = self.width / 2 + self.width % 2;
+        let height = self.height / 2 + self.height % 2;
+        let mut data = vec![0.0; width * height];
+        let mut weights = vec![0.0; width * height];
+
+        for y in 0..height {
+            for x in 0..width {
+                let (mut value_sum, mut weight_sum) = (0.0, 0.0);
+                for fy in (y * 2)..(y * 2 + 2).min(self.height) {
+                    for fx in (x * 2)..(x * 2 + 2).min(self.width) {
+                        let offset = fy * self.width + fx;
+                        value_sum += self.data[offset] * self.weights[offset];
+                        weight_sum += self.weights[offset];
+                    }
+                }
+                if weight_sum > 0.0 {
+                    data[y * width + x] = value_sum / weight_sum;
+                    weights[y * width + x] = weight_sum.min(1.0);
+                }
+            }
+        }
+
+        Level {
+            width,
+            height,
+            data,
+            weights,
+        }
+    }
+
+    /// blends every sample with the bilinear interpolation of `coarse`, by how much data it has
+    fn pull_from(&mut self, coarse: &Level) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let offset = y * self.width + x;
+                let weight = self.weights[offset];
+                if weight >= 1.0 {
+                    continue;
+                }
+                let interpolated =
+                    coarse.bilinear((x as f32 + 0.5) / 2.0 - 0.5, (y as f32 + 0.5) / 2.0 - 0.5);
+                self.data[offset] = self.data[offset] * weight + interpolated * (1.0 - weight);
+                self.weights[offset] = 1.0;
+            }
+        }
+    }
+
+    fn bilinear(&self, x: f32, y: f32) -> f32 {
+        let x = x.clamp(0.0, (self.width - 1) as f32);
+        let y = y.clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+        let sample = |x: usize, y: usize| self.data[y * self.width + x];
+
+        let top = sample(x0, y0) * (1.0 - tx) + sample(x1, y0) * tx;
+        let bottom = sample(x0, y1) * (1.0 - tx) + sample(x1, y1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// a surface with a rectangular void
+    struct Holey<F: Fn(usize, usize) -> f32>(F);
+
+    impl<F: Fn(usize, usize) -> f32> HeightSource for Holey<F> {
+        fn sample_height(&self, x: usize, y: usize) -> f32 {
+            if self.is_valid(x, y) {
+                (self.0)(x, y)
+            } else {
+                -9999.0
+            }
+        }
+
+        fn is_valid(&self, x: usize, y: usize) -> bool {
+            !((4..11).contains(&x) && (3..8).contains(&y))
+        }
+    }
+
+    const SIZE: usize = 17;
+
+    fn max_error<F: Fn(usize, usize) -> f32>(filled: &HeightGrid, surface: F) -> f32 {
+        (0..SIZE * SIZE)
+            .map(|offset| {
+                let (x, y) = (offset % SIZE, offset / SIZE);
+                (filled.sample_height(x, y) - surface(x, y)).abs()
+            })
+            .fold(0.0, f32::max)
+    }
+
+    fn plane(x: usize, y: usize) -> f32 {
+        x as f32 * 0.5 - y as f32 * 0.25 + 3.0
+    }
+
+    fn bowl(x: usize, y: usize) -> f32 {
+        let (dx, dy) = (x as f32 - 8.0, y as f32 - 8.0);
+        (dx * dx + dy * dy) / 32.0
+    }
+
+    #[test]
+    fn test_linear_restores_a_plane() {
+        let filled = interpolate_voids(&Holey(plane), SIZE, SIZE);
+        assert!(max_error(&filled, plane) < 1e-4);
+    }
+
+    #[test]
+    fn test_harmonic_restores_a_plane() {
+        let filled = harmonic_fill(&Holey(plane), SIZE, SIZE);
+        assert!(max_error(&filled, plane) < 1e-3);
+    }
+
+    #[test]
+    fn test_fills_stay_close_to_a_smooth_surface() {
+        let source = Holey(bowl);
+        // the void is about 1.3 deep, a flat fill would be off by about that much
+        for (fill, bound) in [
+            (VoidFill::Linear, 0.45),
+            // weights the whole rim, so the far side of the bowl pulls the fill up
+            (VoidFill::InverseDistance { power: 2.0 }, 0.9),
+            (VoidFill::Harmonic, 0.45),
+            // fills from blurry coarse averages of the rim, which flattens most of the bowl
+            (VoidFill::Pyramid, 1.1),
+        ] {
+            let filled = fill.fill(&source, SIZE, SIZE);
+            let error = max_error(&filled, bowl);
+            assert!(error < bound, "{:?} is off by {}", fill, error);
+        }
+    }
+
+    #[test]
+    fn test_valid_samples_are_kept_and_fill_is_bounded() {
+        let source = Holey(bowl);
+        for fill in [
+            VoidFill::InverseDistance { power: 2.0 },
+            VoidFill::Harmonic,
+            VoidFill::Pyramid,
+        ] {
+            let filled = fill.fill(&source, SIZE, SIZE);
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    let value = filled.sample_height(x, y);
+                    if source.is_valid(x, y) {
+                        assert_eq!(bowl(x, y), value, "{:?} changed {}, {}", fill, x, y);
+                    } else {
+                        // all of them average valid samples in some way, so they can never
+                        // leave the range of the data
+                        assert!((0.0..=4.0).contains(&value), "{:?}: {}", fill, value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_void_fill_from_str() {
+        assert_eq!(VoidFill::Linear, "true".parse().unwrap());
+        assert_eq!(VoidFill::Harmonic, "harmonic".parse().unwrap());
+        assert_eq!(
+            VoidFill::InverseDistance { power: 2.0 },
+            "idw".parse().unwrap()
+        );
+        assert!("bilinear".parse::<VoidFill>().is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! earthwork estimates: how much material has to be dug away (cut) or brought in (fill) to turn
+//! one surface into another, e.g. the terrain now into a levelled building site, or the terrain
+//! before into the terrain after some change.
+//!
+//! Every sample stands for a square of `cell_size` × `cell_size` around it, so volumes are in
+//! world units as long as the heights and the cell size are.
+use bevy::math::Vec2;
+use image::{Rgb, RgbImage};
+
+use crate::height_map::grid::HeightGrid;
+use crate::height_map::HeightSource;
+
+/// a flat reference surface to compare against, tilted by a fixed slope (height per sample)
+/// in x and y if needed
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReferencePlane {
+    /// height at sample 0, 0
+    pub height: f32,
+    pub slope_x: f32,
+    pub slope_y: f32,
+}
+
+impl ReferencePlane {
+    pub fn level(height: f32) -> Self {
+        Self {
+            height,
+            ..Default::default()
+        }
+    }
+}
+
+impl HeightSource for ReferencePlane {
+    fn sample_height(&self, x: usize, y: usize) -> f32 {
+        self.height + x as f32 * self.slope_x + y as f32 * self.slope_y
+    }
+}
+
+/// which samples take part in a volume computation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region<'a> {
+    All,
+    /// the samples that are not 0 in a grid of the same size
+    Mask(&'a HeightGrid),
+    /// the samples inside a polygon in grid coordinates, closed implicitly
+    Polygon(&'a [Vec2]),
+}
+
+impl Region<'_> {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        match self {
+            Region::All => true,
+            Region::Mask(mask) => mask.get(x, y) != 0.0,
+            Region::Polygon(polygon) => inside_polygon(polygon, Vec2::new(x as f32, y as f32)),
+        }
+    }
+}
+
+/// even-odd rule: a point is inside if a ray from it crosses the outline an odd number of times
+fn inside_polygon(polygon: &[Vec2], point: Vec2) -> bool {
+    let mut inside = false;
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.y > point.y) != (b.y > point.y) {
+            let crossing = a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x);
+            if point.x < crossing {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Earthwork {
+    /// material to remove where the existing surface is above the design
+    pub cut: f32,
+    /// material to bring in where the existing surface is below the design
+    pub fill: f32,
+    /// design minus existing height, 0 outside the region. Positive is fill, negative is cut.
+    pub difference: HeightGrid,
+    /// samples that took part: inside the region and valid in both surfaces
+    pub samples: usize,
+}
+
+impl Earthwork {
+    /// fill minus cut: positive if material has to be brought in overall
+    pub fn net(&self) -> f32 {
+        self.fill - self.cut
+    }
+}
+
+/// cut and fill to turn the `existing` surface into the `design` one, within `region`.
+/// Samples that are invalid in either of them are left out.
+pub fn earthwork<A: HeightSource, B: HeightSource>(
+    existing: &A,
+    design: &B,
+    width: usize,
+    height: usize,
+    cell_size: f32,
+    region: Region,
+) -> Earthwork {
+    let cell_area = cell_size * cell_size;
+    let mut earthwork = Earthwork {
+        cut: 0.0,
+        fill: 0.0,
+        difference: HeightGrid::filled(width, height, 0.0),
+        samples: 0,
+    };
+    for y in 0..height {
+        for x in 0..width {
+            if !region.contains(x, y) || !existing.is_valid(x, y) || !design.is_valid(x, y) {
+                continue;
+            }
+            let difference = design.sample_height(x, y) - existing.sample_height(x, y);
+            if difference > 0.0 {
+                earthwork.fill += difference * cell_area;
+            } else {
+                earthwork.cut -= difference * cell_area;
+            }
+            earthwork.difference.set(x, y, difference);
+            earthwork.samples += 1;
+        }
+    }
+
+    earthwork
+}
+
+/// a diverging colour ramp for a difference grid: red where material is cut, white where
+/// nothing changes and blue where it is filled. Scaled to the largest change either way, so the
+/// same colour means the same depth on both sides.
+pub fn difference_image(difference: &HeightGrid) -> RgbImage {
+    let (min, max) = difference.min_max();
+    let range = max.max(-min).max(f32::EPSILON);
+    let mix = |from: [f32; 3], t: f32| {
+        let [r, g, b] = from.map(|c| (255.0 + (c - 255.0) * t).round() as u8);
+        Rgb([r, g, b])
+    };
+    RgbImage::from_fn(
+        difference.width() as u32,
+        difference.height() as u32,
+        |x, y| {
+            let value = difference.get(x as usize, y as usize) / range;
+            if value < 0.0 {
+                mix([200.0, 40.0, 30.0], -value)
+            } else {
+                mix([30.0, 80.0, 200.0], value)
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// a 9 × 9 pyramid, 4 high in the middle
+    fn pyramid() -> HeightGrid {
+        HeightGrid::from_fn(9, 9, |x, y| {
+            let distance = (x as isize - 4).abs().max((y as isize - 4).abs());
+            (4 - distance) as f32
+        })
+    }
+
+    #[test]
+    fn test_levelling_a_pyramid() {
+        let terrain = pyramid();
+        let levelled = earthwork(
+            &terrain,
+            &ReferencePlane::level(2.0),
+            9,
+            9,
+            2.0,
+            Region::All,
+        );
+
+        // rings of 8 * d samples at height 4 - d, one sample at the top
+        let cut = (2.0 + 8.0 * 1.0) * 4.0;
+        let fill = (8.0 * 3.0 * 1.0 + 8.0 * 4.0 * 2.0) * 4.0;
+        assert_eq!(cut, levelled.cut);
+        assert_eq!(fill, levelled.fill);
+        assert_eq!(fill - cut, levelled.net());
+        assert_eq!(81, levelled.samples);
+        assert_eq!(-2.0, levelled.difference.get(4, 4));
+        assert_eq!(2.0, levelled.difference.get(0, 8));
+
+        // the same surface on both sides does not move anything
+        let unchanged = earthwork(&terrain, &terrain.clone(), 9, 9, 1.0, Region::All);
+        assert_eq!((0.0, 0.0), (unchanged.cut, unchanged.fill));
+    }
+
+    #[test]
+    fn test_regions() {
+        let terrain = pyramid();
+        let plane = ReferencePlane {
+            height: 4.0,
+            slope_x: -0.5,
+            slope_y: 0.0,
+        };
+        assert_eq!(3.0, plane.sample_height(2, 7));
+
+        let mask = HeightGrid::from_fn(9, 9, |x, _| if x < 2 { 1.0 } else { 0.0 });
+        let masked = earthwork(&terrain, &plane, 9, 9, 1.0, Region::Mask(&mask));
+        assert_eq!(18, masked.samples);
+        assert_eq!(0.0, masked.difference.get(5, 5));
+
+        // a triangle over the bottom left corner, 3 + 2 + 1 samples
+        let polygon = [
+            Vec2::new(-0.5, 8.5),
+            Vec2::new(3.5, 8.5),
+            Vec2::new(-0.5, 4.5),
+        ];
+        let region = Region::Polygon(&polygon);
+        assert!(region.contains(0, 8));
+        assert!(region.contains(1, 7));
+        assert!(!region.contains(2, 6));
+        let inside = earthwork(&terrain, &plane, 9, 9, 1.0, region);
+        assert_eq!(6, inside.samples);
+    }
+
+    #[test]
+    fn test_difference_colours() {
+        let difference = HeightGrid::new(3, 1, vec![-2.0, 0.0, 1.0]);
+        let image = difference_image(&difference);
+        assert_eq!(Rgb([200, 40, 30]), *image.get_pixel(0, 0));
+        assert_eq!(Rgb([255, 255, 255]), *image.get_pixel(1, 0));
+        // half as deep as the deepest cut, so halfway to the full blue
+        assert_eq!(Rgb([143, 168, 228]), *image.get_pixel(2, 0));
+    }
+}
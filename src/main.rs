@@ -12,9 +12,14 @@ use smooth_bevy_cameras::{
     LookTransformPlugin,
 };
 
-use systems::{TerrainMarker, ToggleWireframe};
+use bevy::asset::AssetPath;
+use systems::{Sculpting, TerrainData, TerrainMarker, ToggleWireframe};
 use venture::debug_ui::DebugUiPlugin;
-use venture::height_map::loader::HeightmapMeshLoader;
+use venture::height_map::geotiff::GeoReference;
+use venture::height_map::terrain::Terrain;
+use venture::height_map::loader::{
+    HeightmapMeshLoader, RasterMeshLoader, RawHeightmapMeshLoader,
+};
 
 mod systems;
 
@@ -48,14 +53,30 @@ fn main() {
         .add_plugin(OrbitCameraPlugin::default())
         .add_plugin(WireframePlugin)
         .add_plugin(DebugUiPlugin)
+        .add_asset::<GeoReference>()
+        .add_asset::<Terrain>()
         .init_asset_loader::<HeightmapMeshLoader>()
+        .init_asset_loader::<RasterMeshLoader>()
+        .init_asset_loader::<RawHeightmapMeshLoader>()
         .insert_resource(LoadTerrainMapPath::default())
+        .insert_resource(Sculpting::default())
         .add_startup_system(setup)
         .add_startup_system(setup_camera)
         .add_system(file_drag_and_drop_system)
         .add_system(load_new_terrain)
         .add_system(systems::exit_from_keypress)
         .add_system(systems::toggle_wireframe)
+        .add_system(systems::pick_terrain)
+        .add_system(systems::erode_terrain)
+        .add_system(systems::toggle_rivers)
+        .add_system(systems::show_catchment)
+        .add_system(systems::cycle_terrain_colouring)
+        .add_system(systems::update_terrain_preview)
+        .add_system(systems::toggle_contours)
+        .add_system(systems::show_viewshed)
+        .add_system(systems::draw_profile_path)
+        .add_system(systems::show_earthwork)
+        .add_system(systems::sculpt_terrain)
         .run();
 }
 
@@ -87,6 +108,7 @@ fn setup(
             ..Default::default()
         })
         .insert(TerrainMarker(terrain_mesh))
+        .insert(TerrainData::default())
         .insert(ToggleWireframe);
 
     // light
@@ -110,7 +132,7 @@ fn setup(
     });
 }
 
-fn load_new_terrain(mut terrain: Query<(&mut Handle<Mesh>, &mut TerrainMarker)>, mut to_load: ResMut<LoadTerrainMapPath>, asset_server: Res<AssetServer>) {
+fn load_new_terrain(mut terrain: Query<(&mut Handle<Mesh>, &mut TerrainMarker, &mut TerrainData)>, mut to_load: ResMut<LoadTerrainMapPath>, asset_server: Res<AssetServer>) {
     if to_load.0.is_none() {
         return;
     }
@@ -118,12 +140,14 @@ fn load_new_terrain(mut terrain: Query<(&mut Handle<Mesh>, &mut TerrainMarker)>,
     // we can unwrap here, as we know that to_load.0 is Some(..)
     let path = to_load.0.take().unwrap();
     println!("loading new map from {:?}", path.as_os_str());
+    let terrain_data = asset_server.load(AssetPath::new(path.clone(), Some("terrain".to_string())));
     let terrain_mesh: Handle<Mesh> = asset_server.load(path);
 
-    let (mut current_mesh, mut marker) = terrain.single_mut();
+    let (mut current_mesh, mut marker, mut data) = terrain.single_mut();
 
     *current_mesh = terrain_mesh.clone_weak();
     marker.0 = terrain_mesh;
+    data.0 = terrain_data;
 }
 
 fn file_drag_and_drop_system(mut events: EventReader<FileDragAndDrop>, mut to_load: ResMut<LoadTerrainMapPath>) {
@@ -1,9 +1,47 @@
-use bevy::{app::AppExit, pbr::wireframe::Wireframe, prelude::*};
+use bevy::{app::AppExit, pbr::wireframe::Wireframe, prelude::*, render::camera::Camera3d};
+use venture::height_map;
+use venture::height_map::analysis::{SlopeUnit, TerrainDerivative};
+use venture::height_map::contours;
+use venture::height_map::erosion::HydraulicErosion;
+use venture::height_map::grid::{HeightGrid, MaskedGrid, Resampling};
+use venture::height_map::hillshade::{self, Hillshade};
+use venture::height_map::hydrology::{self, FlowDirections};
+use venture::height_map::picking::{Ray, TerrainPick};
+use venture::height_map::profile::TerrainPath;
+use venture::height_map::sculpt::{Brush, BrushKind, Falloff, SculptedTerrain, Stroke};
+use venture::height_map::terrain::Terrain;
+use venture::height_map::viewshed::Viewshed;
+use venture::height_map::volume::{self, ReferencePlane, Region};
+use venture::height_map::{HeightSource, TERRAIN_SIZE};
+
+/// droplets per press of the erosion key
+const EROSION_DROPLETS: usize = 20_000;
+
+/// share of the map that has to drain through a cell before it shows up as a river
+const RIVER_THRESHOLD: f32 = 0.002;
+
+/// size of the shaded relief preview in the corner, in pixels
+const PREVIEW_SIZE: usize = 160;
+
+/// what the terrain can be coloured by, in the order the colouring key cycles through them
+const TERRAIN_COLOURINGS: [Option<TerrainDerivative>; 7] = [
+    None,
+    Some(TerrainDerivative::Slope(SlopeUnit::Degrees)),
+    Some(TerrainDerivative::Aspect),
+    Some(TerrainDerivative::ProfileCurvature),
+    Some(TerrainDerivative::PlanCurvature),
+    Some(TerrainDerivative::Ruggedness),
+    Some(TerrainDerivative::TopographicPosition(8)),
+];
 
 #[derive(Component, Debug, Clone, Default, Reflect)]
 #[reflect(Component)]
 pub struct TerrainMarker(pub Handle<Mesh>);
 
+/// the samples the mesh of a [`TerrainMarker`] was built from
+#[derive(Component, Debug, Clone, Default)]
+pub struct TerrainData(pub Handle<Terrain>);
+
 /// test/example on how to get access to a mesh and update its indices.
 /// we might be able to use this later to update the lod of a mesh?
 #[allow(dead_code)]
@@ -48,3 +86,842 @@ pub fn exit_from_keypress(
         app_exit_events.send(AppExit);
     }
 }
+
+/// casts a ray from the mouse cursor onto the terrain, for the debug ui and anything else that
+/// wants to know what is under the cursor
+pub fn pick_terrain(
+    windows: Res<Windows>,
+    terrain_data: Res<Assets<Terrain>>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    terrains: Query<(&TerrainData, &GlobalTransform)>,
+    mut pick: ResMut<TerrainPick>,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let hit = window.cursor_position().and_then(|cursor| {
+        let ndc = cursor / Vec2::new(window.width(), window.height()) * 2.0 - Vec2::ONE;
+        let (camera, camera_transform) = cameras.iter().next()?;
+        let ray = Ray::from_ndc(
+            ndc.x,
+            ndc.y,
+            camera_transform.compute_matrix(),
+            camera.projection_matrix,
+        );
+
+        terrains
+            .iter()
+            .filter_map(|(terrain, transform)| {
+                let terrain = terrain_data.get(&terrain.0)?;
+
+                // intersect in mesh space, so the terrain can be moved around
+                let to_world = transform.compute_matrix();
+                let to_local = to_world.inverse();
+                let local_ray = Ray {
+                    origin: to_local.transform_point3(ray.origin),
+                    direction: to_local.transform_vector3(ray.direction),
+                };
+                let mut hit = terrain.raycast(&local_ray)?;
+                hit.position = to_world.transform_point3(hit.position);
+                hit.normal = to_local
+                    .transpose()
+                    .transform_vector3(hit.normal)
+                    .normalize();
+                Some(hit)
+            })
+            .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap())
+    });
+
+    // only touch the resource if something changed, so the ui does not update every frame
+    if pick.0 != hit {
+        pick.0 = hit;
+    }
+}
+
+/// runs some hydraulic erosion on the current terrain when E is released, and re-meshes it
+pub fn erode_terrain(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain_data: ResMut<Assets<Terrain>>,
+    terrains: Query<(&TerrainMarker, &TerrainData)>,
+    mut runs: Local<u64>,
+) {
+    if !keyboard_input.just_released(KeyCode::E) {
+        return;
+    }
+
+    for (marker, data) in terrains.iter() {
+        let terrain = match terrain_data.get_mut(&data.0) {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+        // a new seed every time, or we would carve the same channels over and over
+        let erosion = HydraulicErosion {
+            iterations: EROSION_DROPLETS,
+            seed: *runs,
+            ..Default::default()
+        };
+        terrain.edit(|grid| erosion.erode(grid));
+        *runs += 1;
+
+        if let Some(mesh) = meshes.get_mut(&marker.0) {
+            *mesh = terrain.mesh();
+        }
+    }
+}
+
+/// despawns every overlay tagged with `M`, returns whether there were any
+fn despawn_overlays<M: Component>(
+    commands: &mut Commands,
+    overlays: &Query<Entity, With<M>>,
+) -> bool {
+    let mut any = false;
+    for entity in overlays.iter() {
+        commands.entity(entity).despawn();
+        any = true;
+    }
+    any
+}
+
+/// spawns `mesh` as an unlit overlay on the terrain at `transform`, tagged with `marker` so it
+/// can be despawned again
+fn spawn_overlay(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    mesh: Mesh,
+    material: StandardMaterial,
+    transform: Transform,
+    marker: impl Component,
+) {
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(mesh),
+            material: materials.add(StandardMaterial {
+                unlit: true,
+                ..material
+            }),
+            transform,
+            ..Default::default()
+        })
+        .insert(marker);
+}
+
+/// the filled heights of a terrain, which keep its voids so no water is routed through them
+fn filled_terrain(terrain: &Terrain) -> MaskedGrid {
+    let size = terrain.size();
+    MaskedGrid {
+        grid: hydrology::fill_depressions(terrain.grid(), size, size, 1e-5),
+        mask: terrain.grid().mask.clone(),
+    }
+}
+
+#[derive(Component, Debug, Clone, Default)]
+pub struct RiverOverlay;
+
+/// shows (or hides again) the rivers of the current terrain when R is released
+pub fn toggle_rivers(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_data: Res<Assets<Terrain>>,
+    terrains: Query<(&TerrainData, &Transform)>,
+    overlays: Query<Entity, With<RiverOverlay>>,
+) {
+    if !keyboard_input.just_released(KeyCode::R) {
+        return;
+    }
+    if despawn_overlays(&mut commands, &overlays) {
+        return;
+    }
+
+    for (data, transform) in terrains.iter() {
+        let terrain = match terrain_data.get(&data.0) {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+        let size = terrain.size();
+        let flow = FlowDirections::d8(&filled_terrain(terrain), size, size);
+        let threshold = (size * size) as f32 * RIVER_THRESHOLD;
+        let streams = flow.streams(&flow.accumulation(), threshold);
+
+        spawn_overlay(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            height_map::polyline_mesh(&streams, terrain.height_map(), 0.01),
+            StandardMaterial {
+                base_color: Color::rgb(0.1, 0.3, 0.9),
+                ..Default::default()
+            },
+            *transform,
+            RiverOverlay,
+        );
+    }
+}
+
+#[derive(Component, Debug, Clone, Default)]
+pub struct CatchmentOverlay;
+
+/// highlights the area draining into the point under the cursor when C is released
+#[allow(clippy::too_many_arguments)]
+pub fn show_catchment(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    pick: Res<TerrainPick>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_data: Res<Assets<Terrain>>,
+    terrains: Query<(&TerrainMarker, &TerrainData, &Transform)>,
+    overlays: Query<Entity, With<CatchmentOverlay>>,
+) {
+    if !keyboard_input.just_released(KeyCode::C) {
+        return;
+    }
+    despawn_overlays(&mut commands, &overlays);
+    let hit = match pick.0 {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    for (marker, data, transform) in terrains.iter() {
+        let (mesh, terrain) = match (meshes.get(&marker.0), terrain_data.get(&data.0)) {
+            (Some(mesh), Some(terrain)) => (mesh, terrain),
+            _ => continue,
+        };
+        let size = terrain.size();
+
+        let local = transform
+            .compute_matrix()
+            .inverse()
+            .transform_point3(hit.position);
+        let (x, y) = terrain.height_map().world_to_grid(local.x, local.z);
+        let last = (size - 1) as f32;
+        let (x, y) = (x.round().clamp(0.0, last), y.round().clamp(0.0, last));
+
+        let flow = FlowDirections::d8(&filled_terrain(terrain), size, size);
+        let (x, y) = hydrology::snap_pour_point(&flow.accumulation(), x as usize, y as usize, 2);
+        let catchment = flow.catchment(x, y);
+        println!(
+            "catchment of {}, {}: {} cells, {} square units",
+            x,
+            y,
+            catchment.cells,
+            catchment.area(TERRAIN_SIZE / size as f32)
+        );
+
+        if let Some(overlay) = height_map::masked_mesh(mesh, &catchment.mask, 0.005) {
+            spawn_overlay(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                overlay,
+                StandardMaterial {
+                    base_color: Color::rgba(0.9, 0.5, 0.1, 0.5),
+                    alpha_mode: AlphaMode::Blend,
+                    ..Default::default()
+                },
+                *transform,
+                CatchmentOverlay,
+            );
+        }
+    }
+}
+
+/// colours the terrain by the next of its derivatives (slope, aspect, ...) when A is released
+pub fn cycle_terrain_colouring(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_data: Res<Assets<Terrain>>,
+    terrains: Query<(&TerrainData, &Handle<StandardMaterial>)>,
+    mut current: Local<usize>,
+) {
+    if !keyboard_input.just_released(KeyCode::A) {
+        return;
+    }
+    *current = (*current + 1) % TERRAIN_COLOURINGS.len();
+
+    for (data, material) in terrains.iter() {
+        let material = match materials.get_mut(material) {
+            Some(material) => material,
+            None => continue,
+        };
+        let derivative = match TERRAIN_COLOURINGS[*current] {
+            Some(derivative) => derivative,
+            None => {
+                material.base_color_texture = None;
+                continue;
+            }
+        };
+        let terrain = match terrain_data.get(&data.0) {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+
+        // the terrain mesh has one texel per sample as its uvs, so the image fits it as it is
+        let size = terrain.size();
+        let values = derivative.compute(terrain.grid(), size, size, TERRAIN_SIZE / size as f32);
+        let texture = hillshade::texture(&derivative.to_image(&values));
+
+        material.base_color_texture = Some(images.add(texture));
+        println!("colouring the terrain by {:?}", derivative);
+    }
+}
+
+#[derive(Component, Debug, Clone, Default)]
+pub struct TerrainPreview;
+
+/// keeps a small shaded relief of the current terrain in the top right corner
+#[allow(clippy::too_many_arguments)]
+pub fn update_terrain_preview(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<Terrain>>,
+    sculpting: Res<Sculpting>,
+    terrain_data: Res<Assets<Terrain>>,
+    mut images: ResMut<Assets<Image>>,
+    terrains: Query<&TerrainData>,
+    mut previews: Query<&mut UiImage, With<TerrainPreview>>,
+    mut changed: Local<Vec<Handle<Terrain>>>,
+) {
+    for event in events.iter() {
+        let handle = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            AssetEvent::Removed { .. } => continue,
+        };
+        if terrains.iter().any(|data| &data.0 == handle) && !changed.contains(handle) {
+            changed.push(handle.clone_weak());
+        }
+    }
+    // every dab of a stroke changes the terrain, the preview only catches up once it ends
+    if sculpting.0 {
+        return;
+    }
+
+    for handle in changed.drain(..) {
+        let terrain = match terrain_data.get(&handle) {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+
+        let size = terrain.size();
+        let relief = Hillshade {
+            cell_size: TERRAIN_SIZE / size as f32,
+            multi_directional: true,
+            tint: true,
+            ..Default::default()
+        }
+        .thumbnail(terrain.grid(), size, size, PREVIEW_SIZE);
+        let image = images.add(hillshade::texture(&relief));
+
+        match previews.get_single_mut() {
+            Ok(mut preview) => preview.0 = image,
+            Err(_) => {
+                commands
+                    .spawn_bundle(ImageBundle {
+                        style: Style {
+                            size: Size::new(
+                                Val::Px(PREVIEW_SIZE as f32),
+                                Val::Px(PREVIEW_SIZE as f32),
+                            ),
+                            position_type: PositionType::Absolute,
+                            position: Rect {
+                                right: Val::Px(5.0),
+                                top: Val::Px(5.0),
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        },
+                        image: UiImage(image),
+                        ..Default::default()
+                    })
+                    .insert(TerrainPreview);
+            }
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, Default)]
+pub struct ContourOverlay;
+
+/// shows (or hides again) contour lines on the terrain when L is released
+pub fn toggle_contours(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_data: Res<Assets<Terrain>>,
+    terrains: Query<(&TerrainData, &Transform)>,
+    overlays: Query<Entity, With<ContourOverlay>>,
+) {
+    if !keyboard_input.just_released(KeyCode::L) {
+        return;
+    }
+    if despawn_overlays(&mut commands, &overlays) {
+        return;
+    }
+
+    for (data, transform) in terrains.iter() {
+        let terrain = match terrain_data.get(&data.0) {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+        // cells touching a void get no lines
+        let (grid, size) = (terrain.grid(), terrain.size());
+        let (min, max) = match contours::height_range(grid, size, size) {
+            Some(range) => range,
+            None => continue,
+        };
+        let interval = contours::nice_interval(min, max, 20);
+        let lines: Vec<Vec<Vec2>> = contours::contours_at_interval(grid, size, size, interval)
+            .into_iter()
+            .map(|contour| contour.points)
+            .collect();
+        println!("{} contour lines every {} units", lines.len(), interval);
+
+        spawn_overlay(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            height_map::polyline_mesh(&lines, terrain.height_map(), 0.005),
+            StandardMaterial {
+                base_color: Color::rgb(0.4, 0.2, 0.05),
+                ..Default::default()
+            },
+            *transform,
+            ContourOverlay,
+        );
+    }
+}
+
+/// eye height of the viewshed observer above the terrain, in world units
+const OBSERVER_HEIGHT: f32 = 0.05;
+
+#[derive(Component, Debug, Clone, Default)]
+pub struct ViewshedOverlay;
+
+/// shows what can be seen from the point under the cursor when V is released, visible terrain in
+/// green and hidden terrain in red
+#[allow(clippy::too_many_arguments)]
+pub fn show_viewshed(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    pick: Res<TerrainPick>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_data: Res<Assets<Terrain>>,
+    terrains: Query<(&TerrainMarker, &TerrainData, &Transform)>,
+    overlays: Query<Entity, With<ViewshedOverlay>>,
+) {
+    if !keyboard_input.just_released(KeyCode::V) {
+        return;
+    }
+    despawn_overlays(&mut commands, &overlays);
+    let hit = match pick.0 {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    for (marker, data, transform) in terrains.iter() {
+        let (mesh, terrain) = match (meshes.get(&marker.0), terrain_data.get(&data.0)) {
+            (Some(mesh), Some(terrain)) => (mesh, terrain),
+            _ => continue,
+        };
+        let (grid, size) = (terrain.grid(), terrain.size());
+        let viewshed = Viewshed {
+            observer_height: OBSERVER_HEIGHT,
+            cell_size: TERRAIN_SIZE / size as f32,
+            ..Default::default()
+        };
+
+        let local = transform
+            .compute_matrix()
+            .inverse()
+            .transform_point3(hit.position);
+        let (x, y) = terrain.height_map().world_to_grid(local.x, local.z);
+        let last = (size - 1) as f32;
+        let observer = (
+            x.round().clamp(0.0, last) as usize,
+            y.round().clamp(0.0, last) as usize,
+        );
+
+        // voids are neither visible nor hidden
+        let visible = viewshed.compute(grid, size, size, observer);
+        let hidden = HeightGrid::from_fn(size, size, |x, y| {
+            if grid.is_valid(x, y) {
+                1.0 - visible.get(x, y)
+            } else {
+                0.0
+            }
+        });
+        println!(
+            "{} of {} samples visible from {}, {}",
+            visible
+                .data()
+                .iter()
+                .filter(|visible| **visible > 0.0)
+                .count(),
+            size * size,
+            observer.0,
+            observer.1
+        );
+
+        let overlays: Vec<(Mesh, Color)> = [
+            (visible, Color::rgba(0.1, 0.8, 0.2, 0.4)),
+            (hidden, Color::rgba(0.8, 0.1, 0.1, 0.4)),
+        ]
+        .into_iter()
+        .filter_map(|(mask, colour)| Some((height_map::masked_mesh(mesh, &mask, 0.005)?, colour)))
+        .collect();
+        for (overlay, colour) in overlays {
+            spawn_overlay(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                overlay,
+                StandardMaterial {
+                    base_color: colour,
+                    alpha_mode: AlphaMode::Blend,
+                    ..Default::default()
+                },
+                *transform,
+                ViewshedOverlay,
+            );
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, Default)]
+pub struct PathOverlay;
+
+/// shift + click adds the point under the cursor to a path over the terrain, P clears it again.
+/// The elevation profile along the path shows up in the debug ui.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_profile_path(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    pick: Res<TerrainPick>,
+    mut path: ResMut<TerrainPath>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_data: Res<Assets<Terrain>>,
+    terrains: Query<(&TerrainData, &Transform)>,
+    overlays: Query<Entity, With<PathOverlay>>,
+) {
+    let clear = keyboard_input.just_released(KeyCode::P);
+    let shift = keyboard_input.pressed(KeyCode::LShift) || keyboard_input.pressed(KeyCode::RShift);
+    let hit = match pick.0 {
+        Some(hit) if shift && mouse_input.just_pressed(MouseButton::Left) => Some(hit),
+        _ => None,
+    };
+    if !clear && hit.is_none() {
+        return;
+    }
+    despawn_overlays(&mut commands, &overlays);
+    match hit {
+        Some(hit) => path.points.push(hit.position),
+        None => {
+            *path = TerrainPath::default();
+            return;
+        }
+    }
+
+    for (data, transform) in terrains.iter() {
+        let terrain = match terrain_data.get(&data.0) {
+            Some(terrain) => terrain,
+            None => continue,
+        };
+        let (hm, size) = (terrain.height_map(), terrain.size());
+        let to_local = transform.compute_matrix().inverse();
+        let local: Vec<Vec2> = path
+            .points
+            .iter()
+            .map(|point| {
+                let point = to_local.transform_point3(*point);
+                Vec2::new(point.x, point.z)
+            })
+            .collect();
+
+        // one sample per terrain sample is as detailed as it gets
+        let profile =
+            hm.elevation_profile(&local, TERRAIN_SIZE / size as f32, Resampling::Bilinear);
+        // the line breaks where the path crosses a void
+        let lines: Vec<Vec<Vec2>> = profile
+            .sections()
+            .map(|section| {
+                section
+                    .iter()
+                    .map(|point| {
+                        let (x, y) = hm.world_to_grid(point.position.x, point.position.y);
+                        Vec2::new(x, y)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        spawn_overlay(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            height_map::polyline_mesh(&lines, hm, 0.01),
+            StandardMaterial {
+                base_color: Color::rgb(0.95, 0.85, 0.3),
+                ..Default::default()
+            },
+            *transform,
+            PathOverlay,
+        );
+        path.profile = Some(profile);
+    }
+}
+
+#[derive(Component, Debug, Clone, Default)]
+pub struct EarthworkOverlay;
+
+/// shows the cut (red) and fill (blue) needed to level the terrain at the height of the point
+/// under the cursor when D is released. Only the area inside the drawn path is levelled if there
+/// is one, the whole terrain otherwise.
+#[allow(clippy::too_many_arguments)]
+pub fn show_earthwork(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    pick: Res<TerrainPick>,
+    path: Res<TerrainPath>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_data: Res<Assets<Terrain>>,
+    terrains: Query<(&TerrainMarker, &TerrainData, &Transform)>,
+    overlays: Query<Entity, With<EarthworkOverlay>>,
+) {
+    if !keyboard_input.just_released(KeyCode::D) {
+        return;
+    }
+    despawn_overlays(&mut commands, &overlays);
+    let hit = match pick.0 {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    for (marker, data, transform) in terrains.iter() {
+        let (mesh, terrain) = match (meshes.get(&marker.0), terrain_data.get(&data.0)) {
+            (Some(mesh), Some(terrain)) => (mesh, terrain),
+            _ => continue,
+        };
+        let (grid, hm, size) = (terrain.grid(), terrain.height_map(), terrain.size());
+        let to_local = transform.compute_matrix().inverse();
+        let level = to_local.transform_point3(hit.position).y;
+
+        let polygon: Vec<Vec2> = path
+            .points
+            .iter()
+            .map(|point| {
+                let point = to_local.transform_point3(*point);
+                let (x, y) = hm.world_to_grid(point.x, point.z);
+                Vec2::new(x, y)
+            })
+            .collect();
+        let region = if polygon.len() >= 3 {
+            Region::Polygon(&polygon)
+        } else {
+            Region::All
+        };
+        // voids are neither cut nor filled
+        let earthwork = volume::earthwork(
+            grid,
+            &ReferencePlane::level(level),
+            size,
+            size,
+            TERRAIN_SIZE / size as f32,
+            region,
+        );
+        println!(
+            "levelling {} samples at {:.3}: cut {:.4}, fill {:.4}, net {:.4} cubic units",
+            earthwork.samples,
+            level,
+            earthwork.cut,
+            earthwork.fill,
+            earthwork.net()
+        );
+
+        let inside = HeightGrid::from_fn(size, size, |x, y| {
+            if region.contains(x, y) && grid.is_valid(x, y) {
+                1.0
+            } else {
+                0.0
+            }
+        });
+        let overlay = match height_map::masked_mesh(mesh, &inside, 0.005) {
+            Some(overlay) => overlay,
+            None => continue,
+        };
+        let texture = hillshade::texture(&volume::difference_image(&earthwork.difference));
+        spawn_overlay(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            overlay,
+            StandardMaterial {
+                base_color: Color::rgba(1.0, 1.0, 1.0, 0.8),
+                base_color_texture: Some(images.add(texture)),
+                alpha_mode: AlphaMode::Blend,
+                ..Default::default()
+            },
+            *transform,
+            EarthworkOverlay,
+        );
+    }
+}
+
+/// the brushes the sculpting key cycles through, with the strength they start with
+const SCULPT_BRUSHES: [(BrushKind, f32); 6] = [
+    (BrushKind::Raise, 0.003),
+    (BrushKind::Lower, 0.003),
+    (BrushKind::Smooth, 0.2),
+    (BrushKind::Flatten, 0.2),
+    (BrushKind::Noise, 0.003),
+    (BrushKind::Ramp, 0.2),
+];
+
+/// whether a sculpting stroke is going on. It changes the mesh every frame, so whatever is
+/// expensive to keep up with it can wait for the stroke to end.
+#[derive(Debug, Clone, Default)]
+pub struct Sculpting(pub bool);
+
+#[derive(Default)]
+pub struct SculptState {
+    /// index into [`SCULPT_BRUSHES`], not sculpting at all if `None`
+    current: Option<usize>,
+    brush: Brush,
+    terrain: Option<SculptedTerrain>,
+    stroke: Option<Stroke>,
+}
+
+/// B cycles through the sculpting brushes (and back to not sculpting), dragging with the left
+/// mouse button applies the brush under the cursor. `[` and `]` change its radius, `-` and `=`
+/// its strength and F its falloff.
+#[allow(clippy::too_many_arguments)]
+pub fn sculpt_terrain(
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    pick: Res<TerrainPick>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain_data: ResMut<Assets<Terrain>>,
+    terrains: Query<(&TerrainMarker, &TerrainData, &Transform)>,
+    mut sculpting: ResMut<Sculpting>,
+    mut state: Local<SculptState>,
+) {
+    let state = &mut *state;
+    if keyboard_input.just_released(KeyCode::B) {
+        state.current = match state.current {
+            Some(current) if current + 1 == SCULPT_BRUSHES.len() => None,
+            Some(current) => Some(current + 1),
+            None => Some(0),
+        };
+        match state.current {
+            Some(current) => {
+                let (kind, strength) = SCULPT_BRUSHES[current];
+                state.brush.kind = kind;
+                state.brush.strength = strength;
+                println!("sculpting with {:?}", state.brush);
+            }
+            None => println!("stopped sculpting"),
+        }
+    }
+    if state.current.is_none() {
+        state.stroke = None;
+        sculpting.0 = false;
+        return;
+    }
+
+    let brush = &mut state.brush;
+    let changed = if keyboard_input.just_released(KeyCode::LBracket) {
+        brush.radius = (brush.radius / 1.25).max(1.0);
+        true
+    } else if keyboard_input.just_released(KeyCode::RBracket) {
+        brush.radius *= 1.25;
+        true
+    } else if keyboard_input.just_released(KeyCode::Minus) {
+        brush.strength /= 1.25;
+        true
+    } else if keyboard_input.just_released(KeyCode::Equals) {
+        brush.strength *= 1.25;
+        true
+    } else if keyboard_input.just_released(KeyCode::F) {
+        brush.falloff = match brush.falloff {
+            Falloff::Constant => Falloff::Linear,
+            Falloff::Linear => Falloff::Smooth,
+            Falloff::Smooth => Falloff::Constant,
+        };
+        true
+    } else {
+        false
+    };
+    if changed {
+        println!("sculpting with {:?}", brush);
+    }
+
+    // shift + click draws paths, and the camera wants ctrl + drag
+    let modified = [
+        KeyCode::LShift,
+        KeyCode::RShift,
+        KeyCode::LControl,
+        KeyCode::RControl,
+    ]
+    .iter()
+    .any(|key| keyboard_input.pressed(*key));
+    if !mouse_input.pressed(MouseButton::Left) || modified {
+        state.stroke = None;
+        sculpting.0 = false;
+        return;
+    }
+    let hit = match pick.0 {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    for (marker, data, transform) in terrains.iter() {
+        let (mesh, terrain) = match (meshes.get_mut(&marker.0), terrain_data.get_mut(&data.0)) {
+            (Some(mesh), Some(terrain)) => (mesh, terrain),
+            _ => continue,
+        };
+        // starting over for every stroke picks up whatever else changed the terrain meanwhile
+        if state.stroke.is_none() {
+            state.terrain = Some(SculptedTerrain::new(
+                terrain.grid().clone(),
+                terrain.triangulation(),
+            ));
+        }
+        let sculpted = match &mut state.terrain {
+            Some(sculpted) => sculpted,
+            None => continue,
+        };
+
+        let local = transform
+            .compute_matrix()
+            .inverse()
+            .transform_point3(hit.position);
+        let (x, y) = sculpted.height_map().world_to_grid(local.x, local.z);
+        let center = Vec2::new(x, y);
+        let stroke = *state
+            .stroke
+            .get_or_insert_with(|| Stroke::begin(&sculpted.grid().grid, center));
+        sculpting.0 = true;
+        let region = match sculpted.apply(&state.brush, &stroke, center, mesh) {
+            Some(region) => region,
+            None => continue,
+        };
+        // so picking (and everything else reading the terrain) sees the dab right away
+        terrain.edit_region(|grid| {
+            for y in region.y.clone() {
+                for x in region.x.clone() {
+                    grid.set(x, y, sculpted.grid().grid.get(x, y));
+                }
+            }
+            Some(region)
+        });
+    }
+}